@@ -1,10 +1,16 @@
-use lexer_bench::lua::lexer_lexgen::Lexer as LuaLexgen;
-use lexer_bench::lua::lexer_luster::Lexer as LuaLuster;
-use lexer_bench::lua::lua_file_iter;
+use lexer_bench::lua::buffered::{self, BufferedTokens};
+use lexer_bench::lua::cst;
+use lexer_bench::lua::lexer_lexgen::{self, Lexer as LuaLexgen};
+use lexer_bench::lua::lexer_luster::{self, Lexer as LuaLuster};
+use lexer_bench::lua::parallel;
+use lexer_bench::lua::parser;
+use lexer_bench::lua::syntax_check;
+use lexer_bench::lua::token::TokenPayloads;
+use lexer_bench::lua::{lua_file_iter, skip_first_line_comment};
 
 use std::fs;
 
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
 
 fn generate_lua_code() -> String {
     let mut code = String::new();
@@ -22,6 +28,17 @@ fn generate_lua_code() -> String {
     code
 }
 
+/// Sources of the test files, to be parsed separately. Like `luaL_loadfile`, the first line is
+/// skipped if it starts with `#`.
+fn read_lua_files() -> Vec<String> {
+    lua_file_iter()
+        .map(|file| {
+            let source = fs::read_to_string(file).expect("Unable to read test file");
+            skip_first_line_comment(&source).to_owned()
+        })
+        .collect()
+}
+
 fn lua_benchmarks(c: &mut Criterion) {
     let code = generate_lua_code();
 
@@ -52,5 +69,295 @@ fn lua_benchmarks(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, lua_benchmarks);
+fn lua_compact_token_benchmarks(c: &mut Criterion) {
+    let code = generate_lua_code();
+
+    c.bench_function("Lex Lua code to compact tokens -- luster", |b| {
+        b.iter(|| {
+            let mut lexer = LuaLuster::new(black_box(code.as_bytes()), |s| s.to_owned());
+            let mut payloads = TokenPayloads::new();
+            let mut tokens = Vec::new();
+            loop {
+                match lexer.read_token() {
+                    Ok(Some(token)) => tokens.push(payloads.compact(token)),
+                    Ok(None) => break,
+                    Err(err) => panic!("Error in luster benchmark: {}", err),
+                }
+            }
+            (tokens, payloads)
+        })
+    });
+
+    c.bench_function("Lex Lua code to compact tokens -- lexgen", |b| {
+        b.iter(|| {
+            let mut lexer = LuaLexgen::new(black_box(&code));
+            let mut payloads = TokenPayloads::new();
+            let mut tokens = Vec::new();
+            loop {
+                match lexer.next() {
+                    Some(Ok((_, token, _))) => tokens.push(payloads.compact(token)),
+                    Some(Err(err)) => panic!("Error in lexgen benchmark: {:?}", err),
+                    None => break,
+                }
+            }
+            (tokens, payloads)
+        })
+    });
+}
+
+fn lua_token_buffer_benchmarks(c: &mut Criterion) {
+    let code = generate_lua_code();
+
+    c.bench_function("Tokenize all Lua code -- luster", |b| {
+        b.iter(
+            || match lexer_luster::tokenize_all(black_box(code.as_bytes())) {
+                Ok(tokens) => tokens,
+                Err(err) => panic!("Error in luster benchmark: {}", err),
+            },
+        )
+    });
+
+    c.bench_function("Tokenize all Lua code -- lexgen", |b| {
+        b.iter(|| match lexer_lexgen::tokenize_all(black_box(&code)) {
+            Ok(tokens) => tokens,
+            Err(err) => panic!("Error in lexgen benchmark: {:?}", err),
+        })
+    });
+}
+
+fn lua_parallel_benchmarks(c: &mut Criterion) {
+    let code = generate_lua_code();
+
+    let mut group = c.benchmark_group("Tokenize all Lua code in parallel -- luster");
+    for n_threads in [1, 2, 4, 8] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(n_threads),
+            &n_threads,
+            |b, &n_threads| {
+                b.iter(
+                    || match parallel::tokenize_all(black_box(code.as_bytes()), n_threads) {
+                        Ok(tokens) => tokens,
+                        Err(err) => panic!("Error in parallel luster benchmark: {}", err),
+                    },
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+fn lua_buffered_benchmarks(c: &mut Criterion) {
+    let code = generate_lua_code();
+
+    // Compare with "Lex Lua code -- luster" and "Lex Lua code -- lexgen" for the overhead of
+    // buffering
+    c.bench_function("Buffered tokens -- luster", |b| {
+        b.iter(|| {
+            let lexer = LuaLuster::new(black_box(code.as_bytes()), |s| s.to_owned());
+            let mut tokens = BufferedTokens::new(lexer);
+            loop {
+                match tokens.next_token() {
+                    Ok(Some(_token)) => {}
+                    Ok(None) => break,
+                    Err((err, _)) => panic!("Error in buffered luster benchmark: {}", err),
+                }
+            }
+        })
+    });
+
+    c.bench_function("Buffered tokens -- lexgen", |b| {
+        b.iter(|| {
+            let mut tokens = BufferedTokens::new(buffered::lexgen_source(black_box(&code)));
+            loop {
+                match tokens.next_token() {
+                    Ok(Some(_token)) => {}
+                    Ok(None) => break,
+                    Err((err, _)) => panic!("Error in buffered lexgen benchmark: {}", err),
+                }
+            }
+        })
+    });
+
+    // Peeks two tokens before every token, and every 16 tokens reads 4 tokens speculatively and
+    // rewinds, like a parser that backtracks
+    c.bench_function(
+        "Buffered tokens with lookahead and backtracking -- luster",
+        |b| {
+            b.iter(|| {
+                let lexer = LuaLuster::new(black_box(code.as_bytes()), |s| s.to_owned());
+                let mut tokens = BufferedTokens::new(lexer);
+                loop {
+                    if let Err((err, _)) = tokens.peek_nth(1) {
+                        panic!("Error in buffered luster benchmark: {}", err);
+                    }
+                    if tokens.position() % 16 == 0 {
+                        let checkpoint = tokens.checkpoint();
+                        for _ in 0..4 {
+                            black_box(tokens.next_token().ok());
+                        }
+                        tokens.rewind(checkpoint);
+                    }
+                    match tokens.next_token() {
+                        Ok(Some(_token)) => {}
+                        Ok(None) => break,
+                        Err((err, _)) => panic!("Error in buffered luster benchmark: {}", err),
+                    }
+                }
+            })
+        },
+    );
+}
+
+fn lua_parser_benchmarks(c: &mut Criterion) {
+    let files = read_lua_files();
+
+    c.bench_function("Lex and parse Lua files -- luster", |b| {
+        b.iter(|| {
+            for source in &files {
+                let lexer = LuaLuster::new(black_box(source.as_bytes()), |s| s.to_owned());
+                if let Err(err) = parser::parse_chunk(lexer) {
+                    panic!("Error in luster parser benchmark: {}", err);
+                }
+            }
+        })
+    });
+
+    c.bench_function("Lex and parse Lua files -- lexgen", |b| {
+        b.iter(|| {
+            for source in &files {
+                let lexer = buffered::lexgen_source(black_box(source));
+                if let Err(err) = parser::parse_chunk(lexer) {
+                    panic!("Error in lexgen parser benchmark: {}", err);
+                }
+            }
+        })
+    });
+}
+
+fn lua_syntax_check_benchmarks(c: &mut Criterion) {
+    let files = read_lua_files();
+
+    // Lexing the same files, for the overhead of checking the syntax
+    c.bench_function("Lex Lua files -- luster", |b| {
+        b.iter(|| {
+            for source in &files {
+                let mut lexer = LuaLuster::new(black_box(source.as_bytes()), |s| s.to_owned());
+                loop {
+                    match lexer.read_token() {
+                        Ok(Some(_token)) => {}
+                        Ok(None) => break,
+                        Err(err) => panic!("Error in luster benchmark: {}", err),
+                    }
+                }
+            }
+        })
+    });
+
+    c.bench_function("Lex Lua files -- lexgen", |b| {
+        b.iter(|| {
+            for source in &files {
+                let mut lexer = LuaLexgen::new(black_box(source));
+                loop {
+                    match lexer.next() {
+                        Some(Ok(_token)) => {}
+                        Some(Err(err)) => panic!("Error in lexgen benchmark: {:?}", err),
+                        None => break,
+                    }
+                }
+            }
+        })
+    });
+
+    c.bench_function("Syntax check Lua files -- luster", |b| {
+        b.iter(|| {
+            for source in &files {
+                let lexer = LuaLuster::new(black_box(source.as_bytes()), |s| s.to_owned());
+                if let Err(err) = syntax_check::check_syntax(lexer) {
+                    panic!("Error in luster syntax check benchmark: {}", err);
+                }
+            }
+        })
+    });
+
+    c.bench_function("Syntax check Lua files -- lexgen", |b| {
+        b.iter(|| {
+            for source in &files {
+                let lexer = buffered::lexgen_source(black_box(source));
+                if let Err(err) = syntax_check::check_syntax(lexer) {
+                    panic!("Error in lexgen syntax check benchmark: {}", err);
+                }
+            }
+        })
+    });
+}
+
+fn lua_cst_benchmarks(c: &mut Criterion) {
+    let files = read_lua_files();
+
+    // Compare with "Lex and parse Lua files -- luster"
+    c.bench_function("Parse Lua files to lossless syntax trees", |b| {
+        b.iter(|| {
+            for source in &files {
+                let parse = cst::parse(black_box(source.as_bytes()));
+                if let Some(err) = parse.error() {
+                    panic!("Error in syntax tree benchmark: {}", err);
+                }
+            }
+        })
+    });
+}
+
+#[cfg(feature = "serde")]
+fn lua_serialize_benchmarks(c: &mut Criterion) {
+    use lexer_bench::lua::serialize::*;
+
+    let code = generate_lua_code();
+    let tokens = lexer_luster::tokenize_all(code.as_bytes()).unwrap();
+
+    let mut json = vec![];
+    write_json_lines(&tokens, &mut json).unwrap();
+
+    let mut binary = vec![];
+    write_binary(&tokens, &mut binary).unwrap();
+
+    c.bench_function("Serialize tokens -- JSON lines", |b| {
+        b.iter(|| {
+            let mut out = Vec::with_capacity(json.len());
+            write_json_lines(black_box(&tokens), &mut out).unwrap();
+            out
+        })
+    });
+
+    c.bench_function("Deserialize tokens -- JSON lines", |b| {
+        b.iter(|| read_json_lines(black_box(json.as_slice())).unwrap())
+    });
+
+    c.bench_function("Serialize tokens -- binary", |b| {
+        b.iter(|| {
+            let mut out = Vec::with_capacity(binary.len());
+            write_binary(black_box(&tokens), &mut out).unwrap();
+            out
+        })
+    });
+
+    c.bench_function("Deserialize tokens -- binary", |b| {
+        b.iter(|| read_binary(black_box(binary.as_slice())).unwrap())
+    });
+}
+
+#[cfg(not(feature = "serde"))]
+fn lua_serialize_benchmarks(_c: &mut Criterion) {}
+
+criterion_group!(
+    benches,
+    lua_benchmarks,
+    lua_compact_token_benchmarks,
+    lua_token_buffer_benchmarks,
+    lua_parallel_benchmarks,
+    lua_buffered_benchmarks,
+    lua_parser_benchmarks,
+    lua_syntax_check_benchmarks,
+    lua_cst_benchmarks,
+    lua_serialize_benchmarks
+);
 criterion_main!(benches);
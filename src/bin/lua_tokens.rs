@@ -0,0 +1,325 @@
+// Command-line tool for inspecting the Lua lexers:
+//
+//     lua_tokens dump <file> [--lexer luster|lexgen]    Print tokens with their spans
+//     lua_tokens compare <file>                         Print differences between the lexers
+//     lua_tokens stats <dir> [--lexer luster|lexgen]    Token counts by kind, bytes, errors
+//     lua_tokens bench <dir>                            Quick lexing throughput in MB/s
+//
+// Errors are printed with source snippets, or in the format of the reference Lua implementation
+// with `--lua-errors`.
+
+use lexer_bench::lua::diagnostic::{lua_error_message, Diagnostic};
+use lexer_bench::lua::error::LexerError;
+use lexer_bench::lua::source_map::LexerChoice;
+use lexer_bench::lua::token::{Span, TokenKind};
+use lexer_bench::lua::token_buffer::TokenBuffer;
+use lexer_bench::lua::{lexer_lexgen, lexer_luster, lua_files_in_dir};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::str;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorFormat {
+    Diagnostic,
+    Lua,
+}
+
+static USAGE: &str = "\
+Usage:
+    lua_tokens dump <file> [--lexer luster|lexgen] [--lua-errors]
+    lua_tokens compare <file> [--lua-errors]
+    lua_tokens stats <dir> [--lexer luster|lexgen] [--lua-errors]
+    lua_tokens bench <dir> [--lua-errors]";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let mut lexer = LexerChoice::Luster;
+    let mut error_format = ErrorFormat::Diagnostic;
+    let mut positional: Vec<&str> = vec![];
+
+    let mut args_iter = args.iter();
+    while let Some(arg) = args_iter.next() {
+        if arg == "--lexer" {
+            lexer = match args_iter.next().map(String::as_str) {
+                Some("luster") => LexerChoice::Luster,
+                Some("lexgen") => LexerChoice::Lexgen,
+                _ => usage_error(),
+            };
+        } else if arg == "--lua-errors" {
+            error_format = ErrorFormat::Lua;
+        } else if arg == "--help" || arg == "-h" {
+            println!("{}", USAGE);
+            return;
+        } else {
+            positional.push(arg);
+        }
+    }
+
+    match positional.as_slice() {
+        ["dump", file] => dump(Path::new(file), lexer, error_format),
+        ["compare", file] => compare(Path::new(file), error_format),
+        ["stats", dir] => stats(Path::new(dir), lexer, error_format),
+        ["bench", dir] => bench(Path::new(dir), error_format),
+        _ => usage_error(),
+    }
+}
+
+fn usage_error() -> ! {
+    eprintln!("{}", USAGE);
+    exit(1)
+}
+
+fn read_file(path: &Path) -> Vec<u8> {
+    match fs::read(path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            eprintln!("Unable to read {}: {}", path.to_string_lossy(), err);
+            exit(1)
+        }
+    }
+}
+
+/// The `.lua` files in a directory, in the order of their paths, with their contents or the error
+/// reading them
+fn read_lua_files(dir: &Path) -> Vec<(PathBuf, io::Result<Vec<u8>>)> {
+    let files = match lua_files_in_dir(dir) {
+        Ok(files) => files,
+        Err(err) => {
+            eprintln!("Unable to read {}: {}", dir.to_string_lossy(), err);
+            exit(1)
+        }
+    };
+
+    let mut paths: Vec<PathBuf> = files.collect();
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| {
+            let contents = fs::read(&path);
+            (path, contents)
+        })
+        .collect()
+}
+
+/// Lexes a whole source. The lexgen lexer works on strings, so it lexes the valid UTF-8 prefix of
+/// the source, and an invalid byte after the prefix is an unexpected character, as in the luster
+/// lexer.
+fn tokenize(source: &[u8], lexer: LexerChoice) -> Result<TokenBuffer, (LexerError, Span)> {
+    match lexer {
+        LexerChoice::Luster => lexer_luster::tokenize_all(source).map_err(|_| {
+            lexer_luster::first_error(source).expect("tokenize_all failed but lexing succeeded")
+        }),
+        LexerChoice::Lexgen => {
+            let valid_len = match str::from_utf8(source) {
+                Ok(_) => source.len(),
+                Err(err) => err.valid_up_to(),
+            };
+            let valid = str::from_utf8(&source[..valid_len]).unwrap();
+            let tokens = lexer_lexgen::tokenize_all(valid)
+                .map_err(|err| lexer_lexgen::luster_fallback_error_span(valid, &err))?;
+            if valid_len < source.len() {
+                // Same as the luster lexer, the span of an unexpected character is empty
+                let err = LexerError::UnexpectedCharacter(source[valid_len]);
+                return Err((err, Span::new(valid_len, valid_len)));
+            }
+            Ok(tokens)
+        }
+    }
+}
+
+fn format_error(
+    file: &Path,
+    source: &[u8],
+    (err, span): &(LexerError, Span),
+    error_format: ErrorFormat,
+) -> String {
+    let file_name = file.to_string_lossy();
+    match error_format {
+        ErrorFormat::Diagnostic => {
+            Diagnostic::from_lexer_error(source, err, *span).render(&file_name, source)
+        }
+        ErrorFormat::Lua => format!("{}\n", lua_error_message(&file_name, source, err, *span)),
+    }
+}
+
+fn dump(file: &Path, lexer: LexerChoice, error_format: ErrorFormat) {
+    let source = read_file(file);
+    match tokenize(&source, lexer) {
+        Ok(tokens) => {
+            for (token, span) in tokens.iter() {
+                println!(
+                    "{:>8}..{:<8} {:<12} {}",
+                    span.start,
+                    span.end,
+                    token.kind(),
+                    token
+                );
+            }
+        }
+        Err(err) => {
+            eprint!("{}", format_error(file, &source, &err, error_format));
+            exit(1)
+        }
+    }
+}
+
+fn compare(file: &Path, error_format: ErrorFormat) {
+    let source = read_file(file);
+    let luster = tokenize(&source, LexerChoice::Luster);
+    let lexgen = tokenize(&source, LexerChoice::Lexgen);
+
+    let (luster, lexgen) = match (luster, lexgen) {
+        (Ok(luster), Ok(lexgen)) => (luster, lexgen),
+        (luster, lexgen) => {
+            // Lexing failed, the lexers agree if they both failed with the same error at the same
+            // span
+            if let Err(err) = &luster {
+                print!("luster: {}", format_error(file, &source, err, error_format));
+            }
+            if let Err(err) = &lexgen {
+                print!("lexgen: {}", format_error(file, &source, err, error_format));
+            }
+            if luster.err() != lexgen.err() {
+                exit(1);
+            }
+            return;
+        }
+    };
+
+    let mut n_differences = 0;
+    for idx in 0..luster.len().max(lexgen.len()) {
+        let luster_token = if idx < luster.len() {
+            Some((luster.token(idx), luster.span(idx)))
+        } else {
+            None
+        };
+        let lexgen_token = if idx < lexgen.len() {
+            Some((lexgen.token(idx), lexgen.span(idx)))
+        } else {
+            None
+        };
+
+        if luster_token != lexgen_token {
+            n_differences += 1;
+            println!("token {}:", idx);
+            for (lexer, token) in &[("luster", luster_token), ("lexgen", lexgen_token)] {
+                match token {
+                    Some((token, span)) => {
+                        println!("  {}: {}..{} {:?}", lexer, span.start, span.end, token)
+                    }
+                    None => println!("  {}: end of input", lexer),
+                }
+            }
+        }
+    }
+
+    println!(
+        "{} tokens (luster), {} tokens (lexgen), {} differences",
+        luster.len(),
+        lexgen.len(),
+        n_differences
+    );
+
+    if n_differences != 0 {
+        exit(1);
+    }
+}
+
+fn stats(dir: &Path, lexer: LexerChoice, error_format: ErrorFormat) {
+    let mut n_files = 0;
+    let mut n_bytes = 0;
+    let mut n_tokens = 0;
+    let mut n_errors = 0;
+    let mut counts: BTreeMap<TokenKind, usize> = BTreeMap::new();
+
+    for (path, source) in read_lua_files(dir) {
+        n_files += 1;
+        let source = match source {
+            Ok(source) => source,
+            Err(err) => {
+                n_errors += 1;
+                println!("Unable to read {}: {}", path.to_string_lossy(), err);
+                continue;
+            }
+        };
+        n_bytes += source.len();
+        match tokenize(&source, lexer) {
+            Ok(tokens) => {
+                n_tokens += tokens.len();
+                for kind in &tokens.kinds {
+                    *counts.entry(*kind).or_insert(0) += 1;
+                }
+            }
+            Err(err) => {
+                n_errors += 1;
+                print!("{}", format_error(&path, &source, &err, error_format));
+            }
+        }
+    }
+
+    println!(
+        "{} files, {} bytes, {} tokens, {} errors",
+        n_files, n_bytes, n_tokens, n_errors
+    );
+
+    let mut counts: Vec<(TokenKind, usize)> = counts.into_iter().collect();
+    counts.sort_by(|(_, n1), (_, n2)| n2.cmp(n1));
+    for (kind, n) in counts {
+        println!(
+            "{:>12} {:>8} {:>6.2}%",
+            kind.as_str(),
+            n,
+            n as f64 * 100.0 / n_tokens as f64
+        );
+    }
+}
+
+fn bench(dir: &Path, error_format: ErrorFormat) {
+    const MIN_DURATION: Duration = Duration::from_secs(1);
+
+    let files: Vec<(PathBuf, Vec<u8>)> = read_lua_files(dir)
+        .into_iter()
+        .map(|(path, source)| match source {
+            Ok(source) => (path, source),
+            Err(err) => {
+                eprintln!("Unable to read {}: {}", path.to_string_lossy(), err);
+                exit(1)
+            }
+        })
+        .collect();
+    let n_bytes: usize = files.iter().map(|(_, source)| source.len()).sum();
+
+    for &lexer in &[LexerChoice::Luster, LexerChoice::Lexgen] {
+        // Run at least once, and until `MIN_DURATION` passes
+        let start = Instant::now();
+        let mut n_iters = 0;
+        loop {
+            for (path, source) in &files {
+                if let Err(err) = tokenize(source, lexer) {
+                    eprint!("{}", format_error(path, source, &err, error_format));
+                    exit(1);
+                }
+            }
+            n_iters += 1;
+            if start.elapsed() >= MIN_DURATION {
+                break;
+            }
+        }
+
+        let elapsed = start.elapsed().as_secs_f64();
+        let mb = (n_bytes * n_iters) as f64 / 1_000_000.0;
+        println!(
+            "{:?}: {:.2} MB/s ({} iterations, {:.2} s)",
+            lexer,
+            mb / elapsed,
+            n_iters,
+            elapsed
+        );
+    }
+}
@@ -5,19 +5,45 @@
 //
 // - Shebang lines (`#!../lua`) removed from Lua files.
 
+pub mod ast;
+pub mod buffered;
+pub mod chunked;
+pub mod cst;
+pub mod diagnostic;
 pub mod error;
+pub mod grammar;
+pub mod incremental;
 pub mod lexer_lexgen;
 pub mod lexer_luster;
+pub mod line_index;
+pub mod line_state;
+pub mod parallel;
+pub mod parser;
+#[cfg(feature = "serde")]
+pub mod serialize;
+pub mod source_map;
+#[cfg(feature = "async")]
+pub mod stream;
+pub mod syntax;
+pub mod syntax_check;
 pub mod token;
+pub mod token_buffer;
+pub mod warning;
 
 use std::fs;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
 
 static LUA_TEST_FILES_DIR: &str = "test_files/lua";
 
 pub fn lua_file_iter() -> impl Iterator<Item = PathBuf> {
-    let dir = fs::read_dir(LUA_TEST_FILES_DIR).expect("Unable to read test_files/lua");
-    dir.filter_map(|entry| {
+    lua_files_in_dir(LUA_TEST_FILES_DIR).expect("Unable to read test_files/lua")
+}
+
+/// Iterates `.lua` files in a directory, non-recursively.
+pub fn lua_files_in_dir<P: AsRef<Path>>(dir: P) -> io::Result<impl Iterator<Item = PathBuf>> {
+    let dir = fs::read_dir(dir)?;
+    Ok(dir.filter_map(|entry| {
         let entry = entry.expect("Unable to read dir entry");
         let path = entry.path();
         let extension = match path.extension() {
@@ -30,13 +56,116 @@ pub fn lua_file_iter() -> impl Iterator<Item = PathBuf> {
         } else {
             None
         }
-    })
+    }))
+}
+
+/// The source without its first line if the line starts with `#`, which `luaL_loadfile` skips.
+/// The newline after the line is kept.
+pub fn skip_first_line_comment(source: &str) -> &str {
+    if source.starts_with('#') {
+        &source[source.find('\n').unwrap_or(source.len())..]
+    } else {
+        source
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use error::LexerError;
+    use token::Token;
+
+    fn lex_luster(s: &str) -> Result<Vec<Token<Vec<u8>>>, LexerError> {
+        let mut lexer = lexer_luster::Lexer::new(s.as_bytes(), |s| s.to_owned());
+        let mut tokens = vec![];
+        while let Some(token) = lexer.read_token()? {
+            tokens.push(token);
+        }
+        Ok(tokens)
+    }
+
+    /// Tokens with their spans, and the error after the last token
+    type SpannedTokens = (
+        Vec<(Token<Vec<u8>>, token::Span)>,
+        Option<(LexerError, token::Span)>,
+    );
+
+    fn lex_luster_spans(s: &[u8]) -> SpannedTokens {
+        let mut lexer = lexer_luster::Lexer::new(s, |s| s.to_owned());
+        let mut tokens = vec![];
+        loop {
+            match lexer.read_token() {
+                Ok(Some(token)) => {
+                    let span = token::Span::new(lexer.token_start(), lexer.byte_offset());
+                    tokens.push((token, span));
+                }
+                Ok(None) => return (tokens, None),
+                Err(err) => {
+                    let span = token::Span::new(lexer.token_start(), lexer.byte_offset());
+                    return (tokens, Some((err, span)));
+                }
+            }
+        }
+    }
+
+    fn lex_lexgen(s: &str) -> Result<Vec<Token<Vec<u8>>>, ::lexgen_util::LexerError<LexerError>> {
+        lexer_lexgen::Lexer::new(s)
+            .map(|t| t.map(|(_, t, _)| t))
+            .collect()
+    }
+
+    /// Lexes until an error, returns the error and the erroneous part of the source
+    fn luster_error(s: &str) -> (LexerError, token::Span) {
+        lexer_luster::first_error(s.as_bytes()).unwrap_or_else(|| panic!("No error in {:?}", s))
+    }
+
+    /// Runs `f` on a thread with a stack large enough to parse `grammar::MAX_DEPTH` levels of
+    /// nesting in debug builds.
+    fn with_large_stack<F: FnOnce() + Send + 'static>(f: F) {
+        std::thread::Builder::new()
+            .stack_size(64 << 20)
+            .spawn(f)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    /// xorshift, for reproducible edits
+    struct Random(u64);
+
+    impl Random {
+        fn new() -> Self {
+            Random(0x2545_f491_4f6c_dd1d)
+        }
+
+        /// A number in `0..n`
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    /// Calls `f` with 20 copies of the source, each with up to 20 random bytes deleted.
+    fn with_deletions<F: FnMut(&str)>(source: &str, random: &mut Random, mut f: F) {
+        for _ in 0..20 {
+            let mut edited = source.as_bytes().to_vec();
+            let start = random.below(edited.len());
+            let end = (start + 1 + random.below(20)).min(edited.len());
+            edited.drain(start..end);
+            f(&String::from_utf8_lossy(&edited));
+        }
+    }
+
+    fn lexgen_error(s: &str) -> (LexerError, token::Span) {
+        let err = lexer_lexgen::Lexer::new(s)
+            .find_map(Result::err)
+            .unwrap_or_else(|| panic!("No error in {:?}", s));
+        lexer_lexgen::luster_fallback_error_span(s, &err)
+    }
+
     #[test]
     fn luster() {
         use lexer_luster::Lexer;
@@ -150,4 +279,2256 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn whitespace_equivalence() {
+        // Characters accepted by `isspace` in the "C" locale, plus the two-character newlines
+        let whitespace = [" ", "\t", "\n", "\r", "\r\n", "\n\r", "\x0B", "\x0C"];
+
+        for ws in &whitespace {
+            let cases = [
+                format!("a{}b", ws),
+                format!("{}local x{}={}1{}", ws, ws, ws, ws),
+                format!("'a\\z{}b'", ws),
+                format!("\"a\\z{}{}b\"", ws, ws),
+                format!("-- comment{}x", ws),
+                format!("--{}x", ws),
+                format!("--[[ long{}comment ]]{}x", ws, ws),
+            ];
+
+            for case in &cases {
+                let luster = lex_luster(case).unwrap();
+                let lexgen = lex_lexgen(case).unwrap();
+                assert_eq!(
+                    lexgen, luster,
+                    "left=lexgen, right=luster, input={:?}",
+                    case
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_numerals() {
+        let malformed = [
+            "3abc", "3x", "0x", "0X", "0xg", "0x.", "0x.p1", "0x1p", "0x1p+", "1e", "1e+", "1E-",
+            "1.e", ".5e", "1..2", "1.2.3", "1e5e5", "0x1p1f", "12_", "1inf",
+        ];
+
+        for numeral in &malformed {
+            let input = format!("x = {} y", numeral);
+            assert_eq!(
+                lex_luster(&input),
+                Err(LexerError::BadNumber),
+                "{}",
+                numeral
+            );
+            assert_eq!(
+                lex_lexgen(&input).map_err(|err| err.kind),
+                Err(::lexgen_util::LexerErrorKind::Custom(LexerError::BadNumber)),
+                "{}",
+                numeral
+            );
+        }
+
+        let well_formed = [
+            ("3", Token::Integer(3)),
+            ("3.", Token::Float(3.0)),
+            (".5", Token::Float(0.5)),
+            ("1.e5", Token::Float(1e5)),
+            ("1E+5", Token::Float(1e5)),
+            ("1e-2", Token::Float(1e-2)),
+            ("0x10", Token::Integer(16)),
+            ("0x1e", Token::Integer(30)),
+            ("0xA.8p1", Token::Float(21.0)),
+            ("0x.8", Token::Float(0.5)),
+            ("0x8.", Token::Float(8.0)),
+        ];
+
+        for (numeral, token) in &well_formed {
+            let input = format!("{} x", numeral);
+            let expected = vec![token.clone(), Token::Name(b"x".to_vec())];
+            assert_eq!(lex_luster(&input).as_ref(), Ok(&expected), "{}", numeral);
+            assert_eq!(
+                lex_lexgen(&input).ok().as_ref(),
+                Some(&expected),
+                "{}",
+                numeral
+            );
+        }
+
+        // `..` right after a numeral is a part of the numeral
+        assert_eq!(lex_luster("3..x"), Err(LexerError::BadNumber));
+        assert_eq!(
+            lex_lexgen("3..x").map_err(|err| err.kind),
+            Err(::lexgen_util::LexerErrorKind::Custom(LexerError::BadNumber)),
+        );
+    }
+
+    #[test]
+    fn numeric_edge_cases() {
+        // Expected values are what Lua 5.4 evaluates the numerals to
+        let cases: &[(&str, Token<Vec<u8>>)] = &[
+            // Largest decimal integer
+            ("9223372036854775807", Token::Integer(i64::MAX)),
+            // Decimal integers that don't fit into an integer are floats
+            ("9223372036854775808", Token::Float(9223372036854775808.0)),
+            ("18446744073709551615", Token::Float(18446744073709551615.0)),
+            ("18446744073709551616", Token::Float(18446744073709551616.0)),
+            // Leading zeros do not count towards overflow
+            ("000000000000000000000000000009", Token::Integer(9)),
+            // Hexadecimal integers wrap around
+            ("0x7fffffffffffffff", Token::Integer(i64::MAX)),
+            ("0x8000000000000000", Token::Integer(i64::MIN)),
+            ("0X8000000000000000", Token::Integer(i64::MIN)),
+            ("0x8000000000000001", Token::Integer(i64::MIN + 1)),
+            ("0xffffffffffffffff", Token::Integer(-1)),
+            ("0x10000000000000000", Token::Integer(0)),
+            ("0x1ffffffffffffffff", Token::Integer(-1)),
+            ("0x00000000000000000000000000000001", Token::Integer(1)),
+            // Hexadecimal floats don't wrap around
+            ("0xffffffffffffffff.0", Token::Float(18446744073709551615.0)),
+            ("0x8000000000000000p0", Token::Float(9223372036854775808.0)),
+            // Floats
+            ("1e308", Token::Float(1e308)),
+            ("1e309", Token::Float(f64::INFINITY)),
+            ("1e-400", Token::Float(0.0)),
+            ("9223372036854775807.0", Token::Float(9223372036854775807.0)),
+            ("4.9e-324", Token::Float(4.9e-324)),
+        ];
+
+        for (numeral, expected) in cases {
+            let expected = vec![expected.clone()];
+            assert_eq!(lex_luster(numeral).as_ref(), Ok(&expected), "{}", numeral);
+            assert_eq!(
+                lex_lexgen(numeral).ok().as_ref(),
+                Some(&expected),
+                "{}",
+                numeral
+            );
+        }
+
+        // `i64::MIN` can't be written as a single numeral token: the minus sign is a separate token
+        // and 9223372036854775808 doesn't fit into an integer.
+        assert_eq!(
+            lex_luster("-9223372036854775808"),
+            Ok(vec![Token::Minus, Token::Float(9223372036854775808.0)])
+        );
+        assert_eq!(
+            lex_lexgen("-0x8000000000000000").ok(),
+            Some(vec![Token::Minus, Token::Integer(i64::MIN)])
+        );
+
+        // With a sign, as in `tonumber`
+        assert_eq!(
+            lexer_luster::read_integer(b"-9223372036854775808"),
+            Some(i64::MIN)
+        );
+        assert_eq!(lexer_luster::read_integer(b"-9223372036854775809"), None);
+        assert_eq!(lexer_luster::read_hex_integer(b"-0x1"), Some(-1));
+        assert_eq!(
+            lexer_luster::read_hex_integer(b"-0x8000000000000000"),
+            Some(i64::MIN)
+        );
+    }
+
+    #[test]
+    fn hex_floats() {
+        use lexer_luster::read_hex_float;
+
+        let cases: &[(&str, u64)] = &[
+            ("0x0p0", 0),
+            ("0x0p99999999999999999999", 0),
+            ("0x1p0", 0x3FF0000000000000),
+            ("0x0.1p4", 0x3FF0000000000000),
+            (
+                "0x.0000000000000000000000000000000001p136",
+                0x3FF0000000000000,
+            ),
+            (
+                "0x100000000000000000000000000000000p-128",
+                0x3FF0000000000000,
+            ),
+            // Smallest subnormal
+            ("0x1p-1074", 0x0000000000000001),
+            ("0x.8p-1073", 0x0000000000000001),
+            // Half of the smallest subnormal, ties to even (zero)
+            ("0x1p-1075", 0),
+            // Slightly more than half of the smallest subnormal
+            ("0x1.0000000000000000001p-1075", 0x0000000000000001),
+            ("0x3p-1076", 0x0000000000000001),
+            // Smallest subnormal and a half, ties to even
+            ("0x3p-1075", 0x0000000000000002),
+            // Largest subnormal
+            ("0x0.fffffffffffffp-1022", 0x000FFFFFFFFFFFFF),
+            // Rounds up from the largest subnormal to the smallest normal
+            ("0x0.fffffffffffff8p-1022", 0x0010000000000000),
+            // Smallest normal
+            ("0x1p-1022", 0x0010000000000000),
+            // Ties to even
+            ("0x1.00000000000008p0", 0x3FF0000000000000),
+            ("0x1.00000000000018p0", 0x3FF0000000000002),
+            // Not a tie, as there are non-zero digits after the mantissa
+            (
+                "0x1.000000000000080000000000000000001p0",
+                0x3FF0000000000001,
+            ),
+            // Largest normal
+            ("0x1.fffffffffffffp1023", 0x7FEFFFFFFFFFFFFF),
+            ("0x1.fffffffffffff7ffffffffp1023", 0x7FEFFFFFFFFFFFFF),
+            // Overflows to infinity
+            ("0x1.fffffffffffff8p1023", 0x7FF0000000000000),
+            ("0x1p1024", 0x7FF0000000000000),
+            ("0x1p99999999999999999999", 0x7FF0000000000000),
+            ("0x1p-99999999999999999999", 0),
+            ("0xffffffffffffffffffffffffp-96", 0x3FF0000000000000),
+            ("-0x1p0", 0xBFF0000000000000),
+        ];
+
+        for (s, bits) in cases {
+            let f = read_hex_float(s.as_bytes()).unwrap();
+            assert_eq!(f.to_bits(), *bits, "{} = {:e}", s, f);
+        }
+
+        // Bit patterns generated with xorshift, printed as exact hexadecimal floats. Also checks
+        // the midpoints between the number and the next one, with and without extra digits.
+        let mut state: u64 = 0x853c_49e6_748f_ea9b;
+        for _ in 0..100_000 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+
+            let f = f64::from_bits(state >> 1); // positive
+            if !f.is_finite() || f == f64::MAX {
+                continue;
+            }
+
+            let bits = f.to_bits();
+            let biased_exp = (bits >> 52) as i64;
+            let (mantissa, exp) = if biased_exp == 0 {
+                (bits & ((1 << 52) - 1), -1074)
+            } else {
+                ((bits & ((1 << 52) - 1)) | (1 << 52), biased_exp - 1075)
+            };
+
+            let s = format!("0x{:x}p{}", mantissa, exp);
+            assert_eq!(read_hex_float(s.as_bytes()), Some(f), "{}", s);
+
+            let s = format!("0x{:x}.{:x}p{}", mantissa >> 4, mantissa & 0xf, exp + 4);
+            assert_eq!(read_hex_float(s.as_bytes()), Some(f), "{}", s);
+
+            let midpoint = format!("0x{:x}p{}", mantissa * 2 + 1, exp - 1);
+            let expected = if mantissa & 1 == 0 { bits } else { bits + 1 };
+            assert_eq!(
+                read_hex_float(midpoint.as_bytes()).map(f64::to_bits),
+                Some(expected),
+                "{}",
+                midpoint
+            );
+
+            let above_midpoint = format!("0x{:x}0000000001p{}", mantissa * 2 + 1, exp - 41);
+            assert_eq!(
+                read_hex_float(above_midpoint.as_bytes()).map(f64::to_bits),
+                Some(bits + 1),
+                "{}",
+                above_midpoint
+            );
+        }
+
+        // Both lexers use the same parser
+        let input = "0x1p-1075 0x3p-1075 0x1.fffffffffffff8p1023";
+        let expected = vec![
+            Token::Float(0.0),
+            Token::Float(f64::from_bits(2)),
+            Token::Float(f64::INFINITY),
+        ];
+        assert_eq!(lex_luster(input).as_ref(), Ok(&expected));
+        assert_eq!(lex_lexgen(input).ok().as_ref(), Some(&expected));
+    }
+
+    #[test]
+    fn long_string_newlines() {
+        let cases: &[(&str, &[u8])] = &[
+            ("[[\nabc]]", b"abc"),
+            ("[[\r\nabc]]", b"abc"),
+            ("[[\n\rabc]]", b"abc"),
+            ("[[\rabc]]", b"abc"),
+            ("[==[\nabc]==]", b"abc"),
+            // Only the first newline is skipped
+            ("[[\n\nabc]]", b"\nabc"),
+            ("[[\r\rabc]]", b"\nabc"),
+            ("[[\r\n\r\nabc]]", b"\nabc"),
+            ("[[\n]]", b""),
+            ("[[ \nabc]]", b" \nabc"),
+            // Newline sequences are normalized
+            ("[[a\r\nb\n\rc\rd\ne]]", b"a\nb\nc\nd\ne"),
+            ("[[a\n\n\r\rb]]", b"a\n\n\nb"),
+        ];
+
+        for (input, expected) in cases {
+            let expected = vec![Token::String(expected.to_vec())];
+            assert_eq!(lex_luster(input).as_ref(), Ok(&expected), "{:?}", input);
+            assert_eq!(
+                lex_lexgen(input).ok().as_ref(),
+                Some(&expected),
+                "{:?}",
+                input
+            );
+
+            // Same in comments
+            let comment = format!("--{}", input);
+            assert_eq!(lex_luster(&comment), Ok(vec![]), "{:?}", comment);
+            assert_eq!(lex_lexgen(&comment).ok(), Some(vec![]), "{:?}", comment);
+        }
+    }
+
+    #[test]
+    fn literals() {
+        use token::{Literal, LiteralKind, NumeralBase, StringDelimiter};
+
+        let input = "'a\\'b' \"a\" [[a]] [==[\na]==] 16 0x10 1.6e1 0x.1p8";
+        let expected = [
+            LiteralKind::String(StringDelimiter::SingleQuote),
+            LiteralKind::String(StringDelimiter::DoubleQuote),
+            LiteralKind::String(StringDelimiter::LongBracket(0)),
+            LiteralKind::String(StringDelimiter::LongBracket(2)),
+            LiteralKind::Numeral {
+                base: NumeralBase::Decimal,
+                has_fraction: false,
+                has_exponent: false,
+            },
+            LiteralKind::Numeral {
+                base: NumeralBase::Hexadecimal,
+                has_fraction: false,
+                has_exponent: false,
+            },
+            LiteralKind::Numeral {
+                base: NumeralBase::Decimal,
+                has_fraction: true,
+                has_exponent: true,
+            },
+            LiteralKind::Numeral {
+                base: NumeralBase::Hexadecimal,
+                has_fraction: true,
+                has_exponent: true,
+            },
+        ];
+        let expected_raw: Vec<&str> = input.split(' ').collect();
+
+        let mut luster = lexer_luster::Lexer::new(input.as_bytes(), |s| s.to_owned());
+        let mut lexgen = lexer_lexgen::Lexer::new(input);
+        for (raw, kind) in expected_raw.iter().zip(expected.iter()) {
+            let expected = Literal {
+                raw: raw.as_bytes(),
+                kind: *kind,
+            };
+
+            let (_, literal) = luster.read_token_with_literal().unwrap().unwrap();
+            let literal = literal.unwrap();
+            assert_eq!(
+                Literal {
+                    raw: literal.raw.as_slice(),
+                    kind: literal.kind
+                },
+                expected
+            );
+
+            let (start, token, end) = lexgen.next().unwrap().unwrap();
+            assert_eq!(
+                lexer_lexgen::literal(input, &start, &token, &end),
+                Some(expected)
+            );
+        }
+
+        // Both lexers should agree on the corpus
+        for lua_file in lua_file_iter() {
+            let file_contents = fs::read_to_string(lua_file).expect("Unable to read test file");
+
+            let mut lexgen = lexer_lexgen::Lexer::new(&file_contents);
+            let mut luster = lexer_luster::Lexer::new(file_contents.as_bytes(), |s| s.to_owned());
+
+            while let Some((_, luster_literal)) = luster.read_token_with_literal().unwrap() {
+                let (start, token, end) = lexgen.next().unwrap().unwrap();
+                let lexgen_literal = lexer_lexgen::literal(&file_contents, &start, &token, &end);
+                assert_eq!(
+                    lexgen_literal,
+                    luster_literal.as_ref().map(|literal| Literal {
+                        raw: literal.raw.as_slice(),
+                        kind: literal.kind,
+                    })
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn compact_tokens() {
+        use std::mem::size_of;
+        use token::{CompactToken, TokenPayloads};
+
+        assert_eq!(size_of::<CompactToken>(), 8);
+
+        for lua_file in lua_file_iter() {
+            let file_contents = fs::read_to_string(lua_file).expect("Unable to read test file");
+
+            let tokens = lex_lexgen(&file_contents).unwrap();
+            let mut payloads = TokenPayloads::new();
+            let compact_tokens: Vec<CompactToken> = tokens
+                .iter()
+                .map(|token| payloads.compact(token.clone()))
+                .collect();
+
+            for (token, compact_token) in tokens.iter().zip(compact_tokens) {
+                assert_eq!(token.kind(), compact_token.kind);
+                assert_eq!(payloads.get(compact_token), token.as_ref());
+            }
+        }
+    }
+
+    #[test]
+    fn token_classification() {
+        use token::{Associativity, TokenKind, UNARY_PRECEDENCE};
+
+        for (input, n_keywords) in &[("and or not if then else elseif end", 8), ("x + 1", 0)] {
+            let tokens = lex_luster(input).unwrap();
+            let keywords = tokens.iter().filter(|t| t.kind().is_keyword()).count();
+            assert_eq!(keywords, *n_keywords);
+        }
+
+        // `a or b and c < d | e ~ f & g << h .. i + j * k ^ l`: each operator binds tighter than
+        // the one before
+        let tokens = lex_lexgen("a or b and c < d | e ~ f & g << h .. i + j * k ^ l").unwrap();
+        let precedences: Vec<u8> = tokens
+            .iter()
+            .filter_map(|t| t.kind().binary_precedence())
+            .map(|(prec, _)| prec)
+            .collect();
+        assert_eq!(precedences.len(), 11);
+        assert!(precedences.windows(2).all(|w| w[0] < w[1]));
+
+        assert_eq!(
+            TokenKind::Concat.binary_precedence(),
+            Some((8, Associativity::Right))
+        );
+        assert_eq!(
+            TokenKind::Pow.binary_precedence(),
+            Some((12, Associativity::Right))
+        );
+        assert!(TokenKind::Mul.binary_precedence().unwrap().0 < UNARY_PRECEDENCE);
+        assert!(TokenKind::Pow.binary_precedence().unwrap().0 > UNARY_PRECEDENCE);
+
+        assert!(TokenKind::Minus.is_unary_operator() && TokenKind::Minus.is_operator());
+        assert!(TokenKind::Not.is_keyword() && TokenKind::Not.is_operator());
+        assert!(!TokenKind::Dots.is_operator());
+        assert!(TokenKind::String.is_literal() && !TokenKind::Name.is_literal());
+    }
+
+    #[test]
+    fn token_buffer() {
+        for lua_file in lua_file_iter() {
+            let file_contents = fs::read_to_string(lua_file).expect("Unable to read test file");
+
+            let tokens = lex_luster(&file_contents).unwrap();
+            let luster = lexer_luster::tokenize_all(file_contents.as_bytes()).unwrap();
+            let lexgen = lexer_lexgen::tokenize_all(&file_contents).unwrap();
+
+            assert_eq!(luster.len(), tokens.len());
+            assert_eq!(lexgen.len(), tokens.len());
+            assert_eq!(luster.spans, lexgen.spans);
+
+            for (idx, token) in tokens.iter().enumerate() {
+                let token = token.as_ref().map_string(|s| s.as_slice());
+                assert_eq!(luster.token(idx), token);
+                assert_eq!(lexgen.token(idx), token);
+
+                let span = luster.span(idx);
+                let text = &file_contents.as_bytes()[span.start as usize..span.end as usize];
+                if let Token::Name(name) = token {
+                    assert_eq!(text, name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn display_roundtrip() {
+        let cases: &[(Token<Vec<u8>>, &str)] = &[
+            (Token::LeftBracket, "["),
+            (Token::DoubleColon, "::"),
+            (Token::ElseIf, "elseif"),
+            (Token::Name(b"foo".to_vec()), "foo"),
+            (Token::Integer(16), "16"),
+            (Token::Integer(-1), "0xffffffffffffffff"),
+            (Token::Integer(i64::MIN), "0x8000000000000000"),
+            (Token::Float(3.0), "3.0"),
+            (Token::Float(0.1), "0.1"),
+            (Token::Float(1e300), "1e300"),
+            (Token::Float(f64::INFINITY), "1e9999"),
+            (Token::String(b"abc".to_vec()), "\"abc\""),
+            (
+                Token::String(b"\"\\\n\r\0\x7f'".to_vec()),
+                "\"\\\"\\\\\\n\\r\\x00\\x7f'\"",
+            ),
+            (Token::String("ğ".as_bytes().to_vec()), "\"ğ\""),
+            (Token::String(vec![0xff, b'a']), "\"\\xffa\""),
+        ];
+
+        for (token, expected) in cases {
+            let rendered = token.to_string();
+            assert_eq!(&rendered, expected);
+            assert_eq!(
+                lex_luster(&rendered),
+                Ok(vec![token.clone()]),
+                "{}",
+                rendered
+            );
+        }
+
+        for lua_file in lua_file_iter() {
+            let file_contents = fs::read_to_string(lua_file).expect("Unable to read test file");
+
+            let tokens = lex_luster(&file_contents).unwrap();
+            let rendered = token::tokens_to_string(&tokens);
+            assert_eq!(lex_luster(&rendered).as_ref(), Ok(&tokens));
+            assert_eq!(lex_lexgen(&rendered).ok().as_ref(), Some(&tokens));
+        }
+    }
+
+    #[test]
+    fn chunked() {
+        use chunked::ChunkedLexer;
+
+        fn lex_chunks<'a>(chunks: impl Iterator<Item = &'a [u8]>) -> SpannedTokens {
+            let mut lexer = ChunkedLexer::new();
+            let mut tokens = vec![];
+            for chunk in chunks {
+                let result = lexer.feed(chunk);
+                tokens.extend(lexer.take_tokens());
+                if let Err(err) = result {
+                    return (tokens, Some(err));
+                }
+            }
+            let result = lexer.finish();
+            tokens.extend(lexer.take_tokens());
+            (tokens, result.err())
+        }
+
+        // One byte at a time, every boundary is a chunk boundary
+        for lua_file in lua_file_iter() {
+            let file_contents = fs::read(lua_file).expect("Unable to read test file");
+            assert_eq!(
+                lex_chunks(file_contents.chunks(1)),
+                lex_luster_spans(&file_contents)
+            );
+        }
+
+        // Split at every boundary into two chunks
+        let sources: &[&[u8]] = &[
+            b"local s = [==[\nlong ]] ]=] string]==] --[=[ comment ]] ]=] x = 0x1p-4 ... 3.0e+10",
+            "t = { 'ü\\u{10FFFF}\\z\r\n   \\x41\\0653', \"\\\n\" } -- é\n".as_bytes(),
+            b"a.b..c...d<=e<<f>=g>>h//i~=j::k==l--",
+            b"x = 1 -- comment\r\ny = 'a\\\nb' -- ]] '\n[[ ' ]]",
+            b"x = 'unfinished",
+            b"x = [==[ unfinished ]=]",
+            b"x = 3e",
+            b"x = '\\xZZ'",
+            b"x = y $",
+        ];
+        for source in sources {
+            let expected = lex_luster_spans(source);
+            for i in 0..=source.len() {
+                let (a, b) = source.split_at(i);
+                assert_eq!(lex_chunks(vec![a, b].into_iter()), expected);
+            }
+        }
+
+        // Tokens are available as soon as they are complete
+        let mut lexer = ChunkedLexer::new();
+        lexer.feed(b"local x = ab").unwrap();
+        assert_eq!(
+            lexer.take_tokens(),
+            [
+                (Token::Local, token::Span::new(0, 5)),
+                (Token::Name(b"x".to_vec()), token::Span::new(6, 7)),
+                (Token::Assign, token::Span::new(8, 9)),
+            ]
+        );
+        lexer.feed(b"c(").unwrap();
+        assert_eq!(
+            lexer.take_tokens(),
+            [
+                (Token::Name(b"abc".to_vec()), token::Span::new(10, 13)),
+                (Token::LeftParen, token::Span::new(13, 14)),
+            ]
+        );
+        lexer.finish().unwrap();
+        assert!(lexer.take_tokens().is_empty());
+
+        // A comment split after "--[" is lexed again when it turns out to be a long comment
+        let source = b"--[[ c ]] x = 1";
+        for i in 2..=3 {
+            let mut lexer = ChunkedLexer::new();
+            lexer.feed(&source[..i]).unwrap();
+            lexer.feed(&source[i..]).unwrap();
+            assert_eq!(
+                lexer.take_tokens(),
+                [
+                    (Token::Name(b"x".to_vec()), token::Span::new(10, 11)),
+                    (Token::Assign, token::Span::new(12, 13)),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn parallel() {
+        fn check(source: &[u8]) {
+            let expected = lexer_luster::tokenize_all(source);
+            for n_threads in 1..=8 {
+                let tokens = parallel::tokenize_all(source, n_threads);
+                match (&tokens, &expected) {
+                    (Ok(tokens), Ok(expected)) => assert!(tokens.iter().eq(expected.iter())),
+                    (Err(err), Err(expected)) => assert_eq!(err, expected),
+                    _ => panic!("{:?} != {:?}", tokens.err(), expected.as_ref().err()),
+                }
+            }
+        }
+
+        let mut all_files = vec![];
+        for lua_file in lua_file_iter() {
+            let file_contents = fs::read(lua_file).expect("Unable to read test file");
+            check(&file_contents);
+            all_files.extend_from_slice(&file_contents);
+        }
+        check(&all_files);
+
+        // Chunks starting in long strings and comments, where lexing the chunk as code fails or
+        // gives different tokens
+        let code = "x = 'a' .. \"b\" -- c\n".repeat(10);
+        let mut source = String::new();
+        for (open, close) in &[("s = [==[\n", "]==]\n"), ("--[[\n", "]]\n")] {
+            source.push_str(&code);
+            source.push_str(open);
+            source.push_str(&"' ]] ]=] \" [[ --\n".repeat(10));
+            source.push_str(close);
+            source.push_str(&code);
+        }
+        check(source.as_bytes());
+
+        // Errors
+        check(format!("{}x = 'unfinished\n{}", code, code).as_bytes());
+        check(format!("{}x = [[ unfinished\n{}", code, code).as_bytes());
+        check(format!("{}x = 3e\n{}", code, code).as_bytes());
+    }
+
+    #[test]
+    fn source_map() {
+        use source_map::{FileSpan, LexerChoice, SourceMap};
+
+        let mut source_map = SourceMap::new();
+        let main = source_map.add_file("main.lua", "x = 1\n  y = 'é' z\n".to_owned());
+        let lexed = source_map
+            .lex_dir(LUA_TEST_FILES_DIR, LexerChoice::Luster)
+            .unwrap();
+        let error = source_map.add_file("error.lua", "x = 'abc".to_owned());
+
+        assert_eq!(lexed.len(), lua_file_iter().count());
+        assert_eq!(source_map.len(), lexed.len() + 2);
+
+        for lexed_file in &lexed {
+            let file = lexed_file.file;
+            assert_ne!(file, main);
+            assert_eq!(lexed_file.error, None);
+            assert!(lexed_file.tokens.iter().all(|(_, span)| span.file == file));
+
+            let source = std::str::from_utf8(source_map.source(file)).unwrap();
+            let tokens: Vec<Token<Vec<u8>>> =
+                lexed_file.tokens.iter().map(|(t, _)| t.clone()).collect();
+            assert_eq!(Ok(tokens), lex_luster(source));
+            assert_eq!(source_map.lex_file(file, LexerChoice::Lexgen), *lexed_file);
+        }
+
+        // Locations
+        let lexed_main = source_map.lex_file(main, LexerChoice::Luster);
+        let (_, z_span) = lexed_main.tokens.last().unwrap();
+        assert_eq!(source_map.snippet(*z_span), b"z");
+        assert_eq!(source_map.location(*z_span).to_string(), "main.lua:2:11");
+
+        let first_span = lexed[0].tokens[0].1;
+        assert_eq!(
+            source_map.path(first_span.file),
+            source_map.location(first_span).path
+        );
+
+        for &lexer in &[LexerChoice::Luster, LexerChoice::Lexgen] {
+            let lexed_error = source_map.lex_file(error, lexer);
+            assert_eq!(lexed_error.tokens.len(), 2);
+            let (err, span) = lexed_error.error.unwrap();
+            assert_eq!(err, LexerError::UnfinishedShortString(b'\''));
+            assert_eq!(
+                span,
+                FileSpan {
+                    file: error,
+                    span: token::Span::new(4, 8)
+                }
+            );
+            assert_eq!(source_map.location(span).to_string(), "error.lua:1:5");
+        }
+
+        // Sources that are not valid UTF-8
+        let path = std::env::temp_dir().join("lexer_bench_source_map_bytes.lua");
+        fs::write(&path, b"x = '\xff' 1\n\xff").unwrap();
+        let bytes = source_map.load_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let lexed_bytes = source_map.lex_file(bytes, LexerChoice::Luster);
+        assert_eq!(lexed_bytes.tokens.len(), 4);
+        assert_eq!(lexed_bytes.tokens[2].0, Token::String(vec![0xff]));
+        let (err, span) = lexed_bytes.error.unwrap();
+        assert_eq!(err, LexerError::UnexpectedCharacter(0xff));
+        assert_eq!(span.span, token::Span::new(10, 10));
+
+        // lexgen lexes until the first invalid byte
+        let bytes = source_map.add_file("bytes.lua", b"x = 1\n\xff".to_vec());
+        for &lexer in &[LexerChoice::Luster, LexerChoice::Lexgen] {
+            let lexed_bytes = source_map.lex_file(bytes, lexer);
+            assert_eq!(lexed_bytes.tokens.len(), 3);
+            let (err, span) = lexed_bytes.error.unwrap();
+            assert_eq!(err, LexerError::UnexpectedCharacter(0xff));
+            assert_eq!(span.span, token::Span::new(6, 6));
+            assert_eq!(source_map.location(span).to_string(), "bytes.lua:2:1");
+        }
+    }
+
+    #[test]
+    fn buffered_tokens() {
+        use buffered::{lexgen_source, BufferedTokens, SpannedToken, TokenSource};
+
+        fn expected_at(
+            expected: &SpannedTokens,
+            idx: usize,
+        ) -> Result<Option<SpannedToken>, (LexerError, token::Span)> {
+            match expected.0.get(idx) {
+                Some((token, span)) => Ok(Some(SpannedToken {
+                    token: token.clone(),
+                    span: *span,
+                })),
+                None => match &expected.1 {
+                    Some(err) => Err(err.clone()),
+                    None => Ok(None),
+                },
+            }
+        }
+
+        fn check<L: TokenSource>(mut tokens: BufferedTokens<L>, expected: &SpannedTokens) {
+            let n_tokens = expected.0.len();
+            for idx in 0..n_tokens {
+                assert_eq!(tokens.position(), idx);
+                for n in 0..3 {
+                    assert_eq!(
+                        tokens.peek_nth(n).map(|t| t.cloned()),
+                        expected_at(expected, idx + n)
+                    );
+                }
+
+                // Rewind after consuming a few tokens, with a committed nested checkpoint
+                if idx % 7 == 0 {
+                    let prev_span = tokens.prev_span();
+                    let outer = tokens.checkpoint();
+                    assert_eq!(tokens.next_token(), expected_at(expected, idx));
+                    let inner = tokens.checkpoint();
+                    for n in 1..5 {
+                        assert_eq!(tokens.next_token(), expected_at(expected, idx + n));
+                    }
+                    tokens.commit(inner);
+                    tokens.rewind(outer);
+                    assert_eq!(tokens.position(), idx);
+                    assert_eq!(tokens.prev_span(), prev_span);
+                }
+
+                // Rewind a nested checkpoint and commit the outer one
+                if idx % 5 == 0 {
+                    let outer = tokens.checkpoint();
+                    let inner = tokens.checkpoint();
+                    assert_eq!(tokens.next_token(), expected_at(expected, idx));
+                    assert_eq!(tokens.next_token(), expected_at(expected, idx + 1));
+                    tokens.rewind(inner);
+                    tokens.commit(outer);
+                    assert_eq!(tokens.position(), idx);
+                }
+
+                assert_eq!(tokens.next_token(), expected_at(expected, idx));
+                assert_eq!(tokens.prev_span(), expected.0[idx].1);
+            }
+
+            assert_eq!(
+                tokens.peek().map(|t| t.cloned()),
+                expected_at(expected, n_tokens)
+            );
+            assert_eq!(tokens.next_token(), expected_at(expected, n_tokens));
+            assert_eq!(tokens.position(), n_tokens);
+        }
+
+        let mut sources: Vec<String> = lua_file_iter()
+            .map(|lua_file| fs::read_to_string(lua_file).expect("Unable to read test file"))
+            .collect();
+        sources.extend(
+            ["x = 'abc", "f(a, b) x = [[ a", "x = 3e + 1"]
+                .iter()
+                .map(|s| s.to_string()),
+        );
+
+        for source in &sources {
+            let expected = lex_luster_spans(source.as_bytes());
+            check(
+                BufferedTokens::new(lexer_luster::Lexer::new(source.as_bytes(), |s| {
+                    s.to_owned()
+                })),
+                &expected,
+            );
+            check(BufferedTokens::new(lexgen_source(source)), &expected);
+        }
+    }
+
+    #[test]
+    fn parser() {
+        use ast::*;
+        use parser::{ParseError, Parser};
+        use syntax::ParseErrorKind;
+        use token::{Span, TokenKind};
+
+        fn parse_luster(s: &str) -> Result<Block, ParseError> {
+            parser::parse_chunk(lexer_luster::Lexer::new(s.as_bytes(), |s| s.to_owned()))
+        }
+
+        fn parse_lexgen(s: &str) -> Result<Block, ParseError> {
+            parser::parse_chunk(buffered::lexgen_source(s))
+        }
+
+        /// Renders an expression as an S-expression, to check precedence
+        fn show(expr: &Expr) -> String {
+            fn show_call(what: String, args: &[Expr]) -> String {
+                let parts: Vec<String> =
+                    std::iter::once(what).chain(args.iter().map(show)).collect();
+                format!("({})", parts.join(" "))
+            }
+
+            match &expr.kind {
+                ExprKind::Integer(i) => i.to_string(),
+                ExprKind::Name(name) => String::from_utf8_lossy(name).into_owned(),
+                ExprKind::Field { object, name } => format!(
+                    "(Field {} {})",
+                    show(object),
+                    String::from_utf8_lossy(&name.name)
+                ),
+                ExprKind::Index { object, key } => {
+                    format!("(Index {} {})", show(object), show(key))
+                }
+                ExprKind::Call { function, args } => show_call(show(function), args),
+                ExprKind::MethodCall {
+                    object,
+                    method,
+                    args,
+                } => show_call(
+                    format!("{}:{}", show(object), String::from_utf8_lossy(&method.name)),
+                    args,
+                ),
+                ExprKind::Paren(expr) => format!("(Paren {})", show(expr)),
+                ExprKind::Unary { op, operand } => format!("({:?} {})", op, show(operand)),
+                ExprKind::Binary { op, lhs, rhs } => {
+                    format!("({:?} {} {})", op, show(lhs), show(rhs))
+                }
+                kind => format!("{:?}", kind),
+            }
+        }
+
+        // The test files parse, with the same AST with both lexers
+        for lua_file in lua_file_iter() {
+            println!("{}", lua_file.to_string_lossy());
+            let source = fs::read_to_string(lua_file).expect("Unable to read test file");
+            let source = skip_first_line_comment(&source);
+            let block = parse_luster(source).unwrap();
+            assert!(!block.stats.is_empty());
+            assert_eq!(parse_lexgen(source), Ok(block));
+        }
+
+        // Precedence and associativity
+        for (expr, expected) in &[
+            ("1 + 2 * 3", "(Add 1 (Mul 2 3))"),
+            ("1 - 2 - 3", "(Sub (Sub 1 2) 3)"),
+            ("a .. b .. c", "(Concat a (Concat b c))"),
+            ("2 ^ 3 ^ 4", "(Pow 2 (Pow 3 4))"),
+            ("-x ^ 2", "(Neg (Pow x 2))"),
+            ("2 ^ -3 ^ 2", "(Pow 2 (Neg (Pow 3 2)))"),
+            ("-a * b", "(Mul (Neg a) b)"),
+            ("not a == b", "(Equal (Not a) b)"),
+            ("a or b and c", "(Or a (And b c))"),
+            ("a < b .. c + d", "(LessThan a (Concat b (Add c d)))"),
+            ("a + b .. c", "(Concat (Add a b) c)"),
+            (
+                "a | b ~ c & d << e",
+                "(BitOr a (BitXor b (BitAnd c (ShiftLeft d e))))",
+            ),
+            ("~a // b % c", "(Mod (IDiv (BitNot a) b) c)"),
+            ("#t.x[1]:m(2)", "(Len ((Index (Field t x) 1):m 2))"),
+            (
+                "(f()) + g{}'s'",
+                "(Add (Paren (f)) ((g Table([])) String([115])))",
+            ),
+        ] {
+            let mut parser =
+                Parser::new(lexer_luster::Lexer::new(expr.as_bytes(), |s| s.to_owned()));
+            assert_eq!(show(&parser.parse_expr().unwrap()), *expected, "{}", expr);
+        }
+
+        // Statements
+        let block = parse_luster("local x <const>, y <close>, z = 1, 2").unwrap();
+        match &block.stats[0].kind {
+            StatKind::Local { vars, values } => {
+                let attribs: Vec<Option<Attrib>> = vars.iter().map(|var| var.attrib).collect();
+                assert_eq!(attribs, [Some(Attrib::Const), Some(Attrib::Close), None]);
+                assert_eq!(values.len(), 2);
+            }
+            stat => panic!("{:?}", stat),
+        }
+
+        let source = "function a.b.c:m(x, ...) return ... end";
+        let block = parse_luster(source).unwrap();
+        assert_eq!(block.stats[0].span, Span::new(0, source.len()));
+        match &block.stats[0].kind {
+            StatKind::Function { name, body } => {
+                assert_eq!(name.path.len(), 3);
+                assert_eq!(name.method.as_ref().unwrap().name, b"m");
+                assert_eq!(body.params.len(), 1);
+                assert!(body.is_vararg);
+                assert_eq!(body.span, Span::new(16, source.len()));
+                assert_eq!(body.body.ret.as_ref().unwrap().span, Span::new(25, 35));
+            }
+            stat => panic!("{:?}", stat),
+        }
+
+        let block = parse_luster("goto continue; ::continue:: t = {[1] = a, b = c; d,}").unwrap();
+        assert!(matches!(&block.stats[0].kind, StatKind::Goto(name) if name.name == b"continue"));
+        assert!(matches!(&block.stats[1].kind, StatKind::Label(name) if name.name == b"continue"));
+        match &block.stats[2].kind {
+            StatKind::Assign { targets, values } => {
+                assert_eq!(targets.len(), 1);
+                match &values[0].kind {
+                    ExprKind::Table(fields) => {
+                        assert!(matches!(fields[0], TableField::Index { .. }));
+                        assert!(matches!(fields[1], TableField::Named { .. }));
+                        assert!(matches!(fields[2], TableField::Positional(_)));
+                        assert_eq!(fields.len(), 3);
+                    }
+                    expr => panic!("{:?}", expr),
+                }
+            }
+            stat => panic!("{:?}", stat),
+        }
+
+        // Errors
+        for (source, kind, span) in &[
+            ("x = ", ParseErrorKind::UnexpectedSymbol, (3, 3)),
+            ("f() = 1", ParseErrorKind::SyntaxError, (4, 5)),
+            ("a, f() = 1", ParseErrorKind::SyntaxError, (7, 8)),
+            ("x", ParseErrorKind::SyntaxError, (1, 1)),
+            (
+                "local x <foo> = 1",
+                ParseErrorKind::UnknownAttribute(b"foo".to_vec()),
+                (9, 12),
+            ),
+            (
+                "local x <close>, y <close>",
+                ParseErrorKind::MultipleToBeClosed,
+                (20, 25),
+            ),
+            ("for i do end", ParseErrorKind::ExpectedAssignOrIn, (6, 8)),
+            (
+                "if x then",
+                ParseErrorKind::Expected(TokenKind::End),
+                (9, 9),
+            ),
+            (
+                "t = {1, 2",
+                ParseErrorKind::Expected(TokenKind::RightBrace),
+                (9, 9),
+            ),
+            (
+                "local function (x) end",
+                ParseErrorKind::Expected(TokenKind::Name),
+                (15, 16),
+            ),
+            (
+                "function f(a, 1) end",
+                ParseErrorKind::ExpectedParam,
+                (14, 15),
+            ),
+            (
+                "function f(a,) end",
+                ParseErrorKind::ExpectedParam,
+                (13, 14),
+            ),
+            ("return 1 x", ParseErrorKind::ExpectedEof, (9, 10)),
+            ("end", ParseErrorKind::ExpectedEof, (0, 3)),
+            (
+                "function f() return ... end",
+                ParseErrorKind::VarargOutsideVarargFunction,
+                (20, 23),
+            ),
+            ("o:m + 1", ParseErrorKind::ExpectedArgs, (4, 5)),
+            (
+                "x = 'abc",
+                ParseErrorKind::Lexer(LexerError::UnfinishedShortString(b'\'')),
+                (4, 8),
+            ),
+        ] {
+            let expected = Err(ParseError {
+                kind: kind.clone(),
+                span: Span::new(span.0, span.1),
+            });
+            assert_eq!(parse_luster(source), expected, "{}", source);
+            assert_eq!(parse_lexgen(source), expected, "{}", source);
+        }
+
+        // Nesting up to `MAX_DEPTH` levels, including the statement
+        with_large_stack(|| {
+            let nested = |n| format!("x = {}1{}", "(".repeat(n), ")".repeat(n));
+            assert!(parse_luster(&nested(198)).is_ok());
+            assert!(parse_lexgen(&nested(198)).is_ok());
+            let expected = Err(ParseError {
+                kind: ParseErrorKind::TooManyLevels,
+                span: Span::new(203, 204),
+            });
+            assert_eq!(parse_luster(&nested(300)), expected);
+            assert_eq!(parse_lexgen(&nested(300)), expected);
+        });
+
+        assert_eq!(
+            ParseErrorKind::Expected(TokenKind::End).to_string(),
+            "'end' expected"
+        );
+        assert_eq!(
+            ParseErrorKind::Expected(TokenKind::Name).to_string(),
+            "<name> expected"
+        );
+        assert_eq!(
+            ParseErrorKind::ExpectedParam.to_string(),
+            "<name> or '...' expected"
+        );
+        assert_eq!(
+            ParseErrorKind::TooManyLevels.to_string(),
+            "C stack overflow"
+        );
+    }
+
+    #[test]
+    fn syntax_check() {
+        use syntax::{ParseErrorKind, SyntaxError};
+        use syntax_check::check_syntax;
+        use token::{Span, TokenKind, TokenSet};
+
+        fn check_luster(s: &str) -> Result<(), SyntaxError> {
+            check_syntax(lexer_luster::Lexer::new(s.as_bytes(), |s| s.to_owned()))
+        }
+
+        /// Checks the source with both lexers, and that the error is the same as the parser's
+        fn check(s: &str) -> Result<(), SyntaxError> {
+            let result = check_luster(s);
+            let parsed =
+                parser::parse_chunk(lexer_luster::Lexer::new(s.as_bytes(), |s| s.to_owned()));
+            match (&result, parsed) {
+                (Ok(()), Ok(_)) => {}
+                (Err(err), Err(parse_err)) => {
+                    assert_eq!(err.kind, parse_err.kind, "{}", s);
+                    assert_eq!(err.span, parse_err.span, "{}", s);
+                }
+                (result, parsed) => panic!("{:?} != {:?} in {:?}", result, parsed.err(), s),
+            }
+            result
+        }
+
+        /// Also checks that the result is the same with the lexgen lexer. Not used for sources
+        /// with random edits, as lexgen accepts newlines in short strings.
+        fn check_both_lexers(s: &str) -> Result<(), SyntaxError> {
+            let result = check(s);
+            assert_eq!(check_syntax(buffered::lexgen_source(s)), result, "{:?}", s);
+            result
+        }
+
+        // The test files are valid, and have the same errors as with the parser after deleting
+        // parts of them
+        let mut random = Random::new();
+        for lua_file in lua_file_iter() {
+            println!("{}", lua_file.to_string_lossy());
+            let source = fs::read_to_string(lua_file).expect("Unable to read test file");
+            let source = skip_first_line_comment(&source);
+            assert_eq!(check_both_lexers(source), Ok(()));
+            with_deletions(source, &mut random, |edited| {
+                let _ = check(edited);
+            });
+        }
+
+        for source in &[
+            "x = ",
+            "f() = 1",
+            "a, (b) = 1",
+            "local x <foo> = 1",
+            "local x <close>, y <close>",
+            "local x <const",
+            "function f() return ... end",
+            "o:m + 1",
+            "x = 'abc",
+            "function f(a, 1) end",
+            "x = a .. b .. (c",
+        ] {
+            assert!(check_both_lexers(source).is_err());
+        }
+
+        with_large_stack(|| {
+            let nested = |n| format!("x = {}1{}", "(".repeat(n), ")".repeat(n));
+            assert_eq!(check_both_lexers(&nested(198)), Ok(()));
+            let err = check_both_lexers(&nested(300)).unwrap_err();
+            assert_eq!(err.kind, ParseErrorKind::TooManyLevels);
+        });
+
+        // Expected tokens
+        let err = check_both_lexers("for i do end").unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::ExpectedAssignOrIn);
+        assert_eq!(err.found, Some(TokenKind::Do));
+        assert_eq!(
+            err.expected,
+            TokenSet::new(&[TokenKind::Assign, TokenKind::Comma, TokenKind::In])
+        );
+
+        let err = check_both_lexers("x").unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::SyntaxError);
+        assert_eq!(err.span, Span::new(1, 1));
+        assert_eq!(err.found, None);
+        assert_eq!(
+            err.expected,
+            TokenSet::new(&[
+                TokenKind::Dot,
+                TokenKind::LeftBracket,
+                TokenKind::Colon,
+                TokenKind::LeftParen,
+                TokenKind::String,
+                TokenKind::LeftBrace,
+                TokenKind::Assign,
+                TokenKind::Comma,
+            ])
+        );
+
+        let err = check_both_lexers("if x then").unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::Expected(TokenKind::End));
+        for kind in &[
+            TokenKind::End,
+            TokenKind::Else,
+            TokenKind::ElseIf,
+            TokenKind::Return,
+            TokenKind::Local,
+            TokenKind::Name,
+        ] {
+            assert!(err.expected.contains(*kind), "{:?}", kind);
+        }
+        assert!(!err.expected.contains(TokenKind::Until));
+
+        let err = check_both_lexers("t = {1 2}").unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::Expected(TokenKind::RightBrace));
+        assert_eq!(err.found, Some(TokenKind::Integer));
+        for kind in &[
+            TokenKind::RightBrace,
+            TokenKind::Comma,
+            TokenKind::SemiColon,
+            TokenKind::Add,
+            TokenKind::Concat,
+        ] {
+            assert!(err.expected.contains(*kind), "{:?}", kind);
+        }
+        assert!(!err.expected.contains(TokenKind::LeftParen));
+
+        let err = check_both_lexers("return return").unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::UnexpectedSymbol);
+        assert!(err.expected.contains(TokenKind::SemiColon));
+        assert!(err.expected.contains(TokenKind::Function));
+        assert!(!err.expected.contains(TokenKind::Return));
+
+        // Errors that are not about an unexpected token
+        for source in &["f() = 1", "local x <foo> = 1", "x = 'abc"] {
+            assert_eq!(
+                check_both_lexers(source).unwrap_err().expected,
+                TokenSet::EMPTY
+            );
+        }
+
+        assert_eq!(
+            check_both_lexers("x = 1 +").unwrap_err().to_string(),
+            "unexpected symbol, expected one of 'function', 'nil', 'true', 'false', 'not', '-', \
+             '#', '~', '...', '(', '{', <integer>, <number>, <name>, <string>"
+        );
+    }
+
+    #[test]
+    fn cst() {
+        use cst::{SyntaxElement, SyntaxNode};
+        use syntax::{ParseErrorKind, SyntaxKind};
+        use token::{Span, TokenKind};
+
+        /// Checks that the tree covers the source, and that spans of nodes are the spans of their
+        /// children
+        fn check_tree(node: &SyntaxNode, source: &[u8]) {
+            let mut end = node.span().start;
+            for child in node.children_with_tokens() {
+                let span = child.span();
+                assert_eq!(span.start, end);
+                end = span.end;
+                match child {
+                    SyntaxElement::Node(child) => {
+                        assert_eq!(child.parent().as_ref(), Some(node));
+                        check_tree(&child, source);
+                    }
+                    SyntaxElement::Token(token) => {
+                        assert_eq!(
+                            token.text(),
+                            &source[span.start as usize..span.end as usize]
+                        );
+                    }
+                }
+            }
+            assert_eq!(end, node.span().end);
+        }
+
+        /// Parses the source, checks the tree, and checks that the error is the same as the
+        /// parser's
+        fn parse(source: &str) -> cst::Parse {
+            let parse = cst::parse(source.as_bytes());
+            let root = parse.syntax();
+            assert_eq!(root.kind(), SyntaxKind::Chunk);
+            assert_eq!(root.to_string(), source);
+            assert_eq!(root.text(), source.as_bytes());
+            assert_eq!(root.span(), Span::new(0, source.len()));
+            check_tree(&root, source.as_bytes());
+
+            if !source.starts_with('#') {
+                let parsed =
+                    parser::parse_chunk(lexer_luster::Lexer::new(source.as_bytes(), |s| {
+                        s.to_owned()
+                    }));
+                assert_eq!(parse.error(), parsed.err().as_ref(), "{:?}", source);
+            }
+            parse
+        }
+
+        // Printing the tree gives the test files, also after deleting parts of them
+        let mut random = Random::new();
+        for lua_file in lua_file_iter() {
+            println!("{}", lua_file.to_string_lossy());
+            let source = fs::read_to_string(lua_file).expect("Unable to read test file");
+            assert_eq!(parse(&source).error(), None);
+
+            // `parser` doesn't skip a first line that starts with `#`, so the edits are made
+            // without it, for comparing the errors
+            with_deletions(skip_first_line_comment(&source), &mut random, |edited| {
+                parse(edited);
+            });
+        }
+
+        for source in &[
+            "",
+            "  -- comment",
+            "x = ",
+            "f() = 1",
+            "local x <foo> = 1",
+            "local x <close>, y <close>",
+            "function f() return ... end",
+            "x = 'abc\ny = 1",
+            "x = 1 --[[ unfinished",
+            "x = a .. b .. (c",
+            "if x then else y() end end",
+            "function f(a, 1) end",
+        ] {
+            parse(source);
+        }
+
+        with_large_stack(|| {
+            let nested = |n| format!("x = {}1{}", "(".repeat(n), ")".repeat(n));
+            assert!(parse(&nested(198)).error().is_none());
+            let kind = parse(&nested(300)).error().map(|err| err.kind.clone());
+            assert_eq!(kind, Some(ParseErrorKind::TooManyLevels));
+        });
+
+        let source = "#!/usr/bin/lua\nlocal t = {a = 1, [2] = -x ^ 2} -- t\nt.a = f'' .. b .. c\n";
+        assert_eq!(
+            format!("{:#?}", parse(source).syntax()),
+            r##"Chunk@0..72
+  Shebang@0..14 "#!/usr/bin/lua"
+  Whitespace@14..15 "\n"
+  Block@15..71
+    LocalStat@15..46
+      Token(Local)@15..20 "local"
+      Whitespace@20..21 " "
+      LocalVar@21..22
+        Token(Name)@21..22 "t"
+      Whitespace@22..23 " "
+      Token(Assign)@23..24 "="
+      Whitespace@24..25 " "
+      ExprList@25..46
+        TableExpr@25..46
+          Token(LeftBrace)@25..26 "{"
+          NamedField@26..31
+            Token(Name)@26..27 "a"
+            Whitespace@27..28 " "
+            Token(Assign)@28..29 "="
+            Whitespace@29..30 " "
+            LiteralExpr@30..31
+              Token(Integer)@30..31 "1"
+          Token(Comma)@31..32 ","
+          Whitespace@32..33 " "
+          IndexField@33..45
+            Token(LeftBracket)@33..34 "["
+            LiteralExpr@34..35
+              Token(Integer)@34..35 "2"
+            Token(RightBracket)@35..36 "]"
+            Whitespace@36..37 " "
+            Token(Assign)@37..38 "="
+            Whitespace@38..39 " "
+            UnaryExpr@39..45
+              Token(Minus)@39..40 "-"
+              BinaryExpr@40..45
+                NameExpr@40..41
+                  Token(Name)@40..41 "x"
+                Whitespace@41..42 " "
+                Token(Pow)@42..43 "^"
+                Whitespace@43..44 " "
+                LiteralExpr@44..45
+                  Token(Integer)@44..45 "2"
+          Token(RightBrace)@45..46 "}"
+    Whitespace@46..47 " "
+    Comment@47..51 "-- t"
+    Whitespace@51..52 "\n"
+    AssignStat@52..71
+      VarList@52..55
+        FieldExpr@52..55
+          NameExpr@52..53
+            Token(Name)@52..53 "t"
+          Token(Dot)@53..54 "."
+          Token(Name)@54..55 "a"
+      Whitespace@55..56 " "
+      Token(Assign)@56..57 "="
+      Whitespace@57..58 " "
+      ExprList@58..71
+        BinaryExpr@58..71
+          CallExpr@58..61
+            NameExpr@58..59
+              Token(Name)@58..59 "f"
+            CallArgs@59..61
+              LiteralExpr@59..61
+                Token(String)@59..61 "''"
+          Whitespace@61..62 " "
+          Token(Concat)@62..64 ".."
+          Whitespace@64..65 " "
+          BinaryExpr@65..71
+            NameExpr@65..66
+              Token(Name)@65..66 "b"
+            Whitespace@66..67 " "
+            Token(Concat)@67..69 ".."
+            Whitespace@69..70 " "
+            NameExpr@70..71
+              Token(Name)@70..71 "c"
+  Whitespace@71..72 "\n"
+"##
+        );
+
+        // Navigation
+        let tree = parse("local x = 1\nx = x + 1").syntax();
+        let token = tree.token_at_offset(16).unwrap();
+        assert_eq!(token.kind(), SyntaxKind::Token(TokenKind::Name));
+        assert_eq!(token.span(), Span::new(16, 17));
+        let ancestors: Vec<SyntaxKind> =
+            std::iter::successors(Some(token.parent()), |node| node.parent())
+                .map(|node| node.kind())
+                .collect();
+        assert_eq!(
+            ancestors,
+            [
+                SyntaxKind::NameExpr,
+                SyntaxKind::BinaryExpr,
+                SyntaxKind::ExprList,
+                SyntaxKind::AssignStat,
+                SyntaxKind::Block,
+                SyntaxKind::Chunk,
+            ]
+        );
+        assert_eq!(
+            tree.token_at_offset(11).unwrap().kind(),
+            SyntaxKind::Whitespace
+        );
+        assert_eq!(tree.token_at_offset(21), None);
+
+        // Tokens with the same text are shared
+        let names: Vec<_> = tree.tokens().filter(|token| token.text() == b"x").collect();
+        assert_eq!(names.len(), 3);
+        assert!(std::sync::Arc::ptr_eq(names[0].green(), names[2].green()));
+
+        // Errors: the rest of the source is in an `Error` node
+        let parse_ = parse("x = 1 + -- c\n= 2");
+        assert_eq!(
+            parse_.error().unwrap().kind,
+            ParseErrorKind::UnexpectedSymbol
+        );
+        let error = parse_
+            .syntax()
+            .descendants_with_tokens()
+            .find_map(|element| match element {
+                SyntaxElement::Node(node) if node.kind() == SyntaxKind::Error => Some(node),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(error.span(), Span::new(13, 16));
+        // In the innermost node that was open at the error
+        assert_eq!(error.parent().unwrap().kind(), SyntaxKind::BinaryExpr);
+
+        let parse_ = parse("x = 'abc\ny = 1");
+        assert!(matches!(
+            parse_.error().unwrap().kind,
+            ParseErrorKind::Lexer(_)
+        ));
+        let tokens: Vec<_> = parse_
+            .syntax()
+            .tokens()
+            .map(|token| (token.kind(), token.span()))
+            .collect();
+        assert_eq!(
+            tokens.last(),
+            Some(&(SyntaxKind::ErrorToken, Span::new(4, 14)))
+        );
+
+        // Whitespace and comments before a lexer error are trivia
+        let (tokens, error) = cst::lex_lossless(b"x -- c\n  'abc");
+        let tokens: Vec<_> = tokens
+            .iter()
+            .map(|token| (token.kind, token.span))
+            .collect();
+        assert_eq!(
+            tokens,
+            [
+                (SyntaxKind::Token(TokenKind::Name), Span::new(0, 1)),
+                (SyntaxKind::Whitespace, Span::new(1, 2)),
+                (SyntaxKind::Comment, Span::new(2, 6)),
+                (SyntaxKind::Whitespace, Span::new(6, 9)),
+                (SyntaxKind::ErrorToken, Span::new(9, 13)),
+            ]
+        );
+        assert_eq!(
+            error,
+            Some((LexerError::UnfinishedShortString(b'\''), Span::new(9, 13)))
+        );
+    }
+
+    #[test]
+    fn diagnostics() {
+        use super::diagnostic::Diagnostic;
+
+        let cases: &[(&str, &str)] = &[
+            (
+                "x = [==[\nabc\n  foo",
+                "\
+error: unfinished long string
+ --> test.lua:3:6
+  |
+3 |   foo
+  |      ^
+note: long string starts here
+ --> test.lua:1:5
+  |
+1 | x = [==[
+  |     ^^^^
+",
+            ),
+            (
+                "--[[ comment\n",
+                "\
+error: unfinished long string
+ --> test.lua:2:1
+  |
+2 |
+  | ^
+note: long comment starts here
+ --> test.lua:1:1
+  |
+1 | --[[ comment
+  | ^^^^
+",
+            ),
+            (
+                "x = 1\ny = \"abc\n",
+                "\
+error: short string not finished, expected matching \"
+ --> test.lua:2:9
+  |
+2 | y = \"abc
+  |         ^
+note: string starts here
+ --> test.lua:2:5
+  |
+2 | y = \"abc
+  |     ^
+",
+            ),
+            (
+                "\tx = 1e+ + 2",
+                "\
+error: malformed number
+ --> test.lua:1:6
+  |
+1 | \tx = 1e+ + 2
+  | \t    ^^^
+",
+            ),
+            (
+                "a\r\nb\n\rc = 'ğü\\u{zz}'",
+                "\
+error: missing '}' in \\u{xxxx} escape
+ --> test.lua:3:5
+  |
+3 | c = 'ğü\\u{zz}'
+  |     ^^^^^^
+",
+            ),
+        ];
+
+        for (source, expected) in cases {
+            for (err, span) in &[luster_error(source), lexgen_error(source)] {
+                let diagnostic = Diagnostic::from_lexer_error(source.as_bytes(), err, *span);
+                assert_eq!(diagnostic.render("test.lua", source.as_bytes()), *expected);
+            }
+        }
+    }
+
+    #[test]
+    fn lua_error_messages() {
+        use super::diagnostic::lua_error_message;
+
+        // Last field is whether lexgen reports the error. Of escape sequences lexgen only checks
+        // digits and the closing brace of `\u{...}`, and it doesn't check long string delimiters.
+        let cases: &[(&str, &str, bool)] = &[
+            (
+                "x = \"abc\ny",
+                "chunk:1: unfinished string near '\"abc'",
+                true,
+            ),
+            ("x = 'abc", "chunk:1: unfinished string near <eof>", true),
+            ("x = 'abc\\", "chunk:1: unfinished string near <eof>", true),
+            (
+                "x = 1\ny = [==[\nabc",
+                "chunk:3: unfinished long string (starting at line 2) near <eof>",
+                true,
+            ),
+            (
+                "--[[ x\n\n",
+                "chunk:3: unfinished long comment (starting at line 1) near <eof>",
+                true,
+            ),
+            ("x = 3abc", "chunk:1: malformed number near '3abc'", true),
+            ("x = 1e+ + 2", "chunk:1: malformed number near '1e+'", true),
+            (
+                "x = [=x",
+                "chunk:1: invalid long string delimiter near '[='",
+                false,
+            ),
+            ("x = @", "chunk:1: unexpected symbol near '@'", true),
+            ("x = \x01", "chunk:1: unexpected symbol near '<\\1>'", true),
+            // Escape sequences before the invalid one are decoded
+            (
+                "x = 'a\\65b\\q'",
+                "chunk:1: invalid escape sequence near ''aAb\\q'",
+                false,
+            ),
+            (
+                "x = 'a\\\nb\\q'",
+                "chunk:2: invalid escape sequence near ''a\nb\\q'",
+                false,
+            ),
+            // Text ends at a NUL
+            (
+                "x = '\\0abc\\q'",
+                "chunk:1: invalid escape sequence near '''",
+                false,
+            ),
+            (
+                "x = '\\xg'",
+                "chunk:1: hexadecimal digit expected near ''\\xg'",
+                false,
+            ),
+            (
+                "x = '\\x5g'",
+                "chunk:1: hexadecimal digit expected near ''\\x5g'",
+                false,
+            ),
+            (
+                "x = 'ğ\\x",
+                "chunk:1: hexadecimal digit expected near ''ğ\\x'",
+                true,
+            ),
+            (
+                "x = '\\u123'",
+                "chunk:1: missing '{' in \\u{xxxx} near ''\\u1'",
+                false,
+            ),
+            (
+                "x = '\\u{zz}'",
+                "chunk:1: hexadecimal digit expected near ''\\u{z'",
+                true,
+            ),
+            (
+                "x = '\\u{12x'",
+                "chunk:1: missing '}' in \\u{xxxx} near ''\\u{12x'",
+                true,
+            ),
+            (
+                "x = '\\u{80000000}'",
+                "chunk:1: UTF-8 value too large near ''\\u{80000000'",
+                false,
+            ),
+            (
+                "x = '\\256'",
+                "chunk:1: decimal escape too large near ''\\256''",
+                false,
+            ),
+        ];
+
+        for (source, expected, lexgen) in cases {
+            let (err, span) = luster_error(source);
+            let message = lua_error_message("chunk", source.as_bytes(), &err, span);
+            assert_eq!(message, *expected, "{:?}", source);
+
+            if *lexgen {
+                let (err, span) = lexgen_error(source);
+                let message = lua_error_message("chunk", source.as_bytes(), &err, span);
+                assert_eq!(message, *expected, "{:?}", source);
+            }
+        }
+    }
+
+    #[test]
+    fn warnings() {
+        use warning::{LexerWarning, Warning};
+
+        fn luster_warnings(s: &str) -> Vec<Warning> {
+            let mut lexer = lexer_luster::Lexer::new(s.as_bytes(), |s| s.to_owned());
+            lexer.enable_warnings();
+            while lexer.read_token().unwrap().is_some() {}
+            lexer.take_warnings()
+        }
+
+        fn lexgen_warnings(s: &str) -> Vec<Warning> {
+            let mut lexer = lexer_lexgen::lex_with_warnings(s);
+            for token in &mut lexer {
+                token.unwrap();
+            }
+            lexer.take_warnings()
+        }
+
+        let cases: &[(&str, &[(LexerWarning, &str)])] = &[
+            (
+                "x = '\\0123'",
+                &[(LexerWarning::DecimalEscapeFollowedByDigit, "\\012")],
+            ),
+            ("x = '\\012' .. '\\1a'", &[]),
+            (
+                "x = 'abc\\z  '",
+                &[(LexerWarning::EscapeZAtEndOfString, "\\z  ")],
+            ),
+            (
+                "x = 'abc\\z\n  \n'",
+                &[(LexerWarning::EscapeZAtEndOfString, "\\z\n  \n")],
+            ),
+            ("x = 'a\\z  b'", &[]),
+            (
+                "--[[ ]=] ]]\nx",
+                &[(LexerWarning::MismatchedLongBracketInComment, "]=]")],
+            ),
+            (
+                "--[=[ ]] ]==] ]=]",
+                &[(LexerWarning::MismatchedLongBracketInComment, "]==]")],
+            ),
+            // Not reported in strings
+            ("x = [[ ]=] ]]", &[]),
+            (
+                "a\nb\r\nc\nd\r\n",
+                &[(LexerWarning::MixedLineEndings, "\r\n")],
+            ),
+            ("a\r\nb\r\n", &[]),
+            // Newlines in strings are checked too
+            ("a\r\nx = '\\\n'", &[(LexerWarning::MixedLineEndings, "\n")]),
+            (
+                "\tx = 1\n  y = 2\n \t-- c\n\t\n",
+                &[
+                    (LexerWarning::TabInIndentation, "\t"),
+                    (LexerWarning::TabInIndentation, " \t"),
+                ],
+            ),
+        ];
+
+        for (source, expected) in cases {
+            for warnings in &[luster_warnings(source), lexgen_warnings(source)] {
+                let warnings: Vec<(LexerWarning, &str)> = warnings
+                    .iter()
+                    .map(|w| {
+                        let span = w.span.start as usize..w.span.end as usize;
+                        (w.kind, &source[span])
+                    })
+                    .collect();
+                assert_eq!(&warnings, expected, "{:?}", source);
+            }
+        }
+
+        // Warnings don't affect tokens, and both lexers report the same warnings
+        for lua_file in lua_file_iter() {
+            let file_contents = fs::read_to_string(lua_file).expect("Unable to read test file");
+
+            let mut lexer = lexer_luster::Lexer::new(file_contents.as_bytes(), |s| s.to_owned());
+            lexer.enable_warnings();
+            let mut tokens = vec![];
+            while let Some(token) = lexer.read_token().unwrap() {
+                tokens.push(token);
+            }
+            assert_eq!(Ok(tokens), lex_luster(&file_contents));
+
+            let lexgen_tokens: Vec<Token<Vec<u8>>> =
+                lexer_lexgen::lex_with_warnings(&file_contents)
+                    .map(|t| t.unwrap().1)
+                    .collect();
+            assert_eq!(Ok(lexgen_tokens), lex_lexgen(&file_contents));
+
+            assert_eq!(lexer.take_warnings(), lexgen_warnings(&file_contents));
+        }
+    }
+
+    #[test]
+    fn incremental() {
+        use incremental::IncrementalLexer;
+
+        fn lexed(lexer: &IncrementalLexer) -> SpannedTokens {
+            let tokens = lexer.tokens().iter().cloned();
+            (
+                tokens.zip(lexer.spans().iter().copied()).collect(),
+                lexer.error().cloned(),
+            )
+        }
+
+        // xorshift, for reproducible edits
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        let mut random = |n: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % n as u64) as usize
+        };
+
+        // Edits that change how the rest of the source is lexed, and some that don't
+        let fragments: &[&[u8]] = &[
+            b"",
+            b" ",
+            b"\n",
+            b"\r\n",
+            b"-",
+            b"--",
+            b"--[[",
+            b"[[",
+            b"]]",
+            b"[==[",
+            b"]==]",
+            b"\"",
+            b"'",
+            b"\\",
+            b"x",
+            b"1",
+            b".",
+            b"e",
+            b"=",
+            b"0x",
+            b"local x = 1\n",
+        ];
+
+        for lua_file in lua_file_iter() {
+            let file_contents = fs::read(lua_file).expect("Unable to read test file");
+            let mut lexer = IncrementalLexer::new(file_contents);
+            assert_eq!(lexed(&lexer), lex_luster_spans(lexer.source()));
+
+            for _ in 0..20 {
+                let len = lexer.source().len();
+                let start = random(len + 1);
+                let end = (start + random(8)).min(len);
+                let text = fragments[random(fragments.len())];
+
+                let old_tokens = lexer.tokens().to_vec();
+                let changed = lexer.edit(start..end, text);
+                assert_eq!(lexed(&lexer), lex_luster_spans(lexer.source()));
+
+                // Tokens outside of the changed range are the old ones
+                let tokens = lexer.tokens();
+                let n_after = tokens.len() - changed.end;
+                assert_eq!(tokens[..changed.start], old_tokens[..changed.start]);
+                assert_eq!(
+                    tokens[changed.end..],
+                    old_tokens[old_tokens.len() - n_after..]
+                );
+            }
+        }
+
+        // Edits that are re-lexed until the end of the source
+        let mut lexer = IncrementalLexer::new(b"a = 1 b = 2".to_vec());
+        assert_eq!(lexer.edit(4..4, b"[["), 2..2);
+        assert_eq!(lexer.error().unwrap().0, LexerError::UnfinishedLongString);
+        assert_eq!(lexer.edit(4..6, b""), 2..6);
+        assert_eq!(lexer.error(), None);
+
+        // Edits that re-synchronise
+        assert_eq!(lexer.edit(6..6, b"cc"), 3..4);
+        assert_eq!(lexer.tokens()[3], Token::Name(b"ccb".to_vec()));
+        assert_eq!(lexer.spans()[4], token::Span::new(10, 11));
+    }
+
+    #[test]
+    fn line_states() {
+        use line_state::{lex_line_lexgen, lex_line_luster, lines, LexedLine, LineState};
+
+        type LexLine<'a> = &'a dyn Fn(&[u8], LineState) -> LexedLine;
+
+        // Compares line-by-line lexing with whole-file lexing. Tokens that start on a previous
+        // line should only be strings, starting at the start of the line, with the end of the
+        // whole string as contents.
+        fn check(source: &[u8], whole: Vec<(Token<Vec<u8>>, token::Span)>, lex_line: LexLine) {
+            let mut state = LineState::Normal;
+            let mut line_start = 0;
+            let mut tokens = vec![];
+            for line in lines(source) {
+                let lexed = lex_line(line, state);
+                for (token, span) in lexed.tokens.into_iter().zip(lexed.spans) {
+                    let span = token::Span::new(
+                        line_start + span.start as usize,
+                        line_start + span.end as usize,
+                    );
+                    tokens.push((token, span));
+                }
+                state = lexed.end_state;
+                line_start += line.len();
+            }
+            assert_eq!(state, LineState::Normal);
+
+            assert_eq!(tokens.len(), whole.len());
+            for ((token, span), (whole_token, whole_span)) in tokens.into_iter().zip(whole) {
+                assert_eq!(span.end, whole_span.end);
+                if span.start == whole_span.start {
+                    assert_eq!(token, whole_token);
+                } else {
+                    assert!(span.start > whole_span.start);
+                    match (token, whole_token) {
+                        (Token::String(part), Token::String(whole)) => {
+                            assert!(whole.ends_with(&part), "{:?} {:?}", part, whole)
+                        }
+                        (token, whole_token) => panic!("{:?} {:?}", token, whole_token),
+                    }
+                }
+            }
+        }
+
+        let lex_luster_line = |line: &[u8], state| lex_line_luster(line, state).unwrap();
+        let lex_lexgen_line = |line: &[u8], state| {
+            lex_line_lexgen(std::str::from_utf8(line).unwrap(), state).unwrap()
+        };
+
+        for lua_file in lua_file_iter() {
+            let file_contents = fs::read_to_string(lua_file).expect("Unable to read test file");
+            let source = file_contents.as_bytes();
+
+            let mut lexer = lexer_luster::Lexer::new(source, |s| s.to_owned());
+            let mut whole = vec![];
+            while let Some(token) = lexer.read_token().unwrap() {
+                whole.push((
+                    token,
+                    token::Span::new(lexer.token_start(), lexer.byte_offset()),
+                ));
+            }
+            check(source, whole, &lex_luster_line);
+
+            let whole = lexer_lexgen::Lexer::new(&file_contents)
+                .map(|t| {
+                    let (start, token, end) = t.unwrap();
+                    (token, token::Span::new(start.byte_idx, end.byte_idx))
+                })
+                .collect();
+            check(source, whole, &lex_lexgen_line);
+        }
+
+        // End states
+        let cases: &[(&str, LineState, LineState)] = &[
+            ("x = 1\n", LineState::Normal, LineState::Normal),
+            (
+                "x = [==[\n",
+                LineState::Normal,
+                LineState::LongString { level: 2 },
+            ),
+            (
+                "]=] ]]\n",
+                LineState::LongString { level: 0 },
+                LineState::Normal,
+            ),
+            (
+                "]]\n",
+                LineState::LongString { level: 1 },
+                LineState::LongString { level: 1 },
+            ),
+            (
+                "--[=[ x\n",
+                LineState::Normal,
+                LineState::LongComment { level: 1 },
+            ),
+            (
+                "]=] x\n",
+                LineState::LongComment { level: 1 },
+                LineState::Normal,
+            ),
+            (
+                "x = 'a\\\n",
+                LineState::Normal,
+                LineState::ShortString { quote: b'\'' },
+            ),
+            (
+                "x = \"a\\z  \n",
+                LineState::Normal,
+                LineState::EscapeZ { quote: b'"' },
+            ),
+            (
+                "\n",
+                LineState::EscapeZ { quote: b'"' },
+                LineState::EscapeZ { quote: b'"' },
+            ),
+            (
+                "  a\"\n",
+                LineState::EscapeZ { quote: b'"' },
+                LineState::Normal,
+            ),
+        ];
+        for (line, state, end_state) in cases {
+            assert_eq!(
+                lex_luster_line(line.as_bytes(), *state).end_state,
+                *end_state
+            );
+            assert_eq!(
+                lex_lexgen_line(line.as_bytes(), *state).end_state,
+                *end_state
+            );
+        }
+
+        // Tokens that start on a previous line
+        let lexed = lex_luster_line(b"  a\" x\n", LineState::EscapeZ { quote: b'"' });
+        assert_eq!(lexed.tokens[0], Token::String(b"a".to_vec()));
+        assert_eq!(lexed.spans[0], token::Span::new(0, 4));
+
+        // A newline at the start of a line in a long string is not skipped
+        for line in &["\n]=]", "\r]=]", "\r\n]=]", "\n\r]=]"] {
+            for lex_line in &[&lex_luster_line as LexLine, &lex_lexgen_line] {
+                let lexed = lex_line(line.as_bytes(), LineState::LongString { level: 1 });
+                assert_eq!(lexed.tokens, [Token::String(b"\n".to_vec())], "{:?}", line);
+                assert_eq!(lexed.spans, [token::Span::new(0, line.len())]);
+            }
+        }
+
+        // Short strings that don't continue on the next line are errors
+        assert_eq!(
+            lex_line_luster(b"x = 'a\n", LineState::Normal),
+            Err((
+                LexerError::UnfinishedShortString(b'\''),
+                token::Span::new(4, 6)
+            ))
+        );
+        assert_eq!(
+            lines(b"a\r\nb\n\rc\rd").collect::<Vec<_>>(),
+            [&b"a\r\n"[..], b"b\n\r", b"c\r", b"d"]
+        );
+    }
+
+    #[test]
+    fn line_index() {
+        use line_index::{ColumnUnit, LineCol, LineIndex};
+
+        // Naive line and columns of a character boundary, with LSP newlines
+        fn naive_line_col(s: &str, offset: usize, unit: ColumnUnit) -> LineCol {
+            let before = &s[..offset];
+            let line_start = before.rfind(['\n', '\r']).map_or(0, |i| i + 1);
+            let mut line = before.matches('\n').count() + before.matches('\r').count()
+                - before.matches("\r\n").count();
+            // Offset between "\r" and "\n" is on the line the newline ends
+            let line_start = if before.ends_with('\r') && s[offset..].starts_with('\n') {
+                line -= 1;
+                before[..line_start - 1]
+                    .rfind(['\n', '\r'])
+                    .map_or(0, |i| i + 1)
+            } else {
+                line_start
+            };
+            let line_text = &before[line_start..];
+            let col = match unit {
+                ColumnUnit::Utf8 => line_text.len(),
+                ColumnUnit::Utf16 => line_text.encode_utf16().count(),
+                ColumnUnit::Char => line_text.chars().count(),
+            };
+            LineCol {
+                line: line as u32,
+                col: col as u32,
+            }
+        }
+
+        let units = [ColumnUnit::Utf8, ColumnUnit::Utf16, ColumnUnit::Char];
+
+        let mut n_non_ascii_files = 0;
+        for lua_file in lua_file_iter() {
+            let file_contents = fs::read_to_string(lua_file).expect("Unable to read test file");
+            if file_contents.is_ascii() {
+                continue;
+            }
+            n_non_ascii_files += 1;
+
+            let index = LineIndex::new(&file_contents);
+            for (offset, _) in file_contents
+                .char_indices()
+                .chain(std::iter::once((file_contents.len(), ' ')))
+            {
+                for &unit in &units {
+                    let line_col = index.line_col(offset, unit);
+                    assert_eq!(line_col, naive_line_col(&file_contents, offset, unit));
+                    if !file_contents[..offset].ends_with('\r')
+                        || !file_contents[offset..].starts_with('\n')
+                    {
+                        assert_eq!(index.offset(line_col, unit), Some(offset));
+                    }
+                }
+            }
+        }
+        assert!(n_non_ascii_files != 0);
+
+        // Lines
+        let index = LineIndex::new("a\nb\r\nc\rd\n");
+        assert_eq!(index.line_count(), 5);
+        assert_eq!(index.line_range(1), Some(2..3));
+        assert_eq!(index.line_range(4), Some(9..9));
+        assert_eq!(index.line_range(5), None);
+        assert_eq!(index.line(4), 1);
+        assert_eq!(index.line(5), 2);
+
+        // Surrogate pairs, offsets in characters, and columns past the end of the line
+        let s = "x = '😀é'\ny";
+        let index = LineIndex::new(s);
+        let pos = |line, col| LineCol { line, col };
+        assert_eq!(index.line_col(9, ColumnUnit::Utf16), pos(0, 7));
+        assert_eq!(index.line_col(9, ColumnUnit::Char), pos(0, 6));
+        assert_eq!(index.line_col(7, ColumnUnit::Utf16), pos(0, 5));
+        assert_eq!(index.line_col(100, ColumnUnit::Char), pos(1, 1));
+        assert_eq!(index.offset(pos(0, 6), ColumnUnit::Utf16), Some(5));
+        assert_eq!(index.offset(pos(0, 7), ColumnUnit::Utf16), Some(9));
+        assert_eq!(index.offset(pos(0, 7), ColumnUnit::Utf8), Some(5));
+        assert_eq!(index.offset(pos(0, 100), ColumnUnit::Char), Some(12));
+        assert_eq!(index.offset(pos(2, 0), ColumnUnit::Char), None);
+
+        // Display columns
+        let s = "\tx\n  \té\ty";
+        let index = LineIndex::new(s);
+        assert_eq!(index.display_col(1), 4);
+        assert_eq!(index.display_col(2), 5);
+        assert_eq!(index.display_col(6), 4);
+        assert_eq!(index.display_col(9), 8);
+        assert_eq!(index.display_col(10), 9);
+        let index = index.with_tab_width(8);
+        assert_eq!(index.display_col(1), 8);
+        assert_eq!(index.display_col(9), 16);
+        assert_eq!(index.display_col(10), 17);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize_roundtrip() {
+        use serialize::*;
+        use token::Span;
+        use token_buffer::TokenBuffer;
+
+        // Token, bits of the float if it's a float, and span. Floats are compared by their bits,
+        // so that NaNs are compared too.
+        type TokenBits<'a> = (Token<&'a [u8]>, Option<u64>, Span);
+
+        fn token_bits(tokens: &TokenBuffer) -> Vec<TokenBits<'_>> {
+            tokens
+                .iter()
+                .map(|(token, span)| match token {
+                    Token::Float(f) => (Token::Float(0.0), Some(f.to_bits()), span),
+                    token => (token, None, span),
+                })
+                .collect()
+        }
+
+        fn check_roundtrip(tokens: &TokenBuffer) {
+            let expected = token_bits(tokens);
+
+            let mut json = vec![];
+            write_json_lines(tokens, &mut json).unwrap();
+            let from_json = read_json_lines(json.as_slice()).unwrap();
+            assert_eq!(token_bits(&from_json), expected);
+
+            let mut binary = vec![];
+            write_binary(tokens, &mut binary).unwrap();
+            let from_binary = read_binary(binary.as_slice()).unwrap();
+            assert_eq!(token_bits(&from_binary), expected);
+        }
+
+        for lua_file in lua_file_iter() {
+            let file_contents = fs::read_to_string(lua_file).expect("Unable to read test file");
+            check_roundtrip(&lexer_luster::tokenize_all(file_contents.as_bytes()).unwrap());
+        }
+
+        // Floats that are not finite, and floats that need all of their digits to roundtrip
+        let tokens = lexer_luster::tokenize_all(b"x = 1e9999 + -0x1p1024 + 0.1 + 1e-320").unwrap();
+        check_roundtrip(&tokens);
+
+        let mut json = vec![];
+        write_json_lines(&tokens, &mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.contains("{\"Float\":\"inf\"}"), "{}", json);
+
+        let mut tokens = TokenBuffer::new();
+        tokens.push_token(Token::<&[u8]>::Float(f64::NAN), Span::new(0, 1));
+        tokens.push_token(Token::<&[u8]>::Float(-f64::NAN), Span::new(2, 3));
+        tokens.push_token(
+            Token::<&[u8]>::Float(f64::from_bits(0x7ff0_0000_0000_0001)),
+            Span::new(4, 5),
+        );
+        check_roundtrip(&tokens);
+
+        // A corrupt length of a payload
+        let mut binary = vec![];
+        write_binary(&lexer_luster::tokenize_all(b"x").unwrap(), &mut binary).unwrap();
+        assert_eq!(binary.pop(), Some(b'x'));
+        assert_eq!(binary.pop(), Some(1));
+        binary.push(253); // a varint in the next 8 bytes
+        binary.extend_from_slice(&(1u64 << 60).to_le_bytes());
+        let err = read_binary(binary.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Strings that are not valid UTF-8
+        let tokens = lexer_luster::tokenize_all(b"local x = '\\xff' .. 10").unwrap();
+        check_roundtrip(&tokens);
+
+        let mut json = vec![];
+        write_json_lines(&tokens, &mut json).unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "{\"span\":{\"start\":0,\"end\":5},\"token\":\"Local\"}\n\
+             {\"span\":{\"start\":6,\"end\":7},\"token\":{\"Name\":\"x\"}}\n\
+             {\"span\":{\"start\":8,\"end\":9},\"token\":\"Assign\"}\n\
+             {\"span\":{\"start\":10,\"end\":16},\"token\":{\"String\":[255]}}\n\
+             {\"span\":{\"start\":17,\"end\":19},\"token\":\"Concat\"}\n\
+             {\"span\":{\"start\":20,\"end\":22},\"token\":{\"Integer\":10}}\n"
+        );
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn token_stream() {
+        use futures_core::Stream;
+        use futures_io::AsyncRead;
+        use std::pin::Pin;
+        use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+        use stream::{StreamError, TokenStream};
+
+        /// Source that is read in chunks and is not ready before every chunk. Fails at the end if
+        /// `fail` is set.
+        struct ChunkedSource<'a> {
+            chunks: std::slice::Chunks<'a, u8>,
+            ready: bool,
+            fail: bool,
+        }
+
+        impl AsyncRead for ChunkedSource<'_> {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context,
+                buf: &mut [u8],
+            ) -> Poll<io::Result<usize>> {
+                if !self.ready {
+                    self.ready = true;
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                self.ready = false;
+
+                match self.chunks.next() {
+                    Some(chunk) => {
+                        buf[..chunk.len()].copy_from_slice(chunk);
+                        Poll::Ready(Ok(chunk.len()))
+                    }
+                    None if self.fail => Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::ConnectionReset,
+                        "connection reset",
+                    ))),
+                    None => Poll::Ready(Ok(0)),
+                }
+            }
+        }
+
+        fn source(s: &[u8], fail: bool) -> ChunkedSource<'_> {
+            ChunkedSource {
+                chunks: s.chunks(7),
+                ready: false,
+                fail,
+            }
+        }
+
+        // Minimal executor: polls the stream to the end on the current thread. Sources wake the
+        // task right away, so the stream is polled again after `Pending` without waiting.
+        fn collect<S: Stream + Unpin>(mut stream: S) -> Vec<S::Item> {
+            fn noop_raw_waker() -> RawWaker {
+                fn clone(_: *const ()) -> RawWaker {
+                    noop_raw_waker()
+                }
+                fn noop(_: *const ()) {}
+                static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+                RawWaker::new(std::ptr::null(), &VTABLE)
+            }
+
+            let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+            let mut cx = Context::from_waker(&waker);
+            let mut items = vec![];
+            loop {
+                match Pin::new(&mut stream).poll_next(&mut cx) {
+                    Poll::Ready(Some(item)) => items.push(item),
+                    Poll::Ready(None) => return items,
+                    Poll::Pending => {}
+                }
+            }
+        }
+
+        for lua_file in lua_file_iter() {
+            let file_contents = fs::read_to_string(lua_file).expect("Unable to read test file");
+            let tokens: Result<Vec<_>, _> =
+                collect(TokenStream::new(source(file_contents.as_bytes(), false)))
+                    .into_iter()
+                    .collect();
+            assert_eq!(tokens.unwrap(), lex_luster(&file_contents).unwrap());
+        }
+
+        // Tokens before an error are yielded before the error
+        let items = collect(TokenStream::new(source(b"x = 'abc\n y", false)));
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[1].as_ref().unwrap(), &Token::Assign);
+        assert!(matches!(
+            items[2],
+            Err(StreamError::Lexer(LexerError::UnfinishedShortString(b'\'')))
+        ));
+
+        let items = collect(TokenStream::new(source(b"x = 1", true)));
+        assert_eq!(items.len(), 3);
+        match &items[2] {
+            Err(StreamError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::ConnectionReset),
+            item => panic!("Unexpected item: {:?}", item),
+        }
+    }
 }
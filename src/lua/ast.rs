@@ -0,0 +1,286 @@
+// Abstract syntax tree of Lua 5.4, produced by `parser`.
+//
+// Nodes have the spans of their source text. Parentheses are kept as `ExprKind::Paren`, as they
+// truncate multiple results to one value. Method calls and method definitions are kept as they
+// are written, and not desugared into calls and functions with a `self` parameter.
+
+use super::token::{Span, TokenKind};
+
+/// A sequence of statements, optionally ending with a `return`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Block {
+    pub stats: Vec<Stat>,
+    pub ret: Option<Return>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Return {
+    pub values: Vec<Expr>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Name {
+    pub name: Vec<u8>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stat {
+    pub kind: StatKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatKind {
+    /// `targets = values`. Targets are names, fields, or indexing expressions.
+    Assign {
+        targets: Vec<Expr>,
+        values: Vec<Expr>,
+    },
+    /// A function or method call
+    Call(Expr),
+    /// `::name::`
+    Label(Name),
+    Break,
+    Goto(Name),
+    Do(Block),
+    While {
+        cond: Expr,
+        body: Block,
+    },
+    Repeat {
+        body: Block,
+        cond: Expr,
+    },
+    /// `if` and `elseif` conditions with their blocks, and the `else` block
+    If {
+        branches: Vec<(Expr, Block)>,
+        else_block: Option<Block>,
+    },
+    /// `for var = start, limit, step do body end`
+    NumericFor {
+        var: Name,
+        start: Expr,
+        limit: Expr,
+        step: Option<Expr>,
+        body: Block,
+    },
+    /// `for names in exprs do body end`
+    GenericFor {
+        names: Vec<Name>,
+        exprs: Vec<Expr>,
+        body: Block,
+    },
+    /// `function name body`
+    Function {
+        name: FuncName,
+        body: FuncBody,
+    },
+    /// `local function name body`
+    LocalFunction {
+        name: Name,
+        body: FuncBody,
+    },
+    /// `local vars = values`
+    Local {
+        vars: Vec<LocalVar>,
+        values: Vec<Expr>,
+    },
+}
+
+/// Name of a function in a function statement: `a.b.c` or `a.b:c`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuncName {
+    /// The name and the fields, `a`, `b`, `c` in `a.b.c`
+    pub path: Vec<Name>,
+    /// The method name, `c` in `a.b:c`
+    pub method: Option<Name>,
+}
+
+/// A variable declared in a `local` statement, with its attribute.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalVar {
+    pub name: Name,
+    pub attrib: Option<Attrib>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Attrib {
+    /// `<const>`
+    Const,
+    /// `<close>`
+    Close,
+}
+
+/// Parameters and body of a function.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuncBody {
+    pub params: Vec<Name>,
+    /// Whether the parameters end with `...`
+    pub is_vararg: bool,
+    pub body: Block,
+    /// From the opening parenthesis of the parameters to the `end`
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Nil,
+    True,
+    False,
+    Integer(i64),
+    Float(f64),
+    String(Vec<u8>),
+    /// `...`
+    VarArgs,
+    /// `function body`
+    Function(Box<FuncBody>),
+    Table(Vec<TableField>),
+    Name(Vec<u8>),
+    /// `object.name`
+    Field {
+        object: Box<Expr>,
+        name: Name,
+    },
+    /// `object[key]`
+    Index {
+        object: Box<Expr>,
+        key: Box<Expr>,
+    },
+    /// `function(args)`. Calls with a string or table argument, `f"s"` and `f{...}`, have the
+    /// string or table as the only argument.
+    Call {
+        function: Box<Expr>,
+        args: Vec<Expr>,
+    },
+    /// `object:method(args)`
+    MethodCall {
+        object: Box<Expr>,
+        method: Name,
+        args: Vec<Expr>,
+    },
+    /// `(expr)`
+    Paren(Box<Expr>),
+    Unary {
+        op: UnOp,
+        operand: Box<Expr>,
+    },
+    Binary {
+        op: BinOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+}
+
+impl ExprKind {
+    /// Whether the expression can be assigned to
+    pub fn is_var(&self) -> bool {
+        matches!(
+            self,
+            ExprKind::Name(_) | ExprKind::Field { .. } | ExprKind::Index { .. }
+        )
+    }
+
+    pub fn is_call(&self) -> bool {
+        matches!(self, ExprKind::Call { .. } | ExprKind::MethodCall { .. })
+    }
+}
+
+/// A field in a table constructor.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TableField {
+    /// `[key] = value`
+    Index { key: Expr, value: Expr },
+    /// `name = value`
+    Named { name: Name, value: Expr },
+    /// `value`, stored at the next integer key
+    Positional(Expr),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnOp {
+    /// `not`
+    Not,
+    /// `-`
+    Neg,
+    /// `#`
+    Len,
+    /// `~`
+    BitNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Or,
+    And,
+    LessThan,
+    GreaterThan,
+    LessEqual,
+    GreaterEqual,
+    NotEqual,
+    Equal,
+    BitOr,
+    BitXor,
+    BitAnd,
+    ShiftLeft,
+    ShiftRight,
+    Concat,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    IDiv,
+    Mod,
+    Pow,
+}
+
+impl UnOp {
+    /// The operator of a token, if the token is a unary operator
+    pub fn from_token_kind(kind: TokenKind) -> Option<UnOp> {
+        Some(match kind {
+            TokenKind::Not => UnOp::Not,
+            TokenKind::Minus => UnOp::Neg,
+            TokenKind::Len => UnOp::Len,
+            TokenKind::BitNotXor => UnOp::BitNot,
+            _ => return None,
+        })
+    }
+}
+
+impl BinOp {
+    /// The operator of a token, if the token is a binary operator. See
+    /// `TokenKind::binary_precedence` for the precedence of the operators.
+    pub fn from_token_kind(kind: TokenKind) -> Option<BinOp> {
+        Some(match kind {
+            TokenKind::Or => BinOp::Or,
+            TokenKind::And => BinOp::And,
+            TokenKind::LessThan => BinOp::LessThan,
+            TokenKind::GreaterThan => BinOp::GreaterThan,
+            TokenKind::LessEqual => BinOp::LessEqual,
+            TokenKind::GreaterEqual => BinOp::GreaterEqual,
+            TokenKind::NotEqual => BinOp::NotEqual,
+            TokenKind::Equal => BinOp::Equal,
+            TokenKind::BitOr => BinOp::BitOr,
+            TokenKind::BitNotXor => BinOp::BitXor,
+            TokenKind::BitAnd => BinOp::BitAnd,
+            TokenKind::ShiftLeft => BinOp::ShiftLeft,
+            TokenKind::ShiftRight => BinOp::ShiftRight,
+            TokenKind::Concat => BinOp::Concat,
+            TokenKind::Add => BinOp::Add,
+            TokenKind::Minus => BinOp::Sub,
+            TokenKind::Mul => BinOp::Mul,
+            TokenKind::Div => BinOp::Div,
+            TokenKind::IDiv => BinOp::IDiv,
+            TokenKind::Mod => BinOp::Mod,
+            TokenKind::Pow => BinOp::Pow,
+            _ => return None,
+        })
+    }
+}
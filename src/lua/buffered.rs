@@ -0,0 +1,212 @@
+// Buffered token stream for parsers, with arbitrary lookahead and backtracking, over either lexer.
+
+use super::error::LexerError;
+use super::lexer_lexgen;
+use super::lexer_luster;
+use super::token::{Span, Token};
+
+use std::collections::VecDeque;
+use std::io::Read;
+
+/// A token and its byte range in the source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpannedToken {
+    pub token: Token<Vec<u8>>,
+    pub span: Span,
+}
+
+/// A lexer that `BufferedTokens` reads tokens from.
+pub trait TokenSource {
+    /// Reads the next token, or `None` at the end of the source. Returns the error and its span if
+    /// the source has an error.
+    fn next_token(&mut self) -> Result<Option<SpannedToken>, (LexerError, Span)>;
+}
+
+impl<R, CS> TokenSource for lexer_luster::Lexer<R, CS>
+where
+    R: Read,
+    CS: FnMut(&[u8]) -> Vec<u8>,
+{
+    fn next_token(&mut self) -> Result<Option<SpannedToken>, (LexerError, Span)> {
+        match self.read_token() {
+            Ok(token) => Ok(token.map(|token| SpannedToken {
+                token,
+                span: Span::new(self.token_start(), self.byte_offset()),
+            })),
+            Err(err) => Err((err, Span::new(self.token_start(), self.byte_offset()))),
+        }
+    }
+}
+
+/// Token source of the lexgen lexer. Errors are converted with
+/// `lexer_lexgen::luster_fallback_error_span`, which needs the source.
+pub struct LexgenSource<'input, L> {
+    source: &'input str,
+    lexer: L,
+}
+
+pub fn lexgen_source(
+    source: &str,
+) -> LexgenSource<'_, impl Iterator<Item = lexer_lexgen::LexerItem> + '_> {
+    LexgenSource {
+        source,
+        lexer: lexer_lexgen::Lexer::new(source),
+    }
+}
+
+impl<L> TokenSource for LexgenSource<'_, L>
+where
+    L: Iterator<Item = lexer_lexgen::LexerItem>,
+{
+    fn next_token(&mut self) -> Result<Option<SpannedToken>, (LexerError, Span)> {
+        match self.lexer.next() {
+            Some(Ok((start, token, end))) => Ok(Some(SpannedToken {
+                token,
+                span: Span::new(start.byte_idx, end.byte_idx),
+            })),
+            Some(Err(err)) => Err(lexer_lexgen::luster_fallback_error_span(self.source, &err)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Position in a `BufferedTokens` to rewind to. Each checkpoint should be passed to either
+/// `rewind` or `commit`, until then the tokens after the checkpoint are kept in the buffer.
+#[must_use]
+#[derive(Debug)]
+pub struct Checkpoint {
+    /// Number of tokens consumed before the checkpoint
+    position: usize,
+    prev_span: Span,
+}
+
+/// Token stream with arbitrary lookahead and backtracking.
+pub struct BufferedTokens<L> {
+    lexer: L,
+    /// Tokens read from the lexer and not dropped yet. Tokens before `pos` are consumed, and kept
+    /// for rewinding to checkpoints.
+    buffer: VecDeque<SpannedToken>,
+    /// Index of the next token in `buffer`
+    pos: usize,
+    /// Number of tokens dropped from the front of the buffer
+    n_dropped: usize,
+    /// Number of checkpoints not rewound to or committed yet
+    n_checkpoints: usize,
+    /// Span of the last consumed token
+    prev_span: Span,
+    /// Error after the last token read from the lexer
+    error: Option<(LexerError, Span)>,
+    /// Whether the lexer reached the end of the source or an error
+    done: bool,
+}
+
+impl<L: TokenSource> BufferedTokens<L> {
+    pub fn new(lexer: L) -> Self {
+        BufferedTokens {
+            lexer,
+            buffer: VecDeque::new(),
+            pos: 0,
+            n_dropped: 0,
+            n_checkpoints: 0,
+            prev_span: Span::default(),
+            error: None,
+            done: false,
+        }
+    }
+
+    /// Consumes the next token.
+    pub fn next_token(&mut self) -> Result<Option<SpannedToken>, (LexerError, Span)> {
+        if !self.fill(0) {
+            return self.end();
+        }
+
+        let token = if self.n_checkpoints == 0 {
+            self.n_dropped += 1;
+            self.buffer.pop_front().unwrap()
+        } else {
+            self.pos += 1;
+            self.buffer[self.pos - 1].clone()
+        };
+        self.prev_span = token.span;
+        Ok(Some(token))
+    }
+
+    /// The next token, without consuming it.
+    pub fn peek(&mut self) -> Result<Option<&SpannedToken>, (LexerError, Span)> {
+        self.peek_nth(0)
+    }
+
+    /// The `n`th token after the next token, without consuming any tokens. `peek_nth(0)` is the
+    /// next token.
+    pub fn peek_nth(&mut self, n: usize) -> Result<Option<&SpannedToken>, (LexerError, Span)> {
+        if self.fill(n) {
+            Ok(Some(&self.buffer[self.pos + n]))
+        } else {
+            self.end()
+        }
+    }
+
+    /// Span of the last consumed token. Together with the span of the next token this gives the
+    /// span of a syntax node.
+    pub fn prev_span(&self) -> Span {
+        self.prev_span
+    }
+
+    /// Number of consumed tokens.
+    pub fn position(&self) -> usize {
+        self.n_dropped + self.pos
+    }
+
+    pub fn checkpoint(&mut self) -> Checkpoint {
+        self.n_checkpoints += 1;
+        Checkpoint {
+            position: self.position(),
+            prev_span: self.prev_span,
+        }
+    }
+
+    /// Rewinds to a checkpoint, so that the tokens consumed after the checkpoint are read again.
+    pub fn rewind(&mut self, checkpoint: Checkpoint) {
+        self.pos = checkpoint.position - self.n_dropped;
+        self.prev_span = checkpoint.prev_span;
+        self.commit(checkpoint);
+    }
+
+    /// Drops a checkpoint without rewinding.
+    pub fn commit(&mut self, checkpoint: Checkpoint) {
+        debug_assert!(checkpoint.position >= self.n_dropped);
+        self.n_checkpoints -= 1;
+        if self.n_checkpoints == 0 {
+            self.buffer.drain(..self.pos);
+            self.n_dropped += self.pos;
+            self.pos = 0;
+        }
+    }
+
+    /// Reads tokens until the buffer has the `n`th token after the next token. Returns whether
+    /// the token exists.
+    fn fill(&mut self, n: usize) -> bool {
+        while self.buffer.len() <= self.pos + n {
+            if self.done {
+                return false;
+            }
+            match self.lexer.next_token() {
+                Ok(Some(token)) => self.buffer.push_back(token),
+                Ok(None) => self.done = true,
+                Err(err) => {
+                    self.error = Some(err);
+                    self.done = true;
+                }
+            }
+        }
+        true
+    }
+
+    /// Result after the last token.
+    fn end<T>(&self) -> Result<Option<T>, (LexerError, Span)> {
+        match &self.error {
+            Some(err) => Err(err.clone()),
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,155 @@
+// Push-based lexing of a source that arrives in chunks, e.g. from a socket. Built on the luster
+// lexer, which can resume lexing from a `LexerState`.
+//
+// The source after the last complete token is buffered. After each chunk the buffered source is
+// lexed token by token, and a token is complete when the lexer didn't need to read past the end
+// of the buffer to lex it. Otherwise the token may continue in the next chunk, and it's lexed
+// again when more of the source arrives.
+//
+// Long strings, long comments, short strings and short comments can span many chunks. To not lex
+// them again for every chunk, the buffer is only lexed again when a chunk has a character that
+// can end them: `]` for long brackets, the quote or a newline for short strings, and a newline for
+// short comments. Errors in them are found when they are lexed again. A comment is only taken to be
+// a short comment once it can no longer open a long bracket.
+
+use super::error::LexerError;
+use super::lexer_luster::{Lexer, LexerState};
+use super::token::{Span, Token};
+
+use std::io::{self, Read};
+
+/// Lexer that is fed a source in chunks. Tokens are available with `take_tokens` as soon as they
+/// are complete.
+#[derive(Debug, Default, Clone)]
+pub struct ChunkedLexer {
+    /// The source after the last complete token
+    buffer: Vec<u8>,
+    /// State of the lexer after the last complete token
+    state: LexerState,
+    /// Complete tokens not taken yet
+    tokens: Vec<(Token<Vec<u8>>, Span)>,
+    error: Option<(LexerError, Span)>,
+    /// What the lexer stopped in at the end of the buffer, when the buffer doesn't need to be
+    /// lexed again until a chunk can end it
+    pending: Option<Pending>,
+}
+
+/// A token or comment at the end of the buffer that may continue in the next chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pending {
+    LongBracket,
+    ShortString { quote: u8 },
+    ShortComment,
+}
+
+impl Pending {
+    /// Whether `chunk` has a character that can end the token or comment.
+    fn can_end_in(self, chunk: &[u8]) -> bool {
+        chunk.iter().any(|&c| match self {
+            Pending::LongBracket => c == b']',
+            Pending::ShortString { quote } => c == quote || c == b'\n' || c == b'\r',
+            Pending::ShortComment => c == b'\n' || c == b'\r',
+        })
+    }
+}
+
+impl ChunkedLexer {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Appends a chunk of the source and lexes the tokens it completes. Returns the error and its
+    /// span if the source has an error. After an error the lexer doesn't lex any more tokens.
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<(), (LexerError, Span)> {
+        self.buffer.extend_from_slice(chunk);
+        match self.pending {
+            Some(pending) if !pending.can_end_in(chunk) => Ok(()),
+            _ => self.lex(false),
+        }
+    }
+
+    /// Lexes the rest of the source after the last chunk.
+    pub fn finish(&mut self) -> Result<(), (LexerError, Span)> {
+        self.lex(true)
+    }
+
+    /// Takes the complete tokens and their spans lexed so far.
+    pub fn take_tokens(&mut self) -> Vec<(Token<Vec<u8>>, Span)> {
+        std::mem::take(&mut self.tokens)
+    }
+
+    fn lex(&mut self, at_end: bool) -> Result<(), (LexerError, Span)> {
+        if let Some(error) = &self.error {
+            return Err(error.clone());
+        }
+        self.pending = None;
+
+        // Start of the source after the last complete token in the buffer
+        let mut start = 0;
+
+        let result = loop {
+            let mut reader = BufferReader {
+                buffer: &self.buffer[start..],
+                read_past_end: false,
+            };
+            let mut lexer =
+                Lexer::with_state(&mut reader, |s: &[u8]| s.to_owned(), self.state.clone());
+            let token = lexer.read_token();
+            let span = Span::new(lexer.token_start(), lexer.byte_offset());
+            let state = lexer.state();
+            let in_short_comment = lexer.in_short_comment();
+            drop(lexer);
+
+            if reader.read_past_end && !at_end {
+                self.pending = match token {
+                    Err(LexerError::UnfinishedLongString) => Some(Pending::LongBracket),
+                    Err(LexerError::UnfinishedShortString(quote)) => {
+                        Some(Pending::ShortString { quote })
+                    }
+                    // The comment may still be a long comment if the buffer ends with "--" or
+                    // "--[", so it's lexed again with the next chunk
+                    Ok(None) if in_short_comment => {
+                        if self.buffer.ends_with(b"--") || self.buffer.ends_with(b"--[") {
+                            None
+                        } else {
+                            Some(Pending::ShortComment)
+                        }
+                    }
+                    _ => None,
+                };
+                break Ok(());
+            }
+
+            match token {
+                Ok(Some(token)) => {
+                    self.tokens.push((token, span));
+                    start += state.byte_offset - self.state.byte_offset;
+                    self.state = state;
+                }
+                Ok(None) => break Ok(()),
+                Err(err) => {
+                    self.error = Some((err.clone(), span));
+                    break Err((err, span));
+                }
+            }
+        };
+
+        self.buffer.drain(..start);
+        result
+    }
+}
+
+/// Reads the buffered source, recording whether the lexer tried to read past the end.
+struct BufferReader<'a> {
+    buffer: &'a [u8],
+    read_past_end: bool,
+}
+
+impl Read for BufferReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.buffer.is_empty() && !buf.is_empty() {
+            self.read_past_end = true;
+        }
+        self.buffer.read(buf)
+    }
+}
@@ -0,0 +1,703 @@
+// Lossless concrete syntax tree of Lua 5.4, for tools that edit source code, e.g. refactoring
+// tools and formatters.
+//
+// Same design as rowan, the syntax tree library of rust-analyzer. The "green" tree has immutable
+// nodes that only know their kind, the length of their text, and their children, so they can be
+// shared within and between trees. The "red" tree is a view of a green tree, built on demand, with
+// parent pointers and offsets in the source.
+//
+// Every byte of the source is in a token: whitespace, comments, and a first line starting with `#`
+// are trivia tokens between the tokens of the lexer, so the text of a tree is the source. Trivia
+// before a node is in the parent of the node, so nodes start and end with tokens of the lexer.
+//
+// Sources are lexed with the luster lexer, as it works on bytes. The syntax is recognized by
+// `grammar`, so the errors are the same as in `parser`. Parsing stops at the first error: the rest
+// of the source is put in an `Error` node, and the open nodes are closed, so a tree is lossless
+// even if it has an error.
+
+use super::buffered::{SpannedToken, TokenSource};
+use super::error::LexerError;
+use super::grammar::{Grammar, Sink};
+use super::lexer_luster::{self, TriviaKind};
+use super::parser::ParseError;
+use super::syntax::SyntaxKind;
+use super::token::Span;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::vec;
+
+/// A token of a lossless lex: a token of the lexer, or a token that the lexer skips.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LosslessToken {
+    pub kind: SyntaxKind,
+    pub span: Span,
+}
+
+/// Lexes a whole source into tokens that cover every byte of it, in order. After a lexer error,
+/// the rest of the source is one `ErrorToken`, and the error is returned with the tokens.
+pub fn lex_lossless(source: &[u8]) -> (Vec<LosslessToken>, Option<(LexerError, Span)>) {
+    let (tokens, _, error) = lex(source);
+    (tokens, error)
+}
+
+/// Lexes a whole source into lossless tokens, and the tokens of the lexer among them.
+fn lex(
+    source: &[u8],
+) -> (
+    Vec<LosslessToken>,
+    Vec<SpannedToken>,
+    Option<(LexerError, Span)>,
+) {
+    let mut tokens = vec![];
+    let mut significant = vec![];
+
+    let mut start = 0;
+    if source.first() == Some(&b'#') {
+        start = source
+            .iter()
+            .position(|&c| c == b'\n' || c == b'\r')
+            .unwrap_or(source.len());
+        tokens.push(LosslessToken {
+            kind: SyntaxKind::Shebang,
+            span: Span::new(0, start),
+        });
+    }
+
+    let mut lexer = lexer_luster::Lexer::new(&source[start..], |s| s.to_owned());
+    lexer.enable_trivia();
+    loop {
+        let token = lexer.read_token();
+        for trivia in lexer.take_trivia() {
+            let kind = match trivia.kind {
+                TriviaKind::Whitespace => SyntaxKind::Whitespace,
+                TriviaKind::Comment => SyntaxKind::Comment,
+            };
+            tokens.push(LosslessToken {
+                kind,
+                span: offset_span(trivia.span, start),
+            });
+        }
+        let span = Span::new(start + lexer.token_start(), start + lexer.byte_offset());
+        match token {
+            Ok(Some(token)) => {
+                tokens.push(LosslessToken {
+                    kind: SyntaxKind::Token(token.kind()),
+                    span,
+                });
+                significant.push(SpannedToken { token, span });
+            }
+            Ok(None) => return (tokens, significant, None),
+            Err(err) => {
+                // The trivia before the error were skipped, the error token starts after them
+                let error_start = tokens.last().map_or(0, |token| token.span.end);
+                tokens.push(LosslessToken {
+                    kind: SyntaxKind::ErrorToken,
+                    span: Span {
+                        start: error_start,
+                        end: source.len() as u32,
+                    },
+                });
+                return (tokens, significant, Some((err, span)));
+            }
+        }
+    }
+}
+
+fn offset_span(span: Span, offset: usize) -> Span {
+    Span {
+        start: span.start + offset as u32,
+        end: span.end + offset as u32,
+    }
+}
+
+//
+// Green tree
+//
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct GreenToken {
+    kind: SyntaxKind,
+    text: Box<[u8]>,
+}
+
+impl GreenToken {
+    pub fn new(kind: SyntaxKind, text: &[u8]) -> GreenToken {
+        debug_assert!(kind.is_token());
+        GreenToken {
+            kind,
+            text: text.into(),
+        }
+    }
+
+    pub fn kind(&self) -> SyntaxKind {
+        self.kind
+    }
+
+    pub fn text(&self) -> &[u8] {
+        &self.text
+    }
+
+    pub fn text_len(&self) -> u32 {
+        self.text.len() as u32
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum GreenElement {
+    Node(Arc<GreenNode>),
+    Token(Arc<GreenToken>),
+}
+
+impl GreenElement {
+    pub fn kind(&self) -> SyntaxKind {
+        match self {
+            GreenElement::Node(node) => node.kind,
+            GreenElement::Token(token) => token.kind,
+        }
+    }
+
+    pub fn text_len(&self) -> u32 {
+        match self {
+            GreenElement::Node(node) => node.text_len,
+            GreenElement::Token(token) => token.text_len(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct GreenNode {
+    kind: SyntaxKind,
+    text_len: u32,
+    children: Box<[GreenElement]>,
+}
+
+impl GreenNode {
+    pub fn new(kind: SyntaxKind, children: Vec<GreenElement>) -> GreenNode {
+        debug_assert!(!kind.is_token());
+        GreenNode {
+            kind,
+            text_len: children.iter().map(GreenElement::text_len).sum(),
+            children: children.into(),
+        }
+    }
+
+    pub fn kind(&self) -> SyntaxKind {
+        self.kind
+    }
+
+    pub fn text_len(&self) -> u32 {
+        self.text_len
+    }
+
+    pub fn children(&self) -> &[GreenElement] {
+        &self.children
+    }
+
+    /// Source text of the node: the text of its tokens
+    pub fn text(&self) -> Vec<u8> {
+        let mut text = Vec::with_capacity(self.text_len as usize);
+        self.write_text(&mut text);
+        text
+    }
+
+    fn write_text(&self, out: &mut Vec<u8>) {
+        for child in self.children.iter() {
+            match child {
+                GreenElement::Node(node) => node.write_text(out),
+                GreenElement::Token(token) => out.extend_from_slice(&token.text),
+            }
+        }
+    }
+}
+
+/// Prints the source text of the node. Bytes that are not valid UTF-8 are replaced with U+FFFD.
+impl fmt::Display for GreenNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for child in self.children.iter() {
+            match child {
+                GreenElement::Node(node) => node.fmt(f)?,
+                GreenElement::Token(token) => f.write_str(&String::from_utf8_lossy(&token.text))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Position in a `GreenNodeBuilder` to start a node at, to wrap nodes and tokens that were added
+/// before knowing the kind of the node, e.g. the left operand of a binary expression.
+#[derive(Debug, Clone, Copy)]
+pub struct Checkpoint(usize);
+
+/// Builds a green tree from the start of its first node. Tokens with the same kind and text are
+/// shared.
+#[derive(Debug, Default)]
+pub struct GreenNodeBuilder {
+    /// Open nodes, with the index of their first child in `children`
+    parents: Vec<(SyntaxKind, usize)>,
+    /// Children of the open nodes
+    children: Vec<GreenElement>,
+    tokens: HashMap<SyntaxKind, HashMap<Box<[u8]>, Arc<GreenToken>>>,
+}
+
+impl GreenNodeBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn start_node(&mut self, kind: SyntaxKind) {
+        self.parents.push((kind, self.children.len()));
+    }
+
+    /// Adds a token to the current node.
+    pub fn token(&mut self, kind: SyntaxKind, text: &[u8]) {
+        let tokens = self.tokens.entry(kind).or_default();
+        let token = match tokens.get(text) {
+            Some(token) => token.clone(),
+            None => {
+                let token = Arc::new(GreenToken::new(kind, text));
+                tokens.insert(text.into(), token.clone());
+                token
+            }
+        };
+        self.children.push(GreenElement::Token(token));
+    }
+
+    pub fn finish_node(&mut self) {
+        let (kind, first_child) = self.parents.pop().unwrap();
+        let children = self.children.drain(first_child..).collect();
+        self.children
+            .push(GreenElement::Node(Arc::new(GreenNode::new(kind, children))));
+    }
+
+    /// Checkpoint before the next child of the current node.
+    pub fn checkpoint(&mut self) -> Checkpoint {
+        Checkpoint(self.children.len())
+    }
+
+    /// Starts a node with the children of the current node that were added after the checkpoint.
+    pub fn start_node_at(&mut self, checkpoint: Checkpoint, kind: SyntaxKind) {
+        let Checkpoint(first_child) = checkpoint;
+        let current_first_child = self.parents.last().map_or(0, |&(_, first)| first);
+        assert!(
+            current_first_child <= first_child && first_child <= self.children.len(),
+            "checkpoint is not in the current node"
+        );
+        self.parents.push((kind, first_child));
+    }
+
+    /// The finished tree. All nodes should be finished, and the tree should have one root node.
+    pub fn finish(mut self) -> Arc<GreenNode> {
+        assert!(self.parents.is_empty() && self.children.len() == 1);
+        match self.children.pop() {
+            Some(GreenElement::Node(node)) => node,
+            _ => panic!("root of the tree is not a node"),
+        }
+    }
+
+    /// Number of open nodes
+    fn depth(&self) -> usize {
+        self.parents.len()
+    }
+}
+
+//
+// Red tree
+//
+
+/// A node in a tree, with its parent and its position in the source. Cloning is cheap.
+#[derive(Clone)]
+pub struct SyntaxNode(Rc<NodeData>);
+
+struct NodeData {
+    green: Arc<GreenNode>,
+    parent: Option<SyntaxNode>,
+    /// Index in the children of the parent
+    index: usize,
+    offset: u32,
+}
+
+/// A token in a tree, with its parent and its position in the source.
+#[derive(Clone, PartialEq)]
+pub struct SyntaxToken {
+    parent: SyntaxNode,
+    /// Index in the children of the parent
+    index: usize,
+    offset: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    Token(SyntaxToken),
+}
+
+impl SyntaxNode {
+    pub fn new_root(green: Arc<GreenNode>) -> SyntaxNode {
+        SyntaxNode(Rc::new(NodeData {
+            green,
+            parent: None,
+            index: 0,
+            offset: 0,
+        }))
+    }
+
+    pub fn kind(&self) -> SyntaxKind {
+        self.0.green.kind
+    }
+
+    pub fn span(&self) -> Span {
+        Span {
+            start: self.0.offset,
+            end: self.0.offset + self.0.green.text_len,
+        }
+    }
+
+    pub fn green(&self) -> &Arc<GreenNode> {
+        &self.0.green
+    }
+
+    pub fn parent(&self) -> Option<SyntaxNode> {
+        self.0.parent.clone()
+    }
+
+    /// Index of the node in the children of its parent
+    pub fn index(&self) -> usize {
+        self.0.index
+    }
+
+    pub fn children_with_tokens(&self) -> impl Iterator<Item = SyntaxElement> + '_ {
+        let mut offset = self.0.offset;
+        self.0
+            .green
+            .children
+            .iter()
+            .enumerate()
+            .map(move |(index, child)| {
+                let child_offset = offset;
+                offset += child.text_len();
+                match child {
+                    GreenElement::Node(green) => {
+                        SyntaxElement::Node(SyntaxNode(Rc::new(NodeData {
+                            green: green.clone(),
+                            parent: Some(self.clone()),
+                            index,
+                            offset: child_offset,
+                        })))
+                    }
+                    GreenElement::Token(_) => SyntaxElement::Token(SyntaxToken {
+                        parent: self.clone(),
+                        index,
+                        offset: child_offset,
+                    }),
+                }
+            })
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = SyntaxNode> + '_ {
+        self.children_with_tokens().filter_map(|child| match child {
+            SyntaxElement::Node(node) => Some(node),
+            SyntaxElement::Token(_) => None,
+        })
+    }
+
+    /// The node and the nodes and tokens in it, in preorder
+    pub fn descendants_with_tokens(&self) -> impl Iterator<Item = SyntaxElement> {
+        let mut stack = vec![SyntaxElement::Node(self.clone())];
+        std::iter::from_fn(move || {
+            let element = stack.pop()?;
+            if let SyntaxElement::Node(node) = &element {
+                let n_elements = stack.len();
+                stack.extend(node.children_with_tokens());
+                stack[n_elements..].reverse();
+            }
+            Some(element)
+        })
+    }
+
+    /// The tokens in the node, in source order
+    pub fn tokens(&self) -> impl Iterator<Item = SyntaxToken> {
+        self.descendants_with_tokens()
+            .filter_map(|element| match element {
+                SyntaxElement::Node(_) => None,
+                SyntaxElement::Token(token) => Some(token),
+            })
+    }
+
+    /// The token that contains the byte at the offset
+    pub fn token_at_offset(&self, offset: u32) -> Option<SyntaxToken> {
+        let mut node = self.clone();
+        loop {
+            let child = node.children_with_tokens().find(|child| {
+                let span = child.span();
+                span.start <= offset && offset < span.end
+            })?;
+            match child {
+                SyntaxElement::Node(child) => node = child,
+                SyntaxElement::Token(token) => return Some(token),
+            }
+        }
+    }
+
+    /// Source text of the node
+    pub fn text(&self) -> Vec<u8> {
+        self.0.green.text()
+    }
+}
+
+/// Nodes are equal if they are the same node of the same tree.
+impl PartialEq for SyntaxNode {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0.green, &other.0.green) && self.0.offset == other.0.offset
+    }
+}
+
+/// Prints the source text of the node. Bytes that are not valid UTF-8 are replaced with U+FFFD.
+impl fmt::Display for SyntaxNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.green.fmt(f)
+    }
+}
+
+/// Prints the kind and the span of the node, e.g. `Block@0..10`. The alternate form (`{:#?}`)
+/// prints the whole tree, one node or token per line, indented by depth.
+impl fmt::Debug for SyntaxNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let span = self.span();
+        write!(f, "{:?}@{}..{}", self.kind(), span.start, span.end)?;
+        if f.alternate() {
+            writeln!(f)?;
+            self.debug_children(f, 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl SyntaxNode {
+    fn debug_children(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        for child in self.children_with_tokens() {
+            write!(f, "{:indent$}", "", indent = depth * 2)?;
+            match child {
+                SyntaxElement::Node(node) => {
+                    writeln!(f, "{:?}", node)?;
+                    node.debug_children(f, depth + 1)?;
+                }
+                SyntaxElement::Token(token) => writeln!(f, "{:?}", token)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SyntaxToken {
+    pub fn kind(&self) -> SyntaxKind {
+        self.green().kind
+    }
+
+    pub fn span(&self) -> Span {
+        Span {
+            start: self.offset,
+            end: self.offset + self.green().text_len(),
+        }
+    }
+
+    pub fn green(&self) -> &Arc<GreenToken> {
+        match &self.parent.0.green.children[self.index] {
+            GreenElement::Token(token) => token,
+            GreenElement::Node(_) => unreachable!(),
+        }
+    }
+
+    pub fn text(&self) -> &[u8] {
+        &self.green().text
+    }
+
+    pub fn parent(&self) -> SyntaxNode {
+        self.parent.clone()
+    }
+
+    /// Index of the token in the children of its parent
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+/// Prints the kind, the span, and the text of the token, e.g. `Token(Name)@0..1 "x"`.
+impl fmt::Debug for SyntaxToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let span = self.span();
+        write!(
+            f,
+            "{:?}@{}..{} {:?}",
+            self.kind(),
+            span.start,
+            span.end,
+            String::from_utf8_lossy(self.text())
+        )
+    }
+}
+
+impl SyntaxElement {
+    pub fn kind(&self) -> SyntaxKind {
+        match self {
+            SyntaxElement::Node(node) => node.kind(),
+            SyntaxElement::Token(token) => token.kind(),
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        match self {
+            SyntaxElement::Node(node) => node.span(),
+            SyntaxElement::Token(token) => token.span(),
+        }
+    }
+}
+
+//
+// Parser
+//
+
+/// A lossless tree of a source, and the first error in the source.
+#[derive(Debug, Clone)]
+pub struct Parse {
+    green: Arc<GreenNode>,
+    error: Option<ParseError>,
+}
+
+impl Parse {
+    pub fn green(&self) -> &Arc<GreenNode> {
+        &self.green
+    }
+
+    /// Root of the tree, a `Chunk` node
+    pub fn syntax(&self) -> SyntaxNode {
+        SyntaxNode::new_root(self.green.clone())
+    }
+
+    /// Same as the error that `parser` returns for the source, after skipping a first line that
+    /// starts with `#`
+    pub fn error(&self) -> Option<&ParseError> {
+        self.error.as_ref()
+    }
+}
+
+/// Parses a whole source as a chunk into a lossless tree.
+pub fn parse(source: &[u8]) -> Parse {
+    let (tokens, significant, lexer_error) = lex(source);
+    let sink = TreeSink {
+        source,
+        tokens,
+        n_added: 0,
+        builder: GreenNodeBuilder::new(),
+    };
+    let lexed = LexedTokens {
+        tokens: significant.into_iter(),
+        error: lexer_error,
+    };
+    let mut grammar = Grammar::new(lexed, sink);
+
+    grammar.sink_mut().builder.start_node(SyntaxKind::Chunk);
+    let error = grammar.chunk().err().map(ParseError::from);
+    let mut sink = grammar.into_sink();
+    if error.is_some() {
+        if sink.n_added < sink.tokens.len() {
+            sink.builder.start_node(SyntaxKind::Error);
+            sink.add_rest();
+            sink.builder.finish_node();
+        }
+        while sink.builder.depth() > 1 {
+            sink.builder.finish_node();
+        }
+    } else {
+        sink.add_rest();
+    }
+    sink.builder.finish_node();
+
+    Parse {
+        green: sink.builder.finish(),
+        error,
+    }
+}
+
+/// Tokens of the lexer from a lossless lex, followed by the lexer error
+struct LexedTokens {
+    tokens: vec::IntoIter<SpannedToken>,
+    error: Option<(LexerError, Span)>,
+}
+
+impl TokenSource for LexedTokens {
+    fn next_token(&mut self) -> Result<Option<SpannedToken>, (LexerError, Span)> {
+        match self.tokens.next() {
+            Some(token) => Ok(Some(token)),
+            None => match self.error.take() {
+                Some(err) => Err(err),
+                None => Ok(None),
+            },
+        }
+    }
+}
+
+/// Builds a lossless tree from the syntax reported by `Grammar`. Trivia before a node or a token
+/// is added to the current node before it.
+struct TreeSink<'source> {
+    source: &'source [u8],
+    tokens: Vec<LosslessToken>,
+    /// Number of tokens added to the tree
+    n_added: usize,
+    builder: GreenNodeBuilder,
+}
+
+impl TreeSink<'_> {
+    /// Adds the trivia before the next token of the lexer, or before the `ErrorToken` after a
+    /// lexer error, to the current node.
+    fn add_trivia(&mut self) {
+        while matches!(self.tokens.get(self.n_added), Some(token) if token.kind.is_trivia()) {
+            self.add_token();
+        }
+    }
+
+    /// Adds the rest of the tokens to the current node.
+    fn add_rest(&mut self) {
+        while self.n_added < self.tokens.len() {
+            self.add_token();
+        }
+    }
+
+    fn add_token(&mut self) {
+        let LosslessToken { kind, span } = self.tokens[self.n_added];
+        self.builder
+            .token(kind, &self.source[span.start as usize..span.end as usize]);
+        self.n_added += 1;
+    }
+}
+
+impl Sink for TreeSink<'_> {
+    type Checkpoint = Checkpoint;
+
+    fn start_node(&mut self, kind: SyntaxKind) {
+        self.add_trivia();
+        self.builder.start_node(kind);
+    }
+
+    fn token(&mut self, token: SpannedToken) {
+        self.add_trivia();
+        debug_assert_eq!(self.tokens[self.n_added].span, token.span);
+        self.add_token();
+    }
+
+    fn finish_node(&mut self) {
+        self.builder.finish_node();
+    }
+
+    /// Checkpoint before the next token, after the trivia before it
+    fn checkpoint(&mut self) -> Checkpoint {
+        self.add_trivia();
+        self.builder.checkpoint()
+    }
+
+    fn start_node_at(&mut self, checkpoint: Checkpoint, kind: SyntaxKind) {
+        self.builder.start_node_at(checkpoint, kind);
+    }
+}
@@ -0,0 +1,436 @@
+// Rendering of lexer errors and warnings with source snippets, e.g.
+//
+//     error: unfinished long string
+//      --> test.lua:3:4
+//       |
+//     3 | foo
+//       |    ^
+//     note: long string starts here
+//      --> test.lua:1:5
+//       |
+//     1 | x = [==[
+//       |     ^^^^
+//
+// and in the format of the reference Lua implementation, e.g.
+//
+//     test.lua:3: unfinished long string (starting at line 1) near <eof>
+
+use super::error::LexerError;
+use super::lexer_luster::Lexer;
+use super::token::{Span, Token};
+use super::warning::{LexerWarning, Warning};
+
+use std::fmt::Write;
+
+/// An error or warning with its location in the source, and notes pointing at related locations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Span,
+    pub notes: Vec<Note>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Note {
+    pub message: String,
+    /// Location the note refers to. Notes without a location are rendered after the snippet.
+    pub span: Option<Span>,
+}
+
+impl Diagnostic {
+    pub fn new(message: String, span: Span) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            message,
+            span,
+            notes: vec![],
+        }
+    }
+
+    pub fn with_note(mut self, message: String, span: Option<Span>) -> Self {
+        self.notes.push(Note { message, span });
+        self
+    }
+
+    /// Creates a diagnostic for a lexer warning.
+    pub fn from_warning(warning: &Warning) -> Self {
+        let diagnostic = Diagnostic {
+            severity: Severity::Warning,
+            ..Diagnostic::new(warning.kind.to_string(), warning.span)
+        };
+        match warning.kind {
+            LexerWarning::DecimalEscapeFollowedByDigit => diagnostic.with_note(
+                "use a hexadecimal escape or split the string to end the escape".to_owned(),
+                None,
+            ),
+            LexerWarning::MixedLineEndings => diagnostic.with_note(
+                "this is the first line ending different from the first line's".to_owned(),
+                None,
+            ),
+            _ => diagnostic,
+        }
+    }
+
+    /// Creates a diagnostic for a lexer error. `span` is the erroneous part of the source: from
+    /// the start of the token or comment being lexed to where the error was found.
+    ///
+    /// For unfinished strings the diagnostic points at where the input or line ended, with a note
+    /// pointing at the opening delimiter.
+    pub fn from_lexer_error(source: &[u8], err: &LexerError, span: Span) -> Self {
+        let start = span.start as usize;
+        let end = Span::new(span.end as usize, span.end as usize);
+
+        match err {
+            LexerError::UnfinishedLongString => {
+                let (what, delim_start) = if source[start..].starts_with(b"--") {
+                    ("long comment", start + 2)
+                } else {
+                    ("long string", start)
+                };
+                let delim_end = long_bracket_end(source, delim_start);
+                Diagnostic::new(err.to_string(), end).with_note(
+                    format!("{} starts here", what),
+                    Some(Span::new(start, delim_end)),
+                )
+            }
+            LexerError::UnfinishedShortString(_) => Diagnostic::new(err.to_string(), end)
+                .with_note(
+                    "string starts here".to_owned(),
+                    Some(Span::new(start, start + 1)),
+                ),
+            _ => Diagnostic::new(err.to_string(), span),
+        }
+    }
+
+    /// Renders the diagnostic with the lines it refers to. `file_name` is only used for display.
+    pub fn render(&self, file_name: &str, source: &[u8]) -> String {
+        let spans = std::iter::once(self.span).chain(self.notes.iter().filter_map(|n| n.span));
+        let max_line = spans
+            .map(|span| SourcePos::new(source, span.start as usize).line)
+            .max()
+            .unwrap_or(1);
+        let gutter = max_line.to_string().len();
+
+        let mut out = String::new();
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        writeln!(out, "{}: {}", severity, self.message).unwrap();
+        render_snippet(&mut out, file_name, source, self.span, gutter);
+
+        for note in &self.notes {
+            if note.span.is_none() {
+                writeln!(
+                    out,
+                    "{:gutter$} = note: {}",
+                    "",
+                    note.message,
+                    gutter = gutter
+                )
+                .unwrap();
+            }
+        }
+
+        for note in &self.notes {
+            if let Some(span) = note.span {
+                writeln!(out, "note: {}", note.message).unwrap();
+                render_snippet(&mut out, file_name, source, span, gutter);
+            }
+        }
+
+        out
+    }
+}
+
+/// Formats a lexer error like the reference Lua implementation does, e.g.
+/// `chunk:3: unfinished string near '"abc'`. `span` is the erroneous part of the source, as in
+/// `Diagnostic::from_lexer_error`.
+///
+/// The "near" part is the text in the Lua lexer's buffer when it fails: the numeral, or the
+/// opening quote and the string contents read so far (with escape sequences decoded) followed by
+/// the characters of the invalid escape sequence.
+pub fn lua_error_message(chunk_name: &str, source: &[u8], err: &LexerError, span: Span) -> String {
+    let start = span.start as usize;
+    let end = span.end as usize;
+    let line = SourcePos::new(source, end).line;
+
+    let (message, near) = match err {
+        LexerError::UnfinishedShortString(_) => {
+            // At the end of input the error is reported for the end-of-stream token
+            let near = if end >= source.len() {
+                "<eof>".to_owned()
+            } else {
+                quote(&string_prefix(&source[start..end]))
+            };
+            (err.lua_message().to_owned(), near)
+        }
+
+        LexerError::UnfinishedLongString => {
+            let what = if source[start..].starts_with(b"--") {
+                "comment"
+            } else {
+                "string"
+            };
+            let message = format!(
+                "unfinished long {} (starting at line {})",
+                what,
+                SourcePos::new(source, start).line
+            );
+            (message, "<eof>".to_owned())
+        }
+
+        LexerError::UnexpectedCharacter(c) => {
+            // The Lua lexer returns unknown characters as tokens, the error is reported by the
+            // parser
+            let near = if c.is_ascii_graphic() {
+                format!("'{}'", *c as char)
+            } else {
+                format!("'<\\{}>'", c)
+            };
+            (err.lua_message().to_owned(), near)
+        }
+
+        LexerError::HexDigitExpected
+        | LexerError::EscapeUnicodeStart
+        | LexerError::EscapeUnicodeEnd
+        | LexerError::EscapeUnicodeInvalid
+        | LexerError::EscapeDecimalTooLarge
+        | LexerError::InvalidEscape => {
+            // The escape sequence being read is the last one in the span
+            let escape_start = match source[start..end].iter().rposition(|&b| b == b'\\') {
+                Some(idx) => start + idx,
+                None => end,
+            };
+            let (message, escape_end) =
+                lua_escape_error(source, escape_start).unwrap_or((err.lua_message(), end));
+            let mut text = string_prefix(&source[start..escape_start]);
+            text.extend_from_slice(&source[escape_start..escape_end.max(escape_start)]);
+            (message.to_owned(), quote(&text))
+        }
+
+        LexerError::InvalidLongStringDelimiter | LexerError::BadNumber => {
+            (err.lua_message().to_owned(), quote(&source[start..end]))
+        }
+    };
+
+    format!("{}:{}: {} near {}", chunk_name, line, message, near)
+}
+
+/// Checks the escape sequence starting at the given offset as the Lua lexer does. Returns the
+/// error message and the end of the escape sequence's text in the Lua lexer's buffer, or `None`
+/// if the escape sequence is valid.
+fn lua_escape_error(s: &[u8], start: usize) -> Option<(&'static str, usize)> {
+    let is_hex_digit = |idx: usize| s.get(idx).is_some_and(u8::is_ascii_hexdigit);
+    let hex_value = |idx: usize| (s[idx] as char).to_digit(16).unwrap();
+
+    // The Lua lexer adds the character it failed at to the buffer, unless it's the end of input
+    let error = |message, idx: usize| Some((message, (idx + 1).min(s.len())));
+
+    let idx = start + 1;
+    match *s.get(idx)? {
+        b'x' => {
+            for idx in idx + 1..idx + 3 {
+                if !is_hex_digit(idx) {
+                    return error("hexadecimal digit expected", idx);
+                }
+            }
+            None
+        }
+
+        b'u' => {
+            if s.get(idx + 1) != Some(&b'{') {
+                return error("missing '{' in \\u{xxxx}", idx + 1);
+            }
+            if !is_hex_digit(idx + 2) {
+                return error("hexadecimal digit expected", idx + 2);
+            }
+            let mut value = hex_value(idx + 2);
+            let mut idx = idx + 3;
+            while is_hex_digit(idx) {
+                if value > 0x7FF_FFFF {
+                    return error("UTF-8 value too large", idx);
+                }
+                value = (value << 4) + hex_value(idx);
+                idx += 1;
+            }
+            if s.get(idx) != Some(&b'}') {
+                return error("missing '}' in \\u{xxxx}", idx);
+            }
+            None
+        }
+
+        c if c.is_ascii_digit() => {
+            let n_digits = s[idx..]
+                .iter()
+                .take(3)
+                .take_while(|c| c.is_ascii_digit())
+                .count();
+            let value = s[idx..idx + n_digits]
+                .iter()
+                .fold(0u32, |value, c| value * 10 + (c - b'0') as u32);
+            if value > 255 {
+                error("decimal escape too large", idx + n_digits)
+            } else {
+                None
+            }
+        }
+
+        b'a' | b'b' | b'f' | b'n' | b'r' | b't' | b'v' | b'\\' | b'"' | b'\'' | b'\n' | b'\r'
+        | b'z' => None,
+
+        _ => error("invalid escape sequence", idx),
+    }
+}
+
+/// Opening quote and decoded contents of a short string, given the source text of the string
+/// without the closing quote.
+fn string_prefix(raw: &[u8]) -> Vec<u8> {
+    let quote = raw[0];
+    let mut string = raw.to_vec();
+    string.push(quote);
+
+    let mut prefix = vec![quote];
+    match Lexer::new(string.as_slice(), |s: &[u8]| s.to_owned()).read_token() {
+        Ok(Some(Token::String(contents))) => prefix.extend_from_slice(&contents),
+        _ => prefix.extend_from_slice(&raw[1..]),
+    }
+    prefix
+}
+
+/// Quotes the text of a token for an error message. As Lua formats the message with `%s`, the text
+/// ends at the first NUL byte.
+fn quote(text: &[u8]) -> String {
+    let text = match text.iter().position(|&b| b == 0) {
+        Some(nul) => &text[..nul],
+        None => text,
+    };
+    format!("'{}'", String::from_utf8_lossy(text))
+}
+
+/// Line and column of a byte offset, and the extent of the line.
+struct SourcePos {
+    /// 1-based line number. "\n", "\r", "\n\r", and "\r\n" are line terminators, as in Lua.
+    line: usize,
+    /// 1-based column, in characters
+    col: usize,
+    line_start: usize,
+    /// End of the line, excluding the terminator
+    line_end: usize,
+}
+
+impl SourcePos {
+    fn new(source: &[u8], offset: usize) -> SourcePos {
+        let offset = offset.min(source.len());
+
+        let mut line = 1;
+        let mut line_start = 0;
+        let mut i = 0;
+        while i < offset {
+            match source[i] {
+                b'\n' | b'\r' => {
+                    let c = source[i];
+                    i += 1;
+                    if i < source.len()
+                        && (source[i] == b'\n' || source[i] == b'\r')
+                        && source[i] != c
+                    {
+                        i += 1;
+                    }
+                    line += 1;
+                    line_start = i;
+                }
+                _ => i += 1,
+            }
+        }
+
+        // The offset may be in the middle of a two-byte line terminator
+        let line_start = line_start.min(offset);
+
+        let mut line_end = line_start;
+        while line_end < source.len() && source[line_end] != b'\n' && source[line_end] != b'\r' {
+            line_end += 1;
+        }
+
+        SourcePos {
+            line,
+            col: count_chars(&source[line_start..offset]) + 1,
+            line_start,
+            line_end,
+        }
+    }
+}
+
+fn render_snippet(out: &mut String, file_name: &str, source: &[u8], span: Span, gutter: usize) {
+    let start = (span.start as usize).min(source.len());
+    let pos = SourcePos::new(source, start);
+    let line = &source[pos.line_start..pos.line_end];
+
+    // Underline the span until the end of the line, with at least one caret
+    let end = (span.end as usize).max(start).min(pos.line_end);
+    let n_carets = count_chars(&source[start..end]).max(1);
+
+    // Keep tabs in the indentation of the carets, so that they line up with the source line
+    let indent: String = String::from_utf8_lossy(&source[pos.line_start..start])
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    writeln!(
+        out,
+        "{:gutter$}--> {}:{}:{}",
+        "",
+        file_name,
+        pos.line,
+        pos.col,
+        gutter = gutter
+    )
+    .unwrap();
+    writeln!(out, "{:gutter$} |", "", gutter = gutter).unwrap();
+    if line.is_empty() {
+        writeln!(out, "{:>gutter$} |", pos.line, gutter = gutter).unwrap();
+    } else {
+        writeln!(
+            out,
+            "{:>gutter$} | {}",
+            pos.line,
+            String::from_utf8_lossy(line),
+            gutter = gutter
+        )
+        .unwrap();
+    }
+    writeln!(
+        out,
+        "{:gutter$} | {}{}",
+        "",
+        indent,
+        "^".repeat(n_carets),
+        gutter = gutter
+    )
+    .unwrap();
+}
+
+/// Number of UTF-8 characters in the bytes. Bytes of invalid sequences are counted as one
+/// character each.
+fn count_chars(s: &[u8]) -> usize {
+    s.iter().filter(|&&b| b & 0xC0 != 0x80).count()
+}
+
+/// End of a long bracket (`[[`, `[==[`) starting at the given offset.
+fn long_bracket_end(source: &[u8], start: usize) -> usize {
+    let mut end = start + 1;
+    while end < source.len() && source[end] == b'=' {
+        end += 1;
+    }
+    if end < source.len() && source[end] == b'[' {
+        end += 1;
+    }
+    end.min(source.len())
+}
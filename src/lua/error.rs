@@ -1,6 +1,10 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
 use std::fmt;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum LexerError {
     UnfinishedShortString(u8),
     UnexpectedCharacter(u8),
@@ -16,6 +20,26 @@ pub enum LexerError {
     // IOError(io::Error),
 }
 
+impl LexerError {
+    /// Message of the reference Lua implementation for the error, without the location and the
+    /// "near" part. See `diagnostic::lua_error_message` for the full message.
+    pub fn lua_message(&self) -> &'static str {
+        match self {
+            LexerError::UnfinishedShortString(_) => "unfinished string",
+            LexerError::UnexpectedCharacter(_) => "unexpected symbol",
+            LexerError::HexDigitExpected => "hexadecimal digit expected",
+            LexerError::EscapeUnicodeStart => "missing '{' in \\u{xxxx}",
+            LexerError::EscapeUnicodeEnd => "missing '}' in \\u{xxxx}",
+            LexerError::EscapeUnicodeInvalid => "UTF-8 value too large",
+            LexerError::EscapeDecimalTooLarge => "decimal escape too large",
+            LexerError::InvalidEscape => "invalid escape sequence",
+            LexerError::InvalidLongStringDelimiter => "invalid long string delimiter",
+            LexerError::UnfinishedLongString => "unfinished long string",
+            LexerError::BadNumber => "malformed number",
+        }
+    }
+}
+
 impl fmt::Display for LexerError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fn print_char(c: u8) -> char {
@@ -0,0 +1,770 @@
+// Grammar of Lua 5.4, shared by `parser`, `syntax_check`, and `cst`.
+//
+// Follows the grammar and the error messages of the reference implementation (`lparser.c`). The
+// grammar doesn't build anything: it reports the syntax as events to a `Sink`, which builds an AST
+// (`parser`), a lossless tree (`cst`), or nothing (`syntax_check`). Nodes are started when their
+// kind is known, so nodes that are only known after their first child, e.g. binary expressions,
+// are started at a checkpoint before the child.
+//
+// Optionally, errors about unexpected tokens have the set of tokens that were expected instead:
+// the tokens that the grammar checked for since the last consumed token.
+
+use super::buffered::{BufferedTokens, SpannedToken, TokenSource};
+use super::syntax::{ParseErrorKind, SyntaxError, SyntaxKind};
+use super::token::{Associativity, Span, Token, TokenKind, TokenSet, UNARY_PRECEDENCE};
+
+/// Maximum nesting of statements and expressions, `LUAI_MAXCCALLS` of the reference
+/// implementation. In debug builds parsing this deep needs more than the 2 MiB stack of spawned
+/// threads.
+pub(crate) const MAX_DEPTH: usize = 200;
+
+/// Receives the syntax of a source from `Grammar`. Each consumed token is in the innermost node
+/// started and not finished yet.
+pub trait Sink {
+    type Checkpoint: Copy;
+
+    fn start_node(&mut self, kind: SyntaxKind);
+
+    /// A token consumed by the grammar
+    fn token(&mut self, token: SpannedToken);
+
+    fn finish_node(&mut self);
+
+    /// Position before the next token or node in the current node.
+    fn checkpoint(&mut self) -> Self::Checkpoint;
+
+    /// Starts a node with the tokens and nodes of the current node after the checkpoint.
+    fn start_node_at(&mut self, checkpoint: Self::Checkpoint, kind: SyntaxKind);
+}
+
+/// Sink that ignores the syntax, for only checking it
+impl Sink for () {
+    type Checkpoint = ();
+
+    fn start_node(&mut self, _kind: SyntaxKind) {}
+
+    fn token(&mut self, _token: SpannedToken) {}
+
+    fn finish_node(&mut self) {}
+
+    fn checkpoint(&mut self) {}
+
+    fn start_node_at(&mut self, _checkpoint: (), _kind: SyntaxKind) {}
+}
+
+/// Tokens that start a statement
+const STAT_START: TokenSet = TokenSet::new(&[
+    TokenKind::If,
+    TokenKind::While,
+    TokenKind::Do,
+    TokenKind::For,
+    TokenKind::Repeat,
+    TokenKind::Function,
+    TokenKind::Local,
+    TokenKind::DoubleColon,
+    TokenKind::Break,
+    TokenKind::Goto,
+    TokenKind::Name,
+    TokenKind::LeftParen,
+    TokenKind::Return,
+    TokenKind::SemiColon,
+]);
+
+const UNARY_OPS: TokenSet = TokenSet::new(&[
+    TokenKind::Not,
+    TokenKind::Minus,
+    TokenKind::Len,
+    TokenKind::BitNotXor,
+]);
+
+const BINARY_OPS: TokenSet = TokenSet::new(&[
+    TokenKind::Or,
+    TokenKind::And,
+    TokenKind::LessThan,
+    TokenKind::GreaterThan,
+    TokenKind::LessEqual,
+    TokenKind::GreaterEqual,
+    TokenKind::NotEqual,
+    TokenKind::Equal,
+    TokenKind::BitOr,
+    TokenKind::BitNotXor,
+    TokenKind::BitAnd,
+    TokenKind::ShiftLeft,
+    TokenKind::ShiftRight,
+    TokenKind::Concat,
+    TokenKind::Add,
+    TokenKind::Minus,
+    TokenKind::Mul,
+    TokenKind::Div,
+    TokenKind::IDiv,
+    TokenKind::Mod,
+    TokenKind::Pow,
+]);
+
+/// Tokens that start an expression other than a suffixed expression
+const SIMPLE_EXPR_START: TokenSet = TokenSet::new(&[
+    TokenKind::Nil,
+    TokenKind::True,
+    TokenKind::False,
+    TokenKind::Integer,
+    TokenKind::Float,
+    TokenKind::String,
+    TokenKind::Dots,
+    TokenKind::LeftBrace,
+    TokenKind::Function,
+]);
+
+const PRIMARY_EXPR_START: TokenSet = TokenSet::new(&[TokenKind::Name, TokenKind::LeftParen]);
+
+const CALL_ARGS_START: TokenSet = TokenSet::new(&[
+    TokenKind::LeftParen,
+    TokenKind::String,
+    TokenKind::LeftBrace,
+]);
+
+/// Tokens that continue a suffixed expression
+const SUFFIXES: TokenSet =
+    TokenSet::new(&[TokenKind::Dot, TokenKind::LeftBracket, TokenKind::Colon])
+        .union(CALL_ARGS_START);
+
+pub struct Grammar<L, S> {
+    tokens: BufferedTokens<L>,
+    sink: S,
+    /// Tokens checked for since the last consumed token, when tracked
+    expected: Option<TokenSet>,
+    /// Whether the functions being parsed are vararg functions, from the outermost. The main chunk
+    /// is a vararg function.
+    vararg: Vec<bool>,
+    /// Nesting of the statements and expressions being parsed
+    depth: usize,
+}
+
+impl<L: TokenSource, S: Sink> Grammar<L, S> {
+    pub fn new(lexer: L, sink: S) -> Self {
+        Grammar {
+            tokens: BufferedTokens::new(lexer),
+            sink,
+            expected: None,
+            vararg: vec![true],
+            depth: 0,
+        }
+    }
+
+    /// Enables tracking the expected tokens, for `SyntaxError::expected`. Without tracking the
+    /// expected tokens of errors are empty.
+    pub fn enable_expected_tokens(&mut self) {
+        if self.expected.is_none() {
+            self.expected = Some(TokenSet::EMPTY);
+        }
+    }
+
+    pub fn sink_mut(&mut self) -> &mut S {
+        &mut self.sink
+    }
+
+    pub fn into_sink(self) -> S {
+        self.sink
+    }
+
+    /// Parses the rest of the tokens as a chunk: a block, followed by the end of the source.
+    pub fn chunk(&mut self) -> Result<(), SyntaxError> {
+        self.block(TokenSet::EMPTY)?;
+        match self.peek(TokenSet::EMPTY)? {
+            None => Ok(()),
+            Some(_) => Err(self.error(ParseErrorKind::ExpectedEof)),
+        }
+    }
+
+    /// Parses an expression, returns the kind of its node. The tokens after the expression are
+    /// not consumed.
+    pub fn expr(&mut self) -> Result<SyntaxKind, SyntaxError> {
+        self.sub_expr(0)
+    }
+
+    /// A block, followed by one of the tokens in `follow`, or the end of the source if `follow` is
+    /// empty.
+    fn block(&mut self, follow: TokenSet) -> Result<(), SyntaxError> {
+        self.node(SyntaxKind::Block, |p| loop {
+            match p.peek(STAT_START.union(follow))? {
+                kind if is_block_end(kind) => return Ok(()),
+                Some(TokenKind::Return) => return p.return_stat(follow),
+                Some(TokenKind::SemiColon) => p.bump()?,
+                _ => p.statement()?,
+            }
+        })
+    }
+
+    fn return_stat(&mut self, follow: TokenSet) -> Result<(), SyntaxError> {
+        self.node(SyntaxKind::ReturnStat, |p| {
+            p.bump()?;
+            let kind = p.peek(follow.union(TokenSet::new(&[TokenKind::SemiColon])))?;
+            if !is_block_end(kind) && kind != Some(TokenKind::SemiColon) {
+                p.expr_list()?;
+            }
+            p.check(TokenKind::SemiColon)?;
+            Ok(())
+        })
+    }
+
+    fn statement(&mut self) -> Result<(), SyntaxError> {
+        self.enter()?;
+        match self.peek(STAT_START)? {
+            Some(TokenKind::If) => self.if_stat()?,
+            Some(TokenKind::While) => self.node(SyntaxKind::WhileStat, |p| {
+                p.bump()?;
+                p.expr()?;
+                p.loop_body()
+            })?,
+            Some(TokenKind::Do) => self.node(SyntaxKind::DoStat, |p| {
+                p.bump()?;
+                p.block(TokenSet::new(&[TokenKind::End]))?;
+                p.expect(TokenKind::End)
+            })?,
+            Some(TokenKind::For) => self.for_stat()?,
+            Some(TokenKind::Repeat) => self.node(SyntaxKind::RepeatStat, |p| {
+                p.bump()?;
+                p.block(TokenSet::new(&[TokenKind::Until]))?;
+                p.expect(TokenKind::Until)?;
+                p.expr().map(|_| ())
+            })?,
+            Some(TokenKind::Function) => self.node(SyntaxKind::FunctionStat, |p| {
+                p.bump()?;
+                p.func_name()?;
+                p.func_body()
+            })?,
+            Some(TokenKind::Local) => {
+                let checkpoint = self.sink.checkpoint();
+                self.bump()?;
+                if self.check(TokenKind::Function)? {
+                    self.sink
+                        .start_node_at(checkpoint, SyntaxKind::LocalFunctionStat);
+                    self.expect(TokenKind::Name)?;
+                    self.func_body()?;
+                } else {
+                    self.sink.start_node_at(checkpoint, SyntaxKind::LocalStat);
+                    self.local_stat()?;
+                }
+                self.sink.finish_node();
+            }
+            Some(TokenKind::DoubleColon) => self.node(SyntaxKind::LabelStat, |p| {
+                p.bump()?;
+                p.expect(TokenKind::Name)?;
+                p.expect(TokenKind::DoubleColon)
+            })?,
+            Some(TokenKind::Break) => self.node(SyntaxKind::BreakStat, |p| p.bump())?,
+            Some(TokenKind::Goto) => self.node(SyntaxKind::GotoStat, |p| {
+                p.bump()?;
+                p.expect(TokenKind::Name)
+            })?,
+            _ => self.expr_stat()?,
+        }
+        self.leave();
+        Ok(())
+    }
+
+    fn if_stat(&mut self) -> Result<(), SyntaxError> {
+        let follow = TokenSet::new(&[TokenKind::ElseIf, TokenKind::Else, TokenKind::End]);
+        self.node(SyntaxKind::IfStat, |p| {
+            p.bump()?;
+            p.expr()?;
+            p.expect(TokenKind::Then)?;
+            p.block(follow)?;
+            while p.peek(TokenSet::new(&[TokenKind::ElseIf]))? == Some(TokenKind::ElseIf) {
+                p.node(SyntaxKind::ElseIfClause, |p| {
+                    p.bump()?;
+                    p.expr()?;
+                    p.expect(TokenKind::Then)?;
+                    p.block(follow)
+                })?;
+            }
+            if p.peek(TokenSet::new(&[TokenKind::Else]))? == Some(TokenKind::Else) {
+                p.node(SyntaxKind::ElseClause, |p| {
+                    p.bump()?;
+                    p.block(TokenSet::new(&[TokenKind::End]))
+                })?;
+            }
+            p.expect(TokenKind::End)
+        })
+    }
+
+    fn for_stat(&mut self) -> Result<(), SyntaxError> {
+        let checkpoint = self.sink.checkpoint();
+        self.bump()?;
+        let names = self.sink.checkpoint();
+        self.expect(TokenKind::Name)?;
+        match self.peek(TokenSet::new(&[
+            TokenKind::Assign,
+            TokenKind::Comma,
+            TokenKind::In,
+        ]))? {
+            Some(TokenKind::Assign) => {
+                self.sink
+                    .start_node_at(checkpoint, SyntaxKind::NumericForStat);
+                self.bump()?;
+                self.expr()?;
+                self.expect(TokenKind::Comma)?;
+                self.expr()?;
+                if self.check(TokenKind::Comma)? {
+                    self.expr()?;
+                }
+            }
+            Some(TokenKind::Comma | TokenKind::In) => {
+                self.sink
+                    .start_node_at(checkpoint, SyntaxKind::GenericForStat);
+                self.sink.start_node_at(names, SyntaxKind::NameList);
+                while self.check(TokenKind::Comma)? {
+                    self.expect(TokenKind::Name)?;
+                }
+                self.sink.finish_node();
+                self.expect(TokenKind::In)?;
+                self.expr_list()?;
+            }
+            _ => return Err(self.error(ParseErrorKind::ExpectedAssignOrIn)),
+        }
+        self.loop_body()?;
+        self.sink.finish_node();
+        Ok(())
+    }
+
+    /// `do block end` of a loop
+    fn loop_body(&mut self) -> Result<(), SyntaxError> {
+        self.expect(TokenKind::Do)?;
+        self.block(TokenSet::new(&[TokenKind::End]))?;
+        self.expect(TokenKind::End)
+    }
+
+    fn func_name(&mut self) -> Result<(), SyntaxError> {
+        self.node(SyntaxKind::FuncName, |p| {
+            p.expect(TokenKind::Name)?;
+            while p.check(TokenKind::Dot)? {
+                p.expect(TokenKind::Name)?;
+            }
+            if p.check(TokenKind::Colon)? {
+                p.expect(TokenKind::Name)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn func_body(&mut self) -> Result<(), SyntaxError> {
+        self.node(SyntaxKind::FuncBody, |p| {
+            let mut is_vararg = false;
+            p.node(SyntaxKind::ParamList, |p| {
+                p.expect(TokenKind::LeftParen)?;
+                if p.check(TokenKind::RightParen)? {
+                    return Ok(());
+                }
+                loop {
+                    match p.peek(TokenSet::new(&[TokenKind::Name, TokenKind::Dots]))? {
+                        Some(TokenKind::Name) => p.bump()?,
+                        Some(TokenKind::Dots) => {
+                            p.bump()?;
+                            is_vararg = true;
+                        }
+                        _ => return Err(p.error(ParseErrorKind::ExpectedParam)),
+                    }
+                    if is_vararg || !p.check(TokenKind::Comma)? {
+                        break;
+                    }
+                }
+                p.expect(TokenKind::RightParen)
+            })?;
+
+            p.vararg.push(is_vararg);
+            p.block(TokenSet::new(&[TokenKind::End]))?;
+            p.expect(TokenKind::End)?;
+            p.vararg.pop();
+            Ok(())
+        })
+    }
+
+    /// `local` statement after the `local` keyword, other than `local function`
+    fn local_stat(&mut self) -> Result<(), SyntaxError> {
+        let mut has_close = false;
+        loop {
+            self.node(SyntaxKind::LocalVar, |p| {
+                p.expect(TokenKind::Name)?;
+                if p.peek(TokenSet::new(&[TokenKind::LessThan]))? != Some(TokenKind::LessThan) {
+                    return Ok(());
+                }
+                p.node(SyntaxKind::Attrib, |p| {
+                    p.bump()?;
+                    p.peek(TokenSet::new(&[TokenKind::Name]))?;
+                    let (err, span) = match p.tokens.peek()? {
+                        Some(SpannedToken {
+                            token: Token::Name(name),
+                            span,
+                        }) => {
+                            let err = match &name[..] {
+                                b"const" => None,
+                                b"close" if !has_close => {
+                                    has_close = true;
+                                    None
+                                }
+                                b"close" => Some(ParseErrorKind::MultipleToBeClosed),
+                                _ => Some(ParseErrorKind::UnknownAttribute(name.clone())),
+                            };
+                            (err, *span)
+                        }
+                        _ => return Err(p.error(ParseErrorKind::Expected(TokenKind::Name))),
+                    };
+                    p.bump()?;
+                    p.expect(TokenKind::GreaterThan)?;
+                    match err {
+                        None => Ok(()),
+                        Some(kind) => Err(SyntaxError {
+                            kind,
+                            span,
+                            found: Some(TokenKind::Name),
+                            expected: TokenSet::EMPTY,
+                        }),
+                    }
+                })
+            })?;
+            if !self.check(TokenKind::Comma)? {
+                break;
+            }
+        }
+
+        if self.check(TokenKind::Assign)? {
+            self.expr_list()?;
+        }
+        Ok(())
+    }
+
+    /// Assignment or function call statement
+    fn expr_stat(&mut self) -> Result<(), SyntaxError> {
+        let checkpoint = self.sink.checkpoint();
+        let mut kind = self.suffixed_expr()?;
+        if matches!(
+            self.peek(TokenSet::new(&[TokenKind::Assign, TokenKind::Comma]))?,
+            Some(TokenKind::Assign | TokenKind::Comma)
+        ) {
+            self.sink.start_node_at(checkpoint, SyntaxKind::AssignStat);
+            self.sink.start_node_at(checkpoint, SyntaxKind::VarList);
+            loop {
+                if !kind.is_var() {
+                    return Err(self.semantic_error(ParseErrorKind::SyntaxError));
+                }
+                if !self.check(TokenKind::Comma)? {
+                    break;
+                }
+                kind = self.suffixed_expr()?;
+            }
+            self.sink.finish_node();
+            self.expect(TokenKind::Assign)?;
+            self.expr_list()?;
+        } else if kind.is_call() {
+            self.sink.start_node_at(checkpoint, SyntaxKind::CallStat);
+        } else {
+            return Err(self.error(ParseErrorKind::SyntaxError));
+        }
+        self.sink.finish_node();
+        Ok(())
+    }
+
+    fn expr_list(&mut self) -> Result<(), SyntaxError> {
+        self.node(SyntaxKind::ExprList, |p| {
+            p.expr()?;
+            while p.check(TokenKind::Comma)? {
+                p.expr()?;
+            }
+            Ok(())
+        })
+    }
+
+    /// An expression with binary operators that have higher precedence than `limit`
+    fn sub_expr(&mut self, limit: u8) -> Result<SyntaxKind, SyntaxError> {
+        self.enter()?;
+        let checkpoint = self.sink.checkpoint();
+
+        let mut kind = match self.peek(UNARY_OPS)? {
+            Some(kind) if UNARY_OPS.contains(kind) => {
+                self.sink.start_node_at(checkpoint, SyntaxKind::UnaryExpr);
+                self.bump()?;
+                self.sub_expr(UNARY_PRECEDENCE)?;
+                self.sink.finish_node();
+                SyntaxKind::UnaryExpr
+            }
+            _ => self.simple_expr()?,
+        };
+
+        loop {
+            let op = self.peek(BINARY_OPS)?;
+            let (precedence, associativity) = match op.and_then(TokenKind::binary_precedence) {
+                Some((precedence, associativity)) if precedence > limit => {
+                    (precedence, associativity)
+                }
+                _ => break,
+            };
+            self.sink.start_node_at(checkpoint, SyntaxKind::BinaryExpr);
+            self.bump()?;
+            match associativity {
+                Associativity::Left => {
+                    self.sub_expr(precedence)?;
+                }
+                Associativity::Right => self.right_assoc_chain(precedence)?,
+            }
+            self.sink.finish_node();
+            kind = SyntaxKind::BinaryExpr;
+        }
+
+        self.leave();
+        Ok(kind)
+    }
+
+    /// The rest of a chain of right associative operators with the same precedence, e.g. `b .. c`
+    /// after `a ..`, in the `BinaryExpr` node of the first operator. Chains are parsed
+    /// iteratively, as long chains of `..` are common and would otherwise exceed `MAX_DEPTH`.
+    fn right_assoc_chain(&mut self, precedence: u8) -> Result<(), SyntaxError> {
+        let mut n_open = 0;
+        loop {
+            let checkpoint = self.sink.checkpoint();
+            self.sub_expr(precedence)?;
+            let op = self.peek(BINARY_OPS)?;
+            if op.and_then(TokenKind::binary_precedence) != Some((precedence, Associativity::Right))
+            {
+                break;
+            }
+            self.sink.start_node_at(checkpoint, SyntaxKind::BinaryExpr);
+            self.bump()?;
+            n_open += 1;
+        }
+        for _ in 0..n_open {
+            self.sink.finish_node();
+        }
+        Ok(())
+    }
+
+    fn simple_expr(&mut self) -> Result<SyntaxKind, SyntaxError> {
+        match self.peek(SIMPLE_EXPR_START)? {
+            Some(
+                kind @ (TokenKind::Nil
+                | TokenKind::True
+                | TokenKind::False
+                | TokenKind::Integer
+                | TokenKind::Float
+                | TokenKind::String
+                | TokenKind::Dots),
+            ) => {
+                if kind == TokenKind::Dots && !self.vararg.last().unwrap() {
+                    return Err(self.semantic_error(ParseErrorKind::VarargOutsideVarargFunction));
+                }
+                self.node(SyntaxKind::LiteralExpr, |p| p.bump())?;
+                Ok(SyntaxKind::LiteralExpr)
+            }
+            Some(TokenKind::LeftBrace) => self.table(),
+            Some(TokenKind::Function) => {
+                self.node(SyntaxKind::FunctionExpr, |p| {
+                    p.bump()?;
+                    p.func_body()
+                })?;
+                Ok(SyntaxKind::FunctionExpr)
+            }
+            _ => self.suffixed_expr(),
+        }
+    }
+
+    /// A name or a parenthesized expression
+    fn primary_expr(&mut self) -> Result<SyntaxKind, SyntaxError> {
+        match self.peek(PRIMARY_EXPR_START)? {
+            Some(TokenKind::Name) => {
+                self.node(SyntaxKind::NameExpr, |p| p.bump())?;
+                Ok(SyntaxKind::NameExpr)
+            }
+            Some(TokenKind::LeftParen) => {
+                self.node(SyntaxKind::ParenExpr, |p| {
+                    p.bump()?;
+                    p.expr()?;
+                    p.expect(TokenKind::RightParen)
+                })?;
+                Ok(SyntaxKind::ParenExpr)
+            }
+            _ => Err(self.error(ParseErrorKind::UnexpectedSymbol)),
+        }
+    }
+
+    /// A primary expression followed by fields, indexing, and calls
+    fn suffixed_expr(&mut self) -> Result<SyntaxKind, SyntaxError> {
+        let checkpoint = self.sink.checkpoint();
+        let mut kind = self.primary_expr()?;
+        loop {
+            kind = match self.peek(SUFFIXES)? {
+                Some(TokenKind::Dot) => {
+                    self.sink.start_node_at(checkpoint, SyntaxKind::FieldExpr);
+                    self.bump()?;
+                    self.expect(TokenKind::Name)?;
+                    SyntaxKind::FieldExpr
+                }
+                Some(TokenKind::LeftBracket) => {
+                    self.sink.start_node_at(checkpoint, SyntaxKind::IndexExpr);
+                    self.bump()?;
+                    self.expr()?;
+                    self.expect(TokenKind::RightBracket)?;
+                    SyntaxKind::IndexExpr
+                }
+                Some(TokenKind::Colon) => {
+                    self.sink
+                        .start_node_at(checkpoint, SyntaxKind::MethodCallExpr);
+                    self.bump()?;
+                    self.expect(TokenKind::Name)?;
+                    self.call_args()?;
+                    SyntaxKind::MethodCallExpr
+                }
+                Some(TokenKind::LeftParen | TokenKind::String | TokenKind::LeftBrace) => {
+                    self.sink.start_node_at(checkpoint, SyntaxKind::CallExpr);
+                    self.call_args()?;
+                    SyntaxKind::CallExpr
+                }
+                _ => return Ok(kind),
+            };
+            self.sink.finish_node();
+        }
+    }
+
+    fn call_args(&mut self) -> Result<(), SyntaxError> {
+        match self.peek(CALL_ARGS_START)? {
+            Some(TokenKind::String) => self.node(SyntaxKind::CallArgs, |p| {
+                p.node(SyntaxKind::LiteralExpr, |p| p.bump())
+            }),
+            Some(TokenKind::LeftBrace) => {
+                self.node(SyntaxKind::CallArgs, |p| p.table().map(|_| ()))
+            }
+            Some(TokenKind::LeftParen) => self.node(SyntaxKind::CallArgs, |p| {
+                p.bump()?;
+                if p.check(TokenKind::RightParen)? {
+                    return Ok(());
+                }
+                p.expr_list()?;
+                p.expect(TokenKind::RightParen)
+            }),
+            _ => Err(self.error(ParseErrorKind::ExpectedArgs)),
+        }
+    }
+
+    /// Table constructor
+    fn table(&mut self) -> Result<SyntaxKind, SyntaxError> {
+        self.node(SyntaxKind::TableExpr, |p| {
+            p.expect(TokenKind::LeftBrace)?;
+            while p.peek(TokenSet::new(&[TokenKind::RightBrace]))? != Some(TokenKind::RightBrace) {
+                match p.peek(TokenSet::new(&[TokenKind::LeftBracket, TokenKind::Name]))? {
+                    Some(TokenKind::LeftBracket) => p.node(SyntaxKind::IndexField, |p| {
+                        p.bump()?;
+                        p.expr()?;
+                        p.expect(TokenKind::RightBracket)?;
+                        p.expect(TokenKind::Assign)?;
+                        p.expr().map(|_| ())
+                    })?,
+                    Some(TokenKind::Name) if p.peek_nth_kind(1)? == Some(TokenKind::Assign) => p
+                        .node(SyntaxKind::NamedField, |p| {
+                            p.bump()?;
+                            p.bump()?;
+                            p.expr().map(|_| ())
+                        })?,
+                    _ => p.node(SyntaxKind::PositionalField, |p| p.expr().map(|_| ()))?,
+                }
+                if !p.check(TokenKind::Comma)? && !p.check(TokenKind::SemiColon)? {
+                    break;
+                }
+            }
+            p.expect(TokenKind::RightBrace)
+        })?;
+        Ok(SyntaxKind::TableExpr)
+    }
+
+    /// A node with the tokens and nodes that `f` consumes. The node is not finished if `f` fails.
+    fn node<F>(&mut self, kind: SyntaxKind, f: F) -> Result<(), SyntaxError>
+    where
+        F: FnOnce(&mut Self) -> Result<(), SyntaxError>,
+    {
+        self.sink.start_node(kind);
+        f(self)?;
+        self.sink.finish_node();
+        Ok(())
+    }
+
+    /// Records that the tokens in `expected` are accepted here, and returns the kind of the next
+    /// token.
+    fn peek(&mut self, expected: TokenSet) -> Result<Option<TokenKind>, SyntaxError> {
+        if let Some(tracked) = &mut self.expected {
+            *tracked = tracked.union(expected);
+        }
+        self.peek_nth_kind(0)
+    }
+
+    fn peek_nth_kind(&mut self, n: usize) -> Result<Option<TokenKind>, SyntaxError> {
+        Ok(self.tokens.peek_nth(n)?.map(|token| token.token.kind()))
+    }
+
+    /// Consumes the next token. Only called after peeking the token.
+    fn bump(&mut self) -> Result<(), SyntaxError> {
+        let token = self.tokens.next_token()?.unwrap();
+        self.sink.token(token);
+        if let Some(tracked) = &mut self.expected {
+            *tracked = TokenSet::EMPTY;
+        }
+        Ok(())
+    }
+
+    /// Consumes the next token if it's of the given kind.
+    fn check(&mut self, kind: TokenKind) -> Result<bool, SyntaxError> {
+        if self.peek(TokenSet::new(&[kind]))? == Some(kind) {
+            self.bump()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Consumes the next token, which must be of the given kind.
+    fn expect(&mut self, kind: TokenKind) -> Result<(), SyntaxError> {
+        if self.check(kind)? {
+            Ok(())
+        } else {
+            Err(self.error(ParseErrorKind::Expected(kind)))
+        }
+    }
+
+    /// Error at the next token, with the expected tokens. The span is the span of the next token,
+    /// or an empty span after the last token at the end of the source.
+    fn error(&mut self, kind: ParseErrorKind) -> SyntaxError {
+        let end = self.tokens.prev_span().end as usize;
+        match self.tokens.peek() {
+            Ok(token) => SyntaxError {
+                kind,
+                span: token.map_or(Span::new(end, end), |token| token.span),
+                found: token.map(|token| token.token.kind()),
+                expected: self.expected.unwrap_or(TokenSet::EMPTY),
+            },
+            Err(err) => err.into(),
+        }
+    }
+
+    /// Error at the next token that is not about the token being unexpected
+    fn semantic_error(&mut self, kind: ParseErrorKind) -> SyntaxError {
+        SyntaxError {
+            expected: TokenSet::EMPTY,
+            ..self.error(kind)
+        }
+    }
+
+    fn enter(&mut self) -> Result<(), SyntaxError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(self.semantic_error(ParseErrorKind::TooManyLevels));
+        }
+        Ok(())
+    }
+
+    fn leave(&mut self) {
+        self.depth -= 1;
+    }
+}
+
+/// Whether the token ends a block: `else`, `elseif`, `end`, `until`, or the end of the source
+fn is_block_end(kind: Option<TokenKind>) -> bool {
+    matches!(
+        kind,
+        None | Some(TokenKind::Else | TokenKind::ElseIf | TokenKind::End | TokenKind::Until)
+    )
+}
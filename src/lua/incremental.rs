@@ -0,0 +1,160 @@
+// Incremental re-lexing for editors. After an edit, tokens are re-lexed from the last token that
+// the edit can't affect until the new tokens re-synchronise with the old ones.
+//
+// Lexing a token only depends on the source from the start of the token, so once a new token
+// starts where an old token after the edit started (after shifting by the edit's length change),
+// the rest of the new tokens are the same as the old ones.
+
+use super::error::LexerError;
+use super::lexer_luster::{Lexer, LexerState};
+use super::token::{Span, Token};
+
+use std::ops::Range;
+
+/// Tokens of a source, updated incrementally with `edit`.
+#[derive(Debug, Clone)]
+pub struct IncrementalLexer {
+    source: Vec<u8>,
+    tokens: Vec<Token<Vec<u8>>>,
+    spans: Vec<Span>,
+    /// State of the lexer after each token
+    states: Vec<LexerState>,
+    /// Error after the last token, if lexing failed
+    error: Option<(LexerError, Span)>,
+}
+
+impl IncrementalLexer {
+    pub fn new(source: Vec<u8>) -> Self {
+        let mut lexer = IncrementalLexer {
+            source,
+            tokens: vec![],
+            spans: vec![],
+            states: vec![],
+            error: None,
+        };
+        lexer.relex(0, 0, 0);
+        lexer
+    }
+
+    pub fn source(&self) -> &[u8] {
+        &self.source
+    }
+
+    pub fn tokens(&self) -> &[Token<Vec<u8>>] {
+        &self.tokens
+    }
+
+    pub fn spans(&self) -> &[Span] {
+        &self.spans
+    }
+
+    /// The error after the last token and its span, if the source is not valid.
+    pub fn error(&self) -> Option<&(LexerError, Span)> {
+        self.error.as_ref()
+    }
+
+    /// Replaces `range` of the source with `text` and re-lexes the changed part. Returns the range
+    /// of the new tokens. Tokens before the range are unchanged, tokens after the range are the
+    /// old tokens after the changed ones with spans shifted by the edit's length change.
+    pub fn edit(&mut self, range: Range<usize>, text: &[u8]) -> Range<usize> {
+        self.source.splice(range.clone(), text.iter().copied());
+        let delta = text.len() as i64 - range.len() as i64;
+
+        // The lexer looks at most one byte past the end of a token, so tokens ending before the
+        // byte before the edit are not affected
+        let restart = self
+            .spans
+            .partition_point(|span| (span.end as usize) < range.start);
+
+        // First old token that starts after the edit, where re-synchronising can start
+        let old = self
+            .spans
+            .partition_point(|span| (span.start as usize) < range.end);
+
+        self.relex(restart, old, delta)
+    }
+
+    /// Re-lexes the source after the token `restart - 1`, replacing the tokens until the first
+    /// token at or after `old` that the new tokens re-synchronise with.
+    fn relex(&mut self, restart: usize, mut old: usize, delta: i64) -> Range<usize> {
+        let state = if restart == 0 {
+            LexerState::default()
+        } else {
+            self.states[restart - 1].clone()
+        };
+
+        let mut lexer = Lexer::with_state(
+            &self.source[state.byte_offset..],
+            |s: &[u8]| s.to_owned(),
+            state,
+        );
+
+        let shift = |offset: u32| (offset as i64 + delta) as u32;
+
+        let mut tokens = vec![];
+        let mut spans = vec![];
+        let mut states = vec![];
+        let mut error = None;
+
+        // Old token that the new tokens re-synchronised with, and the change in line numbers
+        let mut resync = None;
+
+        loop {
+            let token = match lexer.read_token() {
+                Ok(Some(token)) => token,
+                Ok(None) => break,
+                Err(err) => {
+                    error = Some((err, Span::new(lexer.token_start(), lexer.byte_offset())));
+                    break;
+                }
+            };
+            let span = Span::new(lexer.token_start(), lexer.byte_offset());
+
+            while old < self.spans.len() && shift(self.spans[old].start) < span.start {
+                old += 1;
+            }
+            if old < self.spans.len() && shift(self.spans[old].start) == span.start {
+                let line_delta = lexer.line_number() as i64 - self.states[old].line_number as i64;
+                resync = Some((old, line_delta));
+                break;
+            }
+
+            tokens.push(token);
+            spans.push(span);
+            states.push(lexer.state());
+        }
+
+        let end = match resync {
+            Some((old, line_delta)) => {
+                for span in &mut self.spans[old..] {
+                    *span = Span {
+                        start: shift(span.start),
+                        end: shift(span.end),
+                    };
+                }
+                for state in &mut self.states[old..] {
+                    state.byte_offset = (state.byte_offset as i64 + delta) as usize;
+                    state.line_number = (state.line_number as i64 + line_delta) as u64;
+                }
+                if let Some((_, span)) = &mut self.error {
+                    *span = Span {
+                        start: shift(span.start),
+                        end: shift(span.end),
+                    };
+                }
+                old
+            }
+            None => {
+                self.error = error;
+                self.tokens.len()
+            }
+        };
+
+        let n_new = tokens.len();
+        self.tokens.splice(restart..end, tokens);
+        self.spans.splice(restart..end, spans);
+        self.states.splice(restart..end, states);
+
+        restart..restart + n_new
+    }
+}
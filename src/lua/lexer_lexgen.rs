@@ -1,11 +1,15 @@
 use super::error::LexerError as LexerError_;
 use super::lexer_luster as luster;
-use super::token::Token;
+use super::token::{Literal, LiteralKind, Span, Token};
+use super::token_buffer::TokenBuffer;
+use super::warning::Warning;
 
 use lexgen::lexer;
+use lexgen_util::{LexerError, LexerErrorKind, Loc};
 
 use std::convert::TryFrom;
 use std::mem::replace;
+use std::str::Chars;
 
 #[derive(Debug, Default, Clone)]
 pub struct LexerState {
@@ -37,12 +41,22 @@ impl Default for Quote {
     }
 }
 
+impl Quote {
+    fn char(self) -> char {
+        match self {
+            Quote::Single => '\'',
+            Quote::Double => '"',
+        }
+    }
+}
+
 lexer! {
     pub Lexer(LexerState) -> Token<Vec<u8>>;
 
     type Error = LexerError_;
 
-    let whitespace = [' ' '\t' '\n'] | "\r\n";
+    // Same as `lisspace` in the reference implementation: C's `isspace` in the "C" locale
+    let whitespace = [' ' '\t' '\n' '\r' '\x0B' '\x0C'];
 
     // > Names (also called identifiers) in Lua can be any string of letters, digits, and
     // > underscores, not beginning with a digit. This coincides with the definition of names in
@@ -54,6 +68,9 @@ lexer! {
     let digit = ['0'-'9'];
     let hex_digit = ['a'-'f' 'A'-'F' '0'-'9'];
 
+    let numeral_subseq = (('e' | 'E') ('+' | '-')?) | $hex_digit | '.';
+    let hex_numeral_subseq = (('p' | 'P') ('+' | '-')?) | $hex_digit | '.';
+
     rule Init {
         $whitespace,
 
@@ -144,17 +161,16 @@ lexer! {
             lexer.return_(Token::Name(match_.as_bytes().to_owned()))
         },
 
-        $digit+ '.'? $digit* (('e' | 'E') ('+'|'-')? $digit+)? =? |lexer| {
-            let match_ = lexer.match_();
-            lexer.return_(read_numeral(match_))
-        },
-
-        '.' $digit+ (('e' | 'E') ('+'|'-')? $digit+)? =? |lexer| {
+        // Numerals are matched the way the reference implementation reads them: everything that
+        // could be a part of a numeral, plus a letter touching the numeral. Malformed numerals
+        // like `3abc` or `1e` are then rejected by `read_numeral` instead of being split into
+        // multiple tokens.
+        ($digit | '.' $digit) $numeral_subseq* $var_init? =? |lexer| {
             let match_ = lexer.match_();
             lexer.return_(read_numeral(match_))
         },
 
-        '0' ('x'|'X') $hex_digit* '.'? $hex_digit* (('p' | 'P') ('+'|'-')? $hex_digit+)? =? |lexer| {
+        '0' ('x' | 'X') $hex_numeral_subseq* $var_init? =? |lexer| {
             let match_ = lexer.match_();
             lexer.return_(read_numeral(match_))
         },
@@ -202,7 +218,8 @@ lexer! {
                         lexer.switch(LexerRule::Init)
                     } else {
                         let match_ = &lexer.match_()[left_eqs + 2..lexer.match_().len() - right_eqs - 2];
-                        lexer.switch_and_return(LexerRule::Init, Token::String(match_.as_bytes().to_owned()))
+                        let str = long_string_contents(match_.as_bytes());
+                        lexer.switch_and_return(LexerRule::Init, Token::String(str))
                     }
                 } else {
                     lexer.state().long_string_closing_eqs = 0;
@@ -404,7 +421,7 @@ lexer! {
             }
         },
 
-        '\n' => |lexer|
+        ['\n' '\r'] => |lexer|
             lexer.switch(LexerRule::Init),
 
         _ => |lexer|
@@ -412,7 +429,7 @@ lexer! {
     }
 
     rule Comment {
-        '\n' => |lexer|
+        ['\n' '\r'] => |lexer|
             lexer.switch(LexerRule::Init),
 
         _ => |lexer|
@@ -420,7 +437,718 @@ lexer! {
     }
 }
 
+/// Lexes the whole source into a `TokenBuffer`.
+pub fn tokenize_all(source: &str) -> Result<TokenBuffer, LexerError<LexerError_>> {
+    let mut buffer = TokenBuffer::with_capacity_for_source(source.len());
+    for token in Lexer::new(source) {
+        let (start, token, end) = token?;
+        buffer.push_token(token, Span::new(start.byte_idx, end.byte_idx));
+    }
+    Ok(buffer)
+}
+
+/// Item of the lexer
+pub type LexerItem = Result<(Loc, Token<Vec<u8>>, Loc), LexerError<LexerError_>>;
+
+/// Lexer that also collects warnings about legal but suspicious code, see `LexerWarning`. Warnings
+/// are reported by the rules in `warning_rules`, and don't affect the tokens.
+pub struct WarningLexer<'input> {
+    lexer: warning_rules::Lexer<'input, Chars<'input>>,
+}
+
+pub fn lex_with_warnings(source: &str) -> WarningLexer<'_> {
+    WarningLexer {
+        lexer: warning_rules::Lexer::new(source),
+    }
+}
+
+impl<'input> WarningLexer<'input> {
+    /// Takes the warnings collected so far.
+    pub fn take_warnings(&mut self) -> Vec<Warning> {
+        warning_rules::take_warnings(&mut self.lexer)
+    }
+}
+
+impl<'input> Iterator for WarningLexer<'input> {
+    type Item = LexerItem;
+
+    fn next(&mut self) -> Option<LexerItem> {
+        self.lexer.next()
+    }
+}
+
+/// Gets the error and the erroneous part of the source for an error of this lexer, to be rendered
+/// with `Diagnostic::from_lexer_error`, by falling back to the luster lexer.
+///
+/// lexgen errors only have the location of the token that failed to lex, and `InvalidToken`
+/// errors (e.g. unfinished strings, as there's no rule for the end of input in string states)
+/// don't say what's wrong with the token. So the token is lexed again with the luster lexer: the
+/// span is always luster's, and the error is luster's unless lexgen returned a custom error. When
+/// the lexers disagree about the token, the result describes luster's view of it: if luster lexes
+/// the token without an error, the span is empty and an `InvalidToken` error is reported as an
+/// unexpected character.
+pub fn luster_fallback_error_span(
+    source: &str,
+    err: &LexerError<LexerError_>,
+) -> (LexerError_, Span) {
+    let start = err.location.byte_idx;
+
+    let mut lexer = luster::Lexer::new(&source.as_bytes()[start..], |_| ());
+    let luster_err = lexer.read_token().err();
+
+    let span = match luster_err {
+        Some(_) => Span::new(start + lexer.token_start(), start + lexer.byte_offset()),
+        None => Span::new(start, start),
+    };
+
+    let err = match (&err.kind, luster_err) {
+        (LexerErrorKind::Custom(err), _) => err.clone(),
+        (LexerErrorKind::InvalidToken, Some(err)) => err,
+        (LexerErrorKind::InvalidToken, None) => {
+            LexerError_::UnexpectedCharacter(source.as_bytes().get(start).copied().unwrap_or(0))
+        }
+    };
+
+    (err, span)
+}
+
+/// Gets the raw source text and metadata of a literal token. `start` and `end` are the locations of
+/// the token, as returned by the lexer for `input`.
+pub fn literal<'input>(
+    input: &'input str,
+    start: &Loc,
+    token: &Token<Vec<u8>>,
+    end: &Loc,
+) -> Option<Literal<&'input [u8]>> {
+    if !token.is_literal() {
+        return None;
+    }
+
+    let raw = &input.as_bytes()[start.byte_idx..end.byte_idx];
+    Some(Literal {
+        raw,
+        kind: LiteralKind::from_raw(raw),
+    })
+}
+
+/// Skips the newline immediately following the opening delimiter of a long string, and normalizes
+/// newline sequences ("\n", "\r", "\n\r", "\r\n") to "\n".
+fn long_string_contents(s: &[u8]) -> Vec<u8> {
+    fn newline_len(s: &[u8]) -> usize {
+        match s {
+            [b'\n', b'\r', ..] | [b'\r', b'\n', ..] => 2,
+            [b'\n', ..] | [b'\r', ..] => 1,
+            _ => 0,
+        }
+    }
+
+    let mut i = newline_len(s);
+    let mut str = Vec::with_capacity(s.len() - i);
+
+    while i < s.len() {
+        match newline_len(&s[i..]) {
+            0 => {
+                str.push(s[i]);
+                i += 1;
+            }
+            n => {
+                str.push(b'\n');
+                i += n;
+            }
+        }
+    }
+
+    str
+}
+
 fn read_numeral<S>(s: &str) -> Result<Token<S>, LexerError_> {
-    // println!("read_numeral({:?})", s);
-    luster::Lexer::new(s.as_bytes(), |_| panic!()).read_numeral()
+    luster::read_number(s.as_bytes()).ok_or(LexerError_::BadNumber)
+}
+
+// A copy of the rules of `Lexer` that also reports warnings, for `WarningLexer`. The bookkeeping
+// for warnings is kept out of `Lexer`, so that it doesn't slow down the lexer being benchmarked.
+// The rules must lex the same tokens as `Lexer`'s.
+mod warning_rules {
+    use super::super::token::{Span, Token};
+    use super::super::warning::{LexerWarning, NewlineTracker, Warning};
+    use super::{long_string_contents, read_numeral, LexerError_, Quote};
+
+    use lexgen::lexer;
+
+    use std::convert::TryFrom;
+    use std::mem::{self, replace};
+    use std::str::Chars;
+
+    #[derive(Debug, Default, Clone)]
+    pub struct LexerState {
+        /// Number of opening `=`s seen when parsing a long string
+        long_string_opening_eqs: usize,
+        /// Number of closing `=`s seen when parsing a long string
+        long_string_closing_eqs: usize,
+        /// When parsing a short string, whether it's started with a double or single quote
+        short_string_delim: Quote,
+        /// Buffer for strings
+        string_buf: Vec<u8>,
+        /// When parsing a long string, whether we're inside a comment or not. When inside a comment
+        /// we don't return a token. Otherwise we return a string.
+        in_comment: bool,
+        /// Unicode codepoint being parsed.
+        unicode_codepoint: u32,
+        /// Collected warnings
+        warnings: Vec<Warning>,
+        newlines: NewlineTracker,
+        /// Start of the indentation of the current line and whether it has a tab, when the
+        /// whitespace of the indentation is split between a comment's newlines and the following
+        /// whitespace
+        indent: Option<(usize, bool)>,
+    }
+
+    /// Gets the run of newline characters at the end of a match.
+    fn trailing_newlines(match_: &str) -> &[u8] {
+        let n_newlines = match_.len() - match_.trim_end_matches(['\n', '\r']).len();
+        &match_.as_bytes()[match_.len() - n_newlines..]
+    }
+
+    impl LexerState {
+        fn warn(&mut self, kind: LexerWarning, start: usize, end: usize) {
+            self.warnings.push(Warning {
+                kind,
+                span: Span::new(start, end),
+            });
+        }
+
+        /// Tracks the newline sequence at `s[i]`, where `s` starts at byte offset `start`. Returns
+        /// the index after the newline sequence.
+        fn newline(&mut self, s: &[u8], i: usize, start: usize) -> usize {
+            let c = s[i];
+            let second = match s.get(i + 1) {
+                Some(&next) if (next == b'\n' || next == b'\r') && next != c => Some(next),
+                _ => None,
+            };
+            let end = if second.is_some() { i + 2 } else { i + 1 };
+            if self.newlines.newline((c, second)) {
+                self.warn(LexerWarning::MixedLineEndings, start + i, start + end);
+            }
+            end
+        }
+
+        /// Tracks the newline sequences in `s`, which starts at byte offset `start`.
+        fn newlines(&mut self, s: &[u8], start: usize) {
+            let mut i = 0;
+            while i < s.len() {
+                if s[i] == b'\n' || s[i] == b'\r' {
+                    i = self.newline(s, i, start);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+
+        /// Tracks the newline sequences at the end of a match that ends at byte offset `end`.
+        fn match_newlines(&mut self, match_: &str, end: usize) {
+            let newlines = trailing_newlines(match_);
+            self.newlines(newlines, end - newlines.len());
+        }
+
+        /// Same as `match_newlines`, for the newlines that end a comment, which are followed by
+        /// whitespace between tokens. `next` is the character after the newlines.
+        fn comment_newlines(&mut self, match_: &str, end: usize, next: Option<char>) {
+            let newlines = trailing_newlines(match_);
+            self.whitespace(newlines, end - newlines.len(), next);
+        }
+
+        /// Scans whitespace between tokens for newlines and tabs in indentation. `s` starts at byte
+        /// offset `start`, `next` is the character after the whitespace.
+        fn whitespace(&mut self, s: &[u8], start: usize, next: Option<char>) {
+            // Start of the indentation and whether it has a tab, when at the start of a line
+            let mut indent = match self.indent.take() {
+                Some(indent) => Some(indent),
+                None if start == 0 => Some((0, false)),
+                None => None,
+            };
+
+            let mut i = 0;
+            while i < s.len() {
+                match s[i] {
+                    b'\n' | b'\r' => {
+                        i = self.newline(s, i, start);
+                        indent = Some((start + i, false));
+                    }
+                    b'\t' => {
+                        if let Some((_, has_tab)) = &mut indent {
+                            *has_tab = true;
+                        }
+                        i += 1;
+                    }
+                    _ => i += 1,
+                }
+            }
+
+            match next {
+                // Whitespace at the end of the source is not indentation
+                None => {}
+                Some(' ' | '\t' | '\n' | '\r' | '\x0B' | '\x0C') => self.indent = indent,
+                Some(_) => {
+                    if let Some((indent_start, true)) = indent {
+                        self.warn(
+                            LexerWarning::TabInIndentation,
+                            indent_start,
+                            start + s.len(),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    lexer! {
+        pub Lexer(LexerState) -> Token<Vec<u8>>;
+
+        type Error = LexerError_;
+
+        // Same as `lisspace` in the reference implementation: C's `isspace` in the "C" locale
+        let whitespace = [' ' '\t' '\n' '\r' '\x0B' '\x0C'];
+
+        // > Names (also called identifiers) in Lua can be any string of letters, digits, and
+        // > underscores, not beginning with a digit. This coincides with the definition of names in
+        // > most languages. (The definition of letter depends on the current locale: any character
+        // > considered alphabetic by the current locale can be used in an identifier.)
+        let var_init = ['a'-'z' 'A'-'Z' '_'];
+        let var_subseq = $var_init | ['0'-'9'];
+
+        let digit = ['0'-'9'];
+        let hex_digit = ['a'-'f' 'A'-'F' '0'-'9'];
+
+        let numeral_subseq = (('e' | 'E') ('+' | '-')?) | $hex_digit | '.';
+        let hex_numeral_subseq = (('p' | 'P') ('+' | '-')?) | $hex_digit | '.';
+
+        rule Init {
+            $whitespace+ => |lexer| {
+                let start = lexer.match_loc().0.byte_idx;
+                let whitespace = lexer.match_().as_bytes();
+                let next = lexer.peek();
+                lexer.state().whitespace(whitespace, start, next);
+                lexer.reset_match();
+                lexer.continue_()
+            },
+
+            "+" = Token::Add,
+            "-" = Token::Minus,
+            "*" = Token::Mul,
+            "/" = Token::Div,
+            "//" = Token::IDiv,
+            "%" = Token::Mod,
+            "^" = Token::Pow,
+            "#" = Token::Len,
+            "==" = Token::Equal,
+            "~=" = Token::NotEqual,
+            "<=" = Token::LessEqual,
+            ">=" = Token::GreaterEqual,
+            "<" = Token::LessThan,
+            ">" = Token::GreaterThan,
+            "=" = Token::Assign,
+            "(" = Token::LeftParen,
+            ")" = Token::RightParen,
+            "{" = Token::LeftBrace,
+            "}" = Token::RightBrace,
+            "]" = Token::RightBracket,
+            ";" = Token::SemiColon,
+            ":" = Token::Colon,
+            "," = Token::Comma,
+            "." = Token::Dot,
+            ".." = Token::Concat,
+            "..." = Token::Dots,
+            "&" = Token::BitAnd,
+            "|" = Token::BitOr,
+            "~" = Token::BitNotXor,
+            ">>" = Token::ShiftRight,
+            "<<" = Token::ShiftLeft,
+            "::" = Token::DoubleColon,
+            "and" = Token::And,
+            "break" = Token::Break,
+            "do" = Token::Do,
+            "else" = Token::Else,
+            "elseif" = Token::ElseIf,
+            "end" = Token::End,
+            "false" = Token::False,
+            "for" = Token::For,
+            "function" = Token::Function,
+            "if" = Token::If,
+            "in" = Token::In,
+            "local" = Token::Local,
+            "nil" = Token::Nil,
+            "not" = Token::Not,
+            "or" = Token::Or,
+            "repeat" = Token::Repeat,
+            "return" = Token::Return,
+            "then" = Token::Then,
+            "true" = Token::True,
+            "until" = Token::Until,
+            "while" = Token::While,
+            "goto" = Token::Goto,
+
+            '"' => |lexer| {
+                lexer.state().short_string_delim = Quote::Double;
+                lexer.state().string_buf.clear();
+                lexer.switch(LexerRule::String)
+            },
+
+            '\'' => |lexer| {
+                lexer.state().short_string_delim = Quote::Single;
+                lexer.state().string_buf.clear();
+                lexer.switch(LexerRule::String)
+            },
+
+            "[" => |lexer| {
+                match lexer.peek() {
+                    Some('[') | Some('=') => {
+                        lexer.state().long_string_opening_eqs = 0;
+                        lexer.state().in_comment = false;
+                        lexer.switch(LexerRule::LongStringBracketLeft)
+                    }
+                    _ => lexer.return_(Token::LeftBracket),
+                }
+            },
+
+            "--" => |lexer| {
+                lexer.switch(LexerRule::EnterComment)
+            },
+
+            $var_init $var_subseq* => |lexer| {
+                let match_ = lexer.match_();
+                lexer.return_(Token::Name(match_.as_bytes().to_owned()))
+            },
+
+            // Numerals are matched the way the reference implementation reads them: everything that
+            // could be a part of a numeral, plus a letter touching the numeral. Malformed numerals
+            // like `3abc` or `1e` are then rejected by `read_numeral` instead of being split into
+            // multiple tokens.
+            ($digit | '.' $digit) $numeral_subseq* $var_init? =? |lexer| {
+                let match_ = lexer.match_();
+                lexer.return_(read_numeral(match_))
+            },
+
+            '0' ('x' | 'X') $hex_numeral_subseq* $var_init? =? |lexer| {
+                let match_ = lexer.match_();
+                lexer.return_(read_numeral(match_))
+            },
+        }
+
+        rule LongStringBracketLeft {
+            '=' =>
+                |lexer| {
+                    lexer.state().long_string_opening_eqs += 1;
+                    lexer.continue_()
+                },
+
+            '[' =>
+                |lexer|
+                    lexer.switch(LexerRule::LongString),
+        }
+
+        rule LongString {
+            ['\n' '\r']+ =>
+                |lexer| {
+                    let (match_, end) = (lexer.match_(), lexer.match_loc().1.byte_idx);
+                    lexer.state().match_newlines(match_, end);
+                    lexer.continue_()
+                },
+
+            ']' =>
+                |lexer| {
+                    lexer.state().long_string_closing_eqs = 0;
+                    lexer.switch(LexerRule::LongStringBracketRight)
+                },
+
+            _ =>
+                |lexer|
+                    lexer.continue_(),
+        }
+
+        rule LongStringBracketRight {
+            '=' =>
+                |lexer| {
+                    lexer.state().long_string_closing_eqs += 1;
+                    lexer.continue_()
+                },
+
+            ']' =>
+                |lexer| {
+                    let state = lexer.state();
+                    let in_comment = state.in_comment;
+                    let left_eqs = state.long_string_opening_eqs;
+                    let right_eqs = state.long_string_closing_eqs;
+                    if left_eqs == right_eqs {
+                        if in_comment {
+                            lexer.switch(LexerRule::Init)
+                        } else {
+                            let match_ = lexer.match_();
+                            let match_ = &match_[left_eqs + 2..match_.len() - right_eqs - 2];
+                            let str = long_string_contents(match_.as_bytes());
+                            lexer.switch_and_return(LexerRule::Init, Token::String(str))
+                        }
+                    } else {
+                        // Same as the luster lexer, `]]` is not reported
+                        if in_comment && right_eqs != 0 {
+                            let end = lexer.match_loc().1.byte_idx;
+                            lexer.state().warn(
+                                LexerWarning::MismatchedLongBracketInComment,
+                                end - right_eqs - 2,
+                                end,
+                            );
+                        }
+                        lexer.state().long_string_closing_eqs = 0;
+                        lexer.continue_()
+                    }
+                },
+
+            ['\n' '\r']+ =>
+                |lexer| {
+                    let (match_, end) = (lexer.match_(), lexer.match_loc().1.byte_idx);
+                    lexer.state().match_newlines(match_, end);
+                    lexer.switch(LexerRule::LongString)
+                },
+
+            _ =>
+                |lexer|
+                    lexer.switch(LexerRule::LongString),
+        }
+
+        rule String {
+            '"' => |lexer| {
+                if lexer.state().short_string_delim == Quote::Double {
+                    let str = replace(&mut lexer.state().string_buf, Vec::new());
+                    lexer.switch_and_return(LexerRule::Init, Token::String(str))
+                } else {
+                    lexer.state().string_buf.push(b'"');
+                    lexer.continue_()
+                }
+            },
+
+            "'" => |lexer| {
+                if lexer.state().short_string_delim == Quote::Single {
+                    let str = replace(&mut lexer.state().string_buf, Vec::new());
+                    lexer.switch_and_return(LexerRule::Init, Token::String(str))
+                } else {
+                    lexer.state().string_buf.push(b'\'');
+                    lexer.continue_()
+                }
+            },
+
+            "\\a" => |lexer| {
+                lexer.state().string_buf.push(0x7);
+                lexer.continue_()
+            },
+
+            "\\b" => |lexer| {
+                lexer.state().string_buf.push(0x8);
+                lexer.continue_()
+            },
+
+            "\\f" => |lexer| {
+                lexer.state().string_buf.push(0xc);
+                lexer.continue_()
+            },
+
+            "\\n" => |lexer| {
+                lexer.state().string_buf.push(b'\n');
+                lexer.continue_()
+            },
+
+            "\\r" => |lexer| {
+                lexer.state().string_buf.push(b'\r');
+                lexer.continue_()
+            },
+
+            "\\t" => |lexer| {
+                lexer.state().string_buf.push(b'\t');
+                lexer.continue_()
+            },
+
+            "\\v" => |lexer| {
+                lexer.state().string_buf.push(0xb);
+                lexer.continue_()
+            },
+
+            "\\\\" => |lexer| {
+                lexer.state().string_buf.push(b'\\');
+                lexer.continue_()
+            },
+
+            "\\\"" => |lexer| {
+                lexer.state().string_buf.push(b'"');
+                lexer.continue_()
+            },
+
+            "\\'" => |lexer| {
+                lexer.state().string_buf.push(b'\'');
+                lexer.continue_()
+            },
+
+            "\\\n" => |lexer| {
+                let (match_, end) = (lexer.match_(), lexer.match_loc().1.byte_idx);
+                let state = lexer.state();
+                state.string_buf.push(b'\n');
+                state.match_newlines(match_, end);
+                lexer.continue_()
+            },
+
+            // TODO: Better way to match 1-3 digits?
+            '\\' $digit => |lexer| {
+                let match_ = lexer.match_();
+                let bytes = match_.as_bytes();
+                let digit = bytes[bytes.len() - 1] - b'0';
+                lexer.state().string_buf.push(digit);
+                lexer.continue_()
+            },
+
+            '\\' $digit $digit => |lexer| {
+                let match_ = lexer.match_();
+                let bytes = match_.as_bytes();
+                let digit1 = bytes[bytes.len() - 2] - b'0';
+                let digit2 = bytes[bytes.len() - 1] - b'0';
+                lexer.state().string_buf.push(digit1 * 10 + digit2);
+                lexer.continue_()
+            },
+
+            '\\' $digit $digit $digit => |lexer| {
+                let match_ = lexer.match_();
+                let bytes = match_.as_bytes();
+                let digit1 = bytes[bytes.len() - 3] - b'0';
+                let digit2 = bytes[bytes.len() - 2] - b'0';
+                let digit3 = bytes[bytes.len() - 1] - b'0';
+                lexer.state().string_buf.push(
+                    digit1 * 100 + digit2 * 10 + digit3
+                );
+                if matches!(lexer.peek(), Some('0'..='9')) {
+                    let end = lexer.match_loc().1.byte_idx;
+                    lexer.state().warn(LexerWarning::DecimalEscapeFollowedByDigit, end - 4, end);
+                }
+                lexer.continue_()
+            },
+
+            "\\x" $hex_digit $hex_digit => |lexer| {
+                let match_ = lexer.match_();
+                let bytes = match_.as_bytes();
+                use super::luster::from_hex_digit;
+                let digit1 = from_hex_digit(bytes[bytes.len() - 2]).unwrap();
+                let digit2 = from_hex_digit(bytes[bytes.len() - 1]).unwrap();
+                lexer.state().string_buf.push(
+                    digit1 * 16 + digit2
+                );
+                lexer.continue_()
+            },
+
+            // TODO: This is implemented as a separate rule to as otherwise it's difficult to get
+            // the match for the hex characters only (instead of the entire match that includes
+            // "\x{" and stuff before it). We should allow binding regexes inside patterns.
+            "\\u{" => |lexer| {
+                lexer.state().unicode_codepoint = 0;
+                lexer.switch(LexerRule::UnicodeCodepoint)
+            },
+
+            "\\z" $whitespace* => |lexer| {
+                let (match_, end) = (lexer.match_().as_bytes(), lexer.match_loc().1.byte_idx);
+                let n_spaces = match_
+                    .iter()
+                    .rev()
+                    .take_while(|&&c| c != b'z')
+                    .count();
+                let quote = lexer.state().short_string_delim.char();
+                let at_string_end = lexer.peek() == Some(quote);
+                let state = lexer.state();
+                state.newlines(&match_[match_.len() - n_spaces..], end - n_spaces);
+                if at_string_end {
+                    state.warn(LexerWarning::EscapeZAtEndOfString, end - n_spaces - 2, end);
+                }
+                lexer.continue_()
+            },
+
+            _ => |lexer| {
+                let char = lexer.match_().chars().next_back().unwrap();
+                let state = lexer.state();
+                let char_utf8_len = char.len_utf8();
+                let cursor = state.string_buf.len();
+                state.string_buf.reserve(char_utf8_len);
+                for _ in 0 .. char_utf8_len {
+                    state.string_buf.push(0);
+                }
+                char.encode_utf8(&mut state.string_buf[cursor..]);
+                lexer.continue_()
+            },
+        }
+
+        rule UnicodeCodepoint {
+            $hex_digit => |lexer| {
+                let c = lexer.match_().chars().next_back().unwrap();
+                let digit = if c >= '0' && c <= '9' {
+                    c as u32 - '0' as u32
+                } else if c >= 'a' && c <= 'f' {
+                    c as u32 - 'a' as u32 + 10
+                } else {
+                    c as u32 - 'A' as u32 + 10
+                };
+
+                let state = lexer.state();
+                state.unicode_codepoint *= 16;
+                state.unicode_codepoint += digit;
+
+                lexer.continue_()
+            },
+
+            '}' => |lexer| {
+                let state = lexer.state();
+                let char = char::try_from(state.unicode_codepoint).unwrap();
+                let char_utf8_len = char.len_utf8();
+                let cursor = state.string_buf.len();
+                state.string_buf.reserve(char_utf8_len);
+                for _ in 0 .. char_utf8_len {
+                    state.string_buf.push(0);
+                }
+                char.encode_utf8(&mut state.string_buf[cursor..]);
+                lexer.switch(LexerRule::String)
+            },
+        }
+
+        rule EnterComment {
+            '[' => |lexer| {
+                match lexer.peek() {
+                    Some('[') | Some('=') => {
+                        lexer.state().long_string_opening_eqs = 0;
+                        lexer.state().in_comment = true;
+                        lexer.switch(LexerRule::LongStringBracketLeft)
+                    }
+                    _ =>
+                        lexer.switch(LexerRule::Comment),
+                }
+            },
+
+            ['\n' '\r']+ => |lexer| {
+                let (match_, end) = (lexer.match_(), lexer.match_loc().1.byte_idx);
+                let next = lexer.peek();
+                lexer.state().comment_newlines(match_, end, next);
+                lexer.switch(LexerRule::Init)
+            },
+
+            _ => |lexer|
+                lexer.switch(LexerRule::Comment),
+        }
+
+        rule Comment {
+            ['\n' '\r']+ => |lexer| {
+                let (match_, end) = (lexer.match_(), lexer.match_loc().1.byte_idx);
+                let next = lexer.peek();
+                lexer.state().comment_newlines(match_, end, next);
+                lexer.switch(LexerRule::Init)
+            },
+
+            _ => |lexer|
+                lexer.continue_(),
+        }
+    }
+
+    /// Takes the warnings collected by `lexer` so far.
+    pub(super) fn take_warnings(lexer: &mut Lexer<'_, Chars<'_>>) -> Vec<Warning> {
+        mem::take(&mut lexer.state().warnings)
+    }
 }
@@ -1,15 +1,58 @@
 use super::error::LexerError;
-use super::token::Token;
+use super::token::{Literal, LiteralKind, Span, Token};
+use super::token_buffer::TokenBuffer;
+use super::warning::{LexerWarning, NewlineTracker, Warning};
 
 use std::io::{self, Read};
-use std::{char, i32, i64, str};
+use std::{char, i64, mem, str};
 
+/// A token, and the raw source text and metadata of the token if it's a literal
+pub type TokenWithLiteral<S> = (Token<S>, Option<Literal<S>>);
+
+/// Source text skipped between tokens, see `Lexer::enable_trivia`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriviaKind {
+    /// Spaces, tabs, vertical tabs, form feeds, and newlines
+    Whitespace,
+    /// A short or long comment, including the `--`
+    Comment,
+}
+
+#[derive(Clone)]
 pub struct Lexer<R, CS> {
     source: Option<R>,
     create_string: CS,
     peek_buffer: Vec<u8>,
     string_buffer: Vec<u8>,
     line_number: u64,
+    byte_offset: usize,
+    /// Byte offset of the start of the current token or comment
+    token_start: usize,
+    /// Source text of the current token, when `record_raw` is set
+    raw_buffer: Vec<u8>,
+    record_raw: bool,
+    /// Whether the last short comment read runs to the end of the source so far
+    in_short_comment: bool,
+    /// Collected warnings, when enabled
+    warnings: Option<Vec<Warning>>,
+    newlines: NewlineTracker,
+    /// Whitespace and comments skipped since the last `take_trivia`, when enabled
+    trivia: Option<Vec<Trivia>>,
+}
+
+/// State of the lexer between two tokens, for resuming lexing in the middle of a source. See
+/// `Lexer::state` and `Lexer::with_state`.
+#[derive(Debug, Default, Clone)]
+pub struct LexerState {
+    pub line_number: u64,
+    pub byte_offset: usize,
+    newlines: NewlineTracker,
 }
 
 impl<R, S, CS> Lexer<R, CS>
@@ -18,12 +61,35 @@ where
     CS: FnMut(&[u8]) -> S,
 {
     pub fn new(source: R, create_string: CS) -> Lexer<R, CS> {
+        Lexer::with_state(source, create_string, Default::default())
+    }
+
+    /// Creates a lexer that resumes lexing from a state returned by `state`. `source` should start
+    /// at the state's byte offset.
+    pub fn with_state(source: R, create_string: CS, state: LexerState) -> Lexer<R, CS> {
         Lexer {
             source: Some(source),
             create_string,
             peek_buffer: Vec::new(),
             string_buffer: Vec::new(),
-            line_number: 0,
+            line_number: state.line_number,
+            byte_offset: state.byte_offset,
+            token_start: state.byte_offset,
+            raw_buffer: Vec::new(),
+            record_raw: false,
+            in_short_comment: false,
+            warnings: None,
+            newlines: state.newlines,
+            trivia: None,
+        }
+    }
+
+    /// Current state of the lexer. Lexing can be resumed from the state after a token.
+    pub fn state(&self) -> LexerState {
+        LexerState {
+            line_number: self.line_number,
+            byte_offset: self.byte_offset,
+            newlines: self.newlines.clone(),
         }
     }
 
@@ -32,22 +98,87 @@ where
         self.line_number
     }
 
+    /// Number of bytes consumed from the source
+    pub fn byte_offset(&self) -> usize {
+        self.byte_offset
+    }
+
+    /// Byte offset of the start of the token or comment read last. After an error, the erroneous
+    /// part of the source is from this offset to `byte_offset`.
+    pub fn token_start(&self) -> usize {
+        self.token_start
+    }
+
+    /// Whether the source ended in a short comment, after `read_token` or `skip_whitespace`
+    /// returned at the end of the source. The comment may continue if more of the source follows.
+    pub fn in_short_comment(&self) -> bool {
+        self.in_short_comment
+    }
+
+    /// Enables collecting warnings about legal but suspicious code, see `LexerWarning`. Warnings
+    /// don't affect the tokens.
+    pub fn enable_warnings(&mut self) {
+        if self.warnings.is_none() {
+            self.warnings = Some(Vec::new());
+        }
+    }
+
+    /// Takes the warnings collected so far.
+    pub fn take_warnings(&mut self) -> Vec<Warning> {
+        self.warnings.as_mut().map(mem::take).unwrap_or_default()
+    }
+
+    /// Enables collecting the whitespace and comments that the lexer skips, e.g. for lossless
+    /// syntax trees. A run of whitespace is one `Trivia`, and so is each comment.
+    pub fn enable_trivia(&mut self) {
+        if self.trivia.is_none() {
+            self.trivia = Some(Vec::new());
+        }
+    }
+
+    /// Takes the trivia skipped so far. After `read_token` these are the trivia before the token,
+    /// and before the end of the source or the error if there's no token.
+    pub fn take_trivia(&mut self) -> Vec<Trivia> {
+        self.trivia.as_mut().map(mem::take).unwrap_or_default()
+    }
+
     pub fn skip_whitespace(&mut self) -> Result<(), LexerError> {
         let mut do_skip_whitespace = || {
+            // Start of the indentation and whether it has a tab, when at the start of a line
+            let mut indent = if self.byte_offset == 0 {
+                Some((0, false))
+            } else {
+                None
+            };
+
+            // Start of the whitespace being skipped
+            let mut whitespace_start = None;
+
             while let Some(c) = self.peek(0)? {
                 match c {
                     b' ' | b'\t' | VERTICAL_TAB | FORM_FEED => {
+                        whitespace_start.get_or_insert(self.byte_offset);
+                        if c == b'\t' {
+                            if let Some((_, has_tab)) = &mut indent {
+                                *has_tab = true;
+                            }
+                        }
                         self.advance(1);
                     }
 
                     b'\n' | b'\r' => {
+                        whitespace_start.get_or_insert(self.byte_offset);
                         self.read_line_end(false)?;
+                        indent = Some((self.byte_offset, false));
                     }
 
                     b'-' => {
                         if self.peek(1)? != Some(b'-') {
                             break;
                         } else {
+                            self.end_indentation(&mut indent);
+                            self.end_whitespace(&mut whitespace_start);
+                            self.token_start = self.byte_offset;
                             self.advance(2);
 
                             match (self.peek(0)?, self.peek(1)?) {
@@ -57,8 +188,10 @@ where
                                 }
                                 _ => {
                                     // Short comment, read until end of line
+                                    self.in_short_comment = true;
                                     while let Some(c) = self.peek(0)? {
                                         if is_newline(c) {
+                                            self.in_short_comment = false;
                                             break;
                                         } else {
                                             self.advance(1);
@@ -66,6 +199,7 @@ where
                                     }
                                 }
                             }
+                            self.push_trivia(TriviaKind::Comment, self.token_start);
                         }
                     }
 
@@ -73,6 +207,12 @@ where
                 }
             }
 
+            // Whitespace at the end of the source is not indentation
+            if self.peek(0)?.is_some() {
+                self.end_indentation(&mut indent);
+            }
+            self.end_whitespace(&mut whitespace_start);
+
             Ok(())
         };
 
@@ -88,6 +228,7 @@ where
     /// Reads the next token, or None if the end of the source has been reached.
     pub fn read_token(&mut self) -> Result<Option<Token<S>>, LexerError> {
         self.skip_whitespace()?;
+        self.token_start = self.byte_offset;
 
         let mut do_read_token = || {
             if let Some(c) = self.peek(0)? {
@@ -251,6 +392,59 @@ where
         }
     }
 
+    /// Like `read_token`, but also returns the raw source text and metadata of literal tokens.
+    pub fn read_token_with_literal(&mut self) -> Result<Option<TokenWithLiteral<S>>, LexerError> {
+        self.skip_whitespace()?;
+
+        self.raw_buffer.clear();
+        self.record_raw = true;
+        let token = self.read_token();
+        self.record_raw = false;
+
+        Ok(token?.map(|token| {
+            let literal = if token.is_literal() {
+                Some(Literal {
+                    raw: (self.create_string)(&self.raw_buffer),
+                    kind: LiteralKind::from_raw(&self.raw_buffer),
+                })
+            } else {
+                None
+            };
+            (token, literal)
+        }))
+    }
+
+    fn warn(&mut self, kind: LexerWarning, start: usize, end: usize) {
+        if let Some(warnings) = &mut self.warnings {
+            warnings.push(Warning {
+                kind,
+                span: Span::new(start, end),
+            });
+        }
+    }
+
+    fn push_trivia(&mut self, kind: TriviaKind, start: usize) {
+        if let Some(trivia) = &mut self.trivia {
+            trivia.push(Trivia {
+                kind,
+                span: Span::new(start, self.byte_offset),
+            });
+        }
+    }
+
+    fn end_whitespace(&mut self, whitespace_start: &mut Option<usize>) {
+        if let Some(start) = whitespace_start.take() {
+            self.push_trivia(TriviaKind::Whitespace, start);
+        }
+    }
+
+    fn end_indentation(&mut self, indent: &mut Option<(usize, bool)>) {
+        if let Some((start, true)) = *indent {
+            self.warn(LexerWarning::TabInIndentation, start, self.byte_offset);
+        }
+        *indent = None;
+    }
+
     // End of stream encountered, clear any input handles and temp buffers
     fn reset(&mut self) {
         self.source = None;
@@ -261,6 +455,7 @@ where
     // Read any of "\n", "\r", "\n\r", or "\r\n" as a single newline, and increment the current line
     // number.  If `append_buffer` is true, then appends the read newline to the string buffer.
     fn read_line_end(&mut self, append_string: bool) -> Result<(), LexerError> {
+        let start = self.byte_offset;
         let newline = self.peek(0).unwrap().unwrap();
         assert!(is_newline(newline));
         self.advance(1);
@@ -268,15 +463,21 @@ where
             self.string_buffer.push(newline);
         }
 
+        let mut second = None;
         if let Some(next_newline) = self.peek(0)? {
             if is_newline(next_newline) && next_newline != newline {
                 self.advance(1);
                 if append_string {
                     self.string_buffer.push(next_newline);
                 }
+                second = Some(next_newline);
             }
         }
 
+        if self.warnings.is_some() && self.newlines.newline((newline, second)) {
+            self.warn(LexerWarning::MixedLineEndings, start, self.byte_offset);
+        }
+
         self.line_number += 1;
         Ok(())
     }
@@ -303,6 +504,7 @@ where
 
             self.advance(1);
             if c == b'\\' {
+                let escape_start = self.byte_offset - 1;
                 match self
                     .peek(0)?
                     .ok_or_else(|| LexerError::UnfinishedShortString(start_quote))?
@@ -416,14 +618,20 @@ where
                                 break;
                             }
                         }
+                        if self.peek(0)? == Some(start_quote) {
+                            let end = self.byte_offset;
+                            self.warn(LexerWarning::EscapeZAtEndOfString, escape_start, end);
+                        }
                     }
 
                     c => {
                         if is_digit(c) {
                             let mut u: u16 = 0;
+                            let mut n_digits = 0;
                             for _ in 0..3 {
                                 if let Some(d) = self.peek(0)?.and_then(from_digit) {
                                     u = 10 * u + d as u16;
+                                    n_digits += 1;
                                     self.advance(1);
                                 } else {
                                     break;
@@ -432,6 +640,14 @@ where
                             if u > 255 {
                                 return Err(LexerError::EscapeDecimalTooLarge);
                             }
+                            if n_digits == 3 && self.peek(0)?.is_some_and(is_digit) {
+                                let end = self.byte_offset;
+                                self.warn(
+                                    LexerWarning::DecimalEscapeFollowedByDigit,
+                                    escape_start,
+                                    end,
+                                );
+                            }
 
                             self.string_buffer.push(u as u8);
                         } else {
@@ -450,7 +666,8 @@ where
     }
 
     // Read a [=*[...]=*] sequence with matching numbers of '='.  If `into_string` is true, writes
-    // the contained string into the string buffer.
+    // the contained string into the string buffer, skipping the first newline and normalizing
+    // newline sequences to "\n".
     fn read_long_string(&mut self, into_string: bool) -> Result<(), LexerError> {
         assert_eq!(self.peek(0).unwrap().unwrap(), b'[');
         self.advance(1);
@@ -470,6 +687,13 @@ where
         }
         self.advance(1);
 
+        // A newline immediately following the opening delimiter is not included in the string
+        if let Some(c) = self.peek(0)? {
+            if is_newline(c) {
+                self.read_line_end(false)?;
+            }
+        }
+
         loop {
             let c = if let Some(c) = self.peek(0)? {
                 c
@@ -479,10 +703,15 @@ where
 
             match c {
                 b'\n' | b'\r' => {
-                    self.read_line_end(into_string)?;
+                    // Newline sequences are normalized to "\n"
+                    self.read_line_end(false)?;
+                    if into_string {
+                        self.string_buffer.push(b'\n');
+                    }
                 }
 
                 b']' => {
+                    let bracket_start = self.byte_offset;
                     let mut close_sep_length = 0;
                     self.advance(1);
                     while self.peek(0)? == Some(b'=') {
@@ -494,6 +723,17 @@ where
                         self.advance(1);
                         break;
                     } else {
+                        // A closing bracket of another level in a comment may be a typo. `]]` is
+                        // not reported, as it's common in comments of higher levels.
+                        if !into_string && close_sep_length != 0 && self.peek(0)? == Some(b']') {
+                            let end = self.byte_offset + 1;
+                            self.warn(
+                                LexerWarning::MismatchedLongBracketInComment,
+                                bracket_start,
+                                end,
+                            );
+                        }
+
                         // If it turns out this is not a valid long string close delimiter, we need
                         // to add the invalid close delimiter to the string.
                         if into_string {
@@ -520,42 +760,34 @@ where
     // Reads a hex or decimal integer or floating point identifier.  Allows decimal integers (123),
     // hex integers (0xdeadbeef), decimal floating point with optional exponent and exponent sign
     // (3.21e+1), and hex floats with optional exponent and exponent sign (0xe.2fp-1c).
+    //
+    // Like the reference implementation, this first reads everything that looks like it could be
+    // part of a numeral (hex digits, dots, exponent marks and signs following them) plus one letter
+    // touching the numeral, and then checks the whole thing, so `3abc`, `0xg` and `1e` are reported
+    // as malformed numbers instead of being split into multiple tokens.
     pub fn read_numeral(&mut self) -> Result<Token<S>, LexerError> {
         let p1 = self.peek(0).unwrap().unwrap();
         assert!(p1 == b'.' || is_digit(p1));
 
         self.string_buffer.clear();
+        self.string_buffer.push(p1);
+        self.advance(1);
 
-        let p2 = self.peek(1)?;
-        let is_hex = p1 == b'0' && (p2 == Some(b'x') || p2 == Some(b'X'));
-        if is_hex {
-            self.string_buffer.push(p1);
-            self.string_buffer.push(p2.unwrap());
-            self.advance(2);
+        let mut exp_marks = [b'e', b'E'];
+        if p1 == b'0' {
+            if let Some(x) = self.peek(0)? {
+                if x == b'x' || x == b'X' {
+                    self.string_buffer.push(x);
+                    self.advance(1);
+                    exp_marks = [b'p', b'P'];
+                }
+            }
         }
 
-        let mut has_radix = false;
         while let Some(c) = self.peek(0)? {
-            if c == b'.' && !has_radix {
-                self.string_buffer.push(b'.');
-                has_radix = true;
-                self.advance(1);
-            } else if (!is_hex && is_digit(c)) || (is_hex && is_hex_digit(c)) {
+            if exp_marks.contains(&c) {
                 self.string_buffer.push(c);
                 self.advance(1);
-            } else {
-                break;
-            }
-        }
-
-        let mut has_exp = false;
-        if let Some(exp_begin) = self.peek(0)? {
-            if (is_hex && (exp_begin == b'p' || exp_begin == b'P'))
-                || (!is_hex && (exp_begin == b'e' || exp_begin == b'E'))
-            {
-                self.string_buffer.push(exp_begin);
-                has_exp = true;
-                self.advance(1);
 
                 if let Some(sign) = self.peek(0)? {
                     if sign == b'+' || sign == b'-' {
@@ -563,37 +795,23 @@ where
                         self.advance(1);
                     }
                 }
-
-                while let Some(c) = self.peek(0)? {
-                    if is_digit(c) {
-                        self.string_buffer.push(c);
-                        self.advance(1);
-                    } else {
-                        break;
-                    }
-                }
+            } else if is_hex_digit(c) || c == b'.' {
+                self.string_buffer.push(c);
+                self.advance(1);
+            } else {
+                break;
             }
         }
 
-        if !has_exp && !has_radix {
-            if is_hex {
-                if let Some(i) = read_hex_integer(&self.string_buffer) {
-                    return Ok(Token::Integer(i));
-                }
-            }
-            if let Some(i) = read_integer(&self.string_buffer) {
-                return Ok(Token::Integer(i));
+        // Numeral touching a letter, consume the letter to force an error
+        if let Some(c) = self.peek(0)? {
+            if is_alpha(c) {
+                self.string_buffer.push(c);
+                self.advance(1);
             }
         }
 
-        Ok(Token::Float(
-            if is_hex {
-                read_hex_float(&self.string_buffer)
-            } else {
-                read_float(&self.string_buffer)
-            }
-            .ok_or(LexerError::BadNumber)?,
-        ))
+        read_number(&self.string_buffer).ok_or(LexerError::BadNumber)
     }
 
     fn peek(&mut self, n: usize) -> Result<Option<u8>, LexerError> {
@@ -627,7 +845,11 @@ where
             n <= self.peek_buffer.len(),
             "cannot advance over un-peeked characters"
         );
+        if self.record_raw {
+            self.raw_buffer.extend_from_slice(&self.peek_buffer[..n]);
+        }
         self.peek_buffer.drain(0..n);
+        self.byte_offset += n;
     }
 
     fn take_string(&mut self) -> S {
@@ -637,58 +859,120 @@ where
     }
 }
 
+/// Lexes the whole source into a `TokenBuffer`. Contents of names and strings are written directly
+/// to the buffer's string arena.
+pub fn tokenize_all(source: &[u8]) -> Result<TokenBuffer, LexerError> {
+    let mut buffer = TokenBuffer::with_capacity_for_source(source.len());
+    let mut strings = mem::take(&mut buffer.strings);
+
+    let mut lexer = Lexer::new(source, |s: &[u8]| strings.alloc(s));
+    loop {
+        lexer.skip_whitespace()?;
+        let start = lexer.byte_offset();
+        match lexer.read_token()? {
+            Some(token) => buffer.push(token, Span::new(start, lexer.byte_offset())),
+            None => break,
+        }
+    }
+    drop(lexer);
+
+    buffer.strings = strings;
+    Ok(buffer)
+}
+
+/// Lexes until the first error. Returns the error and the erroneous part of the source, or `None`
+/// if the source has no errors. `tokenize_all` only returns the error, this finds its location.
+pub fn first_error(source: &[u8]) -> Option<(LexerError, Span)> {
+    let mut lexer = Lexer::new(source, |_: &[u8]| ());
+    loop {
+        match lexer.read_token() {
+            Ok(Some(_)) => {}
+            Ok(None) => return None,
+            Err(err) => return Some((err, Span::new(lexer.token_start(), lexer.byte_offset()))),
+        }
+    }
+}
+
+/// Converts a numeral to an integer or float token. Integers that don't fit into an `i64` are
+/// converted to floats. Returns `None` if the numeral is malformed.
+pub fn read_number<S>(s: &[u8]) -> Option<Token<S>> {
+    let (_, unsigned) = read_neg(s);
+    if unsigned.len() >= 2 && unsigned[0] == b'0' && (unsigned[1] == b'x' || unsigned[1] == b'X') {
+        read_hex_integer(s)
+            .map(Token::Integer)
+            .or_else(|| read_hex_float(s).map(Token::Float))
+    } else {
+        read_integer(s)
+            .map(Token::Integer)
+            .or_else(|| read_float(s).map(Token::Float))
+    }
+}
+
+/// Reads a decimal integer. Returns `None` if the integer doesn't fit into an `i64`, in which case
+/// the reference implementation reads the numeral as a float.
 pub fn read_integer(s: &[u8]) -> Option<i64> {
     let (is_neg, s) = read_neg(s);
 
-    let mut i: i64 = 0;
+    if s.is_empty() {
+        return None;
+    }
+
+    let mut i: u64 = 0;
     for &c in s {
-        let d = from_digit(c)? as i64;
+        let d = from_digit(c)? as u64;
         i = i.checked_mul(10)?.checked_add(d)?;
     }
 
     if is_neg {
-        i = i.checked_neg()?;
-    }
-
-    if i == i64::MIN {
+        if i > i64::MIN.unsigned_abs() {
+            None
+        } else {
+            Some((i as i64).wrapping_neg())
+        }
+    } else if i > i64::MAX as u64 {
         None
     } else {
-        Some(i)
+        Some(i as i64)
     }
 }
 
+/// Reads a hexadecimal integer. Unlike decimal integers, hexadecimal integers wrap around modulo
+/// 2^64, so `0xffffffffffffffff` is `-1`.
 pub fn read_hex_integer(s: &[u8]) -> Option<i64> {
     let (is_neg, s) = read_neg(s);
 
-    if s[0] != b'0' || (s[1] != b'x' && s[1] != b'X') {
+    if s.len() < 3 || s[0] != b'0' || (s[1] != b'x' && s[1] != b'X') {
         return None;
     }
 
-    let mut i: i64 = 0;
+    let mut i: u64 = 0;
     for &c in &s[2..] {
-        let d = from_hex_digit(c)? as i64;
-        i = i.checked_mul(16)?.checked_add(d)?;
+        let d = from_hex_digit(c)? as u64;
+        i = i.wrapping_mul(16).wrapping_add(d);
     }
 
     if is_neg {
-        i = i.checked_neg()?;
+        i = i.wrapping_neg();
     }
 
-    if i == i64::MIN {
-        None
-    } else {
-        Some(i)
-    }
+    Some(i as i64)
 }
 
 pub fn read_float(s: &[u8]) -> Option<f64> {
+    // `str::parse` also accepts "inf" and "nan", which are not valid Lua numerals
+    if !s
+        .iter()
+        .all(|&c| is_digit(c) || c == b'.' || c == b'e' || c == b'E' || c == b'+' || c == b'-')
+    {
+        return None;
+    }
     let s = str::from_utf8(s).ok()?;
     str::parse(s).ok()
 }
 
+/// Reads a hexadecimal float, correctly rounded to the nearest `f64` (ties to even). Values too
+/// large for an `f64` are read as infinity, values too small are read as subnormals or zero.
 pub fn read_hex_float(s: &[u8]) -> Option<f64> {
-    const MAX_SIGNIFICANT_DIGITS: u32 = 30;
-
     let (is_neg, s) = read_neg(s);
 
     if s.len() < 2 {
@@ -699,11 +983,15 @@ pub fn read_hex_float(s: &[u8]) -> Option<f64> {
         return None;
     }
 
-    let mut significant_digits: u32 = 0;
-    let mut non_significant_digits: u32 = 0;
+    // The value is `mantissa * 2^exp`, plus something less than `2^exp` when `sticky` is set.
+    // `sticky` tracks non-zero digits that don't fit into `mantissa`, which are only needed to
+    // break ties when rounding.
+    let mut mantissa: u64 = 0;
+    let mut sticky = false;
+    let mut exp: i64 = 0;
+
+    let mut n_digits = 0;
     let mut found_dot = false;
-    let mut base: f64 = 0.0;
-    let mut exp: i32 = 0;
     let mut i = 2;
 
     while i < s.len() {
@@ -714,18 +1002,18 @@ pub fn read_hex_float(s: &[u8]) -> Option<f64> {
             }
             found_dot = true;
         } else if let Some(d) = from_hex_digit(c) {
-            if significant_digits == 0 && d == 0 {
-                non_significant_digits += 1;
-            } else if significant_digits < MAX_SIGNIFICANT_DIGITS {
-                significant_digits += 1;
-                base = (base * 16.0) + d as f64;
+            n_digits += 1;
+            if mantissa >> 60 == 0 {
+                mantissa = (mantissa << 4) | d as u64;
+                if found_dot {
+                    exp -= 4;
+                }
             } else {
-                // ignore the digit, but count it towards the expontent
-                exp = exp.checked_add(4)?;
-            }
-            if found_dot {
-                // Correct exponent for the fractional part
-                exp = exp.checked_sub(4)?;
+                // Mantissa is full, the digit only affects rounding
+                sticky |= d != 0;
+                if !found_dot {
+                    exp += 4;
+                }
             }
         } else {
             break;
@@ -733,30 +1021,95 @@ pub fn read_hex_float(s: &[u8]) -> Option<f64> {
         i += 1;
     }
 
-    if non_significant_digits + significant_digits == 0 {
+    if n_digits == 0 {
         return None;
     }
 
-    if i + 1 < s.len() && (s[i] == b'p' || s[i] == b'P') {
+    if i < s.len() {
+        if s[i] != b'p' && s[i] != b'P' {
+            return None;
+        }
         let (exp_neg, exp_s) = read_neg(&s[i + 1..]);
-        let mut exp1: i32 = 0;
+        if exp_s.is_empty() {
+            return None;
+        }
+        // Anything outside of this range overflows to infinity or underflows to zero, but we can't
+        // saturate at the `f64` exponent range as the mantissa may have extra digits.
+        const MAX_EXP: i64 = 1 << 32;
+        let mut exp1: i64 = 0;
         for &c in exp_s {
             let d = from_digit(c)?;
-            exp1 = exp1.saturating_mul(10).saturating_add(d as i32);
+            exp1 = (exp1 * 10 + d as i64).min(MAX_EXP);
         }
         if exp_neg {
             exp1 = -exp1;
         }
-        exp = exp.saturating_add(exp1);
-    } else if i != s.len() {
-        return None;
+        exp += exp1;
     }
 
-    if is_neg {
-        base = -base;
+    let f = hex_float_to_f64(mantissa, exp, sticky);
+    Some(if is_neg { -f } else { f })
+}
+
+// Rounds `mantissa * 2^exp` (plus a value less than `2^exp` if `sticky` is set) to the nearest
+// `f64`, with ties to even.
+fn hex_float_to_f64(mut mantissa: u64, mut exp: i64, sticky: bool) -> f64 {
+    const MANTISSA_BITS: i64 = 53;
+    const MIN_EXP: i64 = -1022;
+    const MAX_EXP: i64 = 1023;
+
+    if mantissa == 0 {
+        return 0.0;
+    }
+
+    // Normalize so that the highest bit of `mantissa` is set. The value is then
+    // `1.xxx * 2^(exp + 63)`.
+    let leading_zeros = mantissa.leading_zeros();
+    mantissa <<= leading_zeros;
+    exp -= leading_zeros as i64;
+    let binary_exp = exp + 63;
+
+    if binary_exp > MAX_EXP {
+        return f64::INFINITY;
+    }
+
+    // Number of bits of `mantissa` we can keep: 53 for normals, less for subnormals
+    let keep = if binary_exp >= MIN_EXP {
+        MANTISSA_BITS
+    } else {
+        MANTISSA_BITS - (MIN_EXP - binary_exp)
+    };
+
+    if keep < 0 {
+        return 0.0;
     }
 
-    Some(base * (exp as f64).exp2())
+    // Split `mantissa` into the part we keep and the part we round off
+    let shift = 64 - keep as u32;
+    let (kept, rest) = if shift == 64 {
+        (0, mantissa)
+    } else {
+        (mantissa >> shift, mantissa & ((1 << shift) - 1))
+    };
+    let half = 1 << (shift - 1);
+
+    let round_up = rest > half || (rest == half && (sticky || kept & 1 == 1));
+    let kept = if round_up { kept + 1 } else { kept };
+
+    // `kept` has at most 54 bits (when rounding up carries), so it's exactly representable as
+    // `f64`, and multiplying it with a power of two is exact unless it overflows.
+    let scale = binary_exp - keep + 1;
+    kept as f64 * exp2(scale)
+}
+
+// `2^exp` for exponents in the range of normal and subnormal `f64`s
+fn exp2(exp: i64) -> f64 {
+    debug_assert!((-1074..=1023).contains(&exp));
+    if exp >= -1022 {
+        f64::from_bits(((exp + 1023) as u64) << 52)
+    } else {
+        f64::from_bits(1 << (exp + 1074))
+    }
 }
 
 fn read_neg(s: &[u8]) -> (bool, &[u8]) {
@@ -827,11 +1180,11 @@ fn get_reserved_word_token<S>(word: &[u8]) -> Option<Token<S>> {
     }
 }
 
-fn is_newline(c: u8) -> bool {
+pub(crate) fn is_newline(c: u8) -> bool {
     c == b'\n' || c == b'\r'
 }
 
-fn is_space(c: u8) -> bool {
+pub(crate) fn is_space(c: u8) -> bool {
     c == b' ' || c == b'\t' || c == VERTICAL_TAB || c == FORM_FEED || is_newline(c)
 }
 
@@ -0,0 +1,282 @@
+// Conversions between byte offsets and line/column positions, for editors and LSP clients.
+//
+// Lines are separated by "\n", "\r\n", or "\r", as in LSP. Note that Lua also treats "\n\r" as a
+// single newline, so line numbers differ from the lexers' in sources with "\n\r" newlines.
+
+/// 0-based line and column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LineCol {
+    pub line: u32,
+    pub col: u32,
+}
+
+/// Unit of a column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ColumnUnit {
+    /// Bytes of UTF-8
+    Utf8,
+    /// UTF-16 code units, as in LSP
+    Utf16,
+    /// Unicode scalar values
+    Char,
+}
+
+/// Line starts and positions of multi-byte characters and tabs of a source, for converting byte
+/// offsets to line/column positions and back in O(log n).
+#[derive(Debug, Clone)]
+pub struct LineIndex {
+    /// Start offsets of lines. The first line starts at 0.
+    line_starts: Vec<u32>,
+    /// End offsets of lines, excluding the newline
+    line_ends: Vec<u32>,
+    /// Start offsets of multi-byte characters
+    multibyte_starts: Vec<u32>,
+    /// `char_shifts[i]` is the number of bytes minus the number of characters in the first `i`
+    /// multi-byte characters
+    char_shifts: Vec<u32>,
+    /// `utf16_shifts[i]` is the number of bytes minus the number of UTF-16 code units in the first
+    /// `i` multi-byte characters
+    utf16_shifts: Vec<u32>,
+    /// Offsets of tabs
+    tabs: Vec<u32>,
+    /// `tab_display_cols[i]` is the display column after the tab `i`, with `tab_width`
+    tab_display_cols: Vec<u32>,
+    tab_width: u32,
+}
+
+impl LineIndex {
+    pub fn new(source: &str) -> Self {
+        LineIndex::from_bytes(source.as_bytes())
+    }
+
+    /// Same as `new`, for a source that may not be valid UTF-8. A byte that is not a part of a
+    /// valid UTF-8 character is one character and one UTF-16 code unit.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut index = LineIndex {
+            line_starts: vec![0],
+            line_ends: vec![],
+            multibyte_starts: vec![],
+            char_shifts: vec![0],
+            utf16_shifts: vec![0],
+            tabs: vec![],
+            tab_display_cols: vec![],
+            tab_width: 4,
+        };
+
+        let mut char_shift = 0;
+        let mut utf16_shift = 0;
+
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'\n' | b'\r' => {
+                    index.line_ends.push(i as u32);
+                    i += if bytes[i..].starts_with(b"\r\n") {
+                        2
+                    } else {
+                        1
+                    };
+                    index.line_starts.push(i as u32);
+                }
+                b'\t' => {
+                    index.tabs.push(i as u32);
+                    i += 1;
+                }
+                c if c < 0x80 => i += 1,
+                _ => {
+                    let len = char_len(&bytes[i..]);
+                    if len == 1 {
+                        i += 1;
+                        continue;
+                    }
+                    char_shift += len as u32 - 1;
+                    utf16_shift += if len == 4 { 2 } else { len as u32 - 1 };
+                    index.multibyte_starts.push(i as u32);
+                    index.char_shifts.push(char_shift);
+                    index.utf16_shifts.push(utf16_shift);
+                    i += len;
+                }
+            }
+        }
+        index.line_ends.push(bytes.len() as u32);
+        index.set_tab_display_cols();
+
+        index
+    }
+
+    /// Sets the tab width for display columns. The default is 4.
+    pub fn with_tab_width(mut self, tab_width: u32) -> Self {
+        self.tab_width = tab_width;
+        self.set_tab_display_cols();
+        self
+    }
+
+    /// Number of lines. A source ending with a newline has an empty last line.
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    /// Byte range of a line, excluding the newline.
+    pub fn line_range(&self, line: u32) -> Option<std::ops::Range<usize>> {
+        let line = line as usize;
+        if line < self.line_count() {
+            Some(self.line_starts[line] as usize..self.line_ends[line] as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Line of a byte offset. Offsets in a newline are on the line the newline ends.
+    pub fn line(&self, offset: usize) -> u32 {
+        (self
+            .line_starts
+            .partition_point(|&start| start as usize <= offset)
+            - 1) as u32
+    }
+
+    /// Line and column of a byte offset. Offsets in the middle of a character are rounded down to
+    /// the start of the character, offsets past the end of the source to the end.
+    pub fn line_col(&self, offset: usize, unit: ColumnUnit) -> LineCol {
+        let offset = self.char_boundary(offset.min(*self.line_ends.last().unwrap() as usize));
+        let line = self.line(offset);
+        LineCol {
+            line,
+            col: self.col(line, offset, unit),
+        }
+    }
+
+    /// Byte offset of a line and column. Columns past the end of the line are clamped to the end,
+    /// and UTF-16 columns in the middle of a character are rounded down to the start of the
+    /// character. Returns `None` if the line doesn't exist.
+    pub fn offset(&self, pos: LineCol, unit: ColumnUnit) -> Option<usize> {
+        let range = self.line_range(pos.line)?;
+        let line_start = range.start as u32;
+        let line_end = range.end as u32;
+
+        let offset = match unit {
+            ColumnUnit::Utf8 => self.char_boundary((line_start + pos.col).min(line_end) as usize),
+            ColumnUnit::Char | ColumnUnit::Utf16 => {
+                let shifts = self.shifts(unit);
+                let first = self.multibyte_idx(line_start);
+                let last = self.multibyte_idx(line_end);
+
+                // Column of the multi-byte character `idx` of the line
+                let multibyte_col = |idx: usize| {
+                    self.multibyte_starts[idx] - line_start - (shifts[idx] - shifts[first])
+                };
+
+                // Binary search for the first multi-byte character at or after the column
+                let (mut lo, mut hi) = (first, last);
+                while lo < hi {
+                    let mid = (lo + hi) / 2;
+                    if multibyte_col(mid) < pos.col {
+                        lo = mid + 1;
+                    } else {
+                        hi = mid;
+                    }
+                }
+
+                let shift = shifts[lo] - shifts[first];
+                let offset = (line_start + pos.col + shift).min(line_end) as usize;
+                self.char_boundary(offset)
+            }
+        };
+
+        Some(offset)
+    }
+
+    /// Column of a byte offset when tabs are expanded to the next multiple of the tab width, in
+    /// characters.
+    pub fn display_col(&self, offset: usize) -> u32 {
+        let offset = self.char_boundary(offset.min(*self.line_ends.last().unwrap() as usize));
+        let line = self.line(offset);
+        let col = self.col(line, offset, ColumnUnit::Char);
+        let line_start = self.line_starts[line as usize];
+
+        // Last tab of the line before the offset
+        let tab = self.tabs.partition_point(|&tab| (tab as usize) < offset);
+        match tab.checked_sub(1) {
+            Some(tab) if self.tabs[tab] >= line_start => {
+                let char_col = self.col(line, self.tabs[tab] as usize, ColumnUnit::Char) + 1;
+                self.tab_display_cols[tab] + (col - char_col)
+            }
+            _ => col,
+        }
+    }
+
+    /// Computes the display columns after the tabs, from the previous tab on the same line
+    fn set_tab_display_cols(&mut self) {
+        self.tab_display_cols.clear();
+        // Line of the previous tab, display column and character column after it
+        let mut prev = None;
+        for i in 0..self.tabs.len() {
+            let tab = self.tabs[i] as usize;
+            let line = self.line(tab);
+            let tab_col = self.col(line, tab, ColumnUnit::Char);
+            let (display_col, char_col) = match prev {
+                Some((prev_line, display_col, char_col)) if prev_line == line => {
+                    (display_col, char_col)
+                }
+                _ => (0, 0),
+            };
+            let display_col = display_col + (tab_col - char_col);
+            let display_col = (display_col / self.tab_width + 1) * self.tab_width;
+            self.tab_display_cols.push(display_col);
+            prev = Some((line, display_col, tab_col + 1));
+        }
+    }
+
+    /// Column of an offset on a line. The offset should be at a character boundary.
+    fn col(&self, line: u32, offset: usize, unit: ColumnUnit) -> u32 {
+        let line_start = self.line_starts[line as usize];
+        let col = offset as u32 - line_start;
+        match unit {
+            ColumnUnit::Utf8 => col,
+            ColumnUnit::Char | ColumnUnit::Utf16 => {
+                let shifts = self.shifts(unit);
+                let first = self.multibyte_idx(line_start);
+                let last = self.multibyte_idx(offset as u32);
+                col - (shifts[last] - shifts[first])
+            }
+        }
+    }
+
+    fn shifts(&self, unit: ColumnUnit) -> &[u32] {
+        match unit {
+            ColumnUnit::Char => &self.char_shifts,
+            _ => &self.utf16_shifts,
+        }
+    }
+
+    /// Number of multi-byte characters starting before the offset
+    fn multibyte_idx(&self, offset: u32) -> usize {
+        self.multibyte_starts
+            .partition_point(|&start| start < offset)
+    }
+
+    /// Rounds an offset in the middle of a multi-byte character down to the start of the character
+    fn char_boundary(&self, offset: usize) -> usize {
+        let idx = self.multibyte_idx(offset as u32);
+        if idx == 0 {
+            return offset;
+        }
+        let start = self.multibyte_starts[idx - 1] as usize;
+        let shift = self.char_shifts[idx] - self.char_shifts[idx - 1];
+        if offset <= start + shift as usize {
+            start
+        } else {
+            offset
+        }
+    }
+}
+
+/// Length of the UTF-8 character at the start of `s`, or 1 if `s` doesn't start with a valid UTF-8
+/// character.
+fn char_len(s: &[u8]) -> usize {
+    let s = &s[..s.len().min(4)];
+    let valid = match std::str::from_utf8(s) {
+        Ok(valid) => valid,
+        Err(err) => std::str::from_utf8(&s[..err.valid_up_to()]).unwrap(),
+    };
+    valid.chars().next().map_or(1, char::len_utf8)
+}
@@ -0,0 +1,246 @@
+// Line-by-line lexing for line-oriented syntax highlighters. Each line is lexed on its own, given
+// the state at the start of the line, and the state at the end of the line is returned for lexing
+// the next line.
+//
+// A line that starts inside a token is lexed with a prefix that opens the token, e.g. `[==[\n` for
+// a long string of level 2. The newline after a long bracket is skipped by the lexers, so the
+// prefix ends with one to keep a newline at the start of the line in the string. A token that
+// continues on the next line is an unfinished token at the end of the line, and the state at the
+// end of the line is found from the token's source text.
+
+use super::error::LexerError;
+use super::lexer_lexgen;
+use super::lexer_luster::{self, is_newline, is_space};
+use super::token::{Span, Token};
+
+/// State of the lexer at the start of a line.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LineState {
+    #[default]
+    Normal,
+    /// In a long string with the given number of `=`s in the brackets
+    LongString { level: usize },
+    /// In a long comment with the given number of `=`s in the brackets
+    LongComment { level: usize },
+    /// In a short string with the given quote, after an escaped newline
+    ShortString { quote: u8 },
+    /// In a short string with the given quote, skipping whitespace after a `\z`
+    EscapeZ { quote: u8 },
+}
+
+impl LineState {
+    /// Source text that opens the token of the state, for lexing `line`.
+    fn prefix(&self, line: &[u8]) -> Vec<u8> {
+        // The newline skipped after the opening bracket. "\r" when the line starts with "\r", as
+        // "\n\r" would be skipped as one newline.
+        let newline = if line.first() == Some(&b'\r') {
+            b'\r'
+        } else {
+            b'\n'
+        };
+        let long_bracket = |level| {
+            let mut prefix = vec![b'['];
+            prefix.resize(level + 1, b'=');
+            prefix.extend([b'[', newline]);
+            prefix
+        };
+
+        match *self {
+            LineState::Normal => vec![],
+            LineState::LongString { level } => long_bracket(level),
+            LineState::LongComment { level } => [b"--".to_vec(), long_bracket(level)].concat(),
+            LineState::ShortString { quote } => vec![quote],
+            LineState::EscapeZ { quote } => vec![quote, b'\\', b'z'],
+        }
+    }
+}
+
+/// Tokens of a line and the state at the end of the line.
+///
+/// Spans are relative to the start of the line. A token that starts on a previous line starts at
+/// 0, and strings that start on a previous line only contain the part on this line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LexedLine {
+    pub tokens: Vec<Token<Vec<u8>>>,
+    pub spans: Vec<Span>,
+    pub end_state: LineState,
+}
+
+/// Lexes a line with the luster lexer. The line should include its newline. Returns the error and
+/// its span if the line has an error.
+pub fn lex_line_luster(line: &[u8], state: LineState) -> Result<LexedLine, (LexerError, Span)> {
+    let prefix = state.prefix(line);
+    let source = [&prefix, line].concat();
+    let mut line = PrefixedLine::new(&source, prefix.len());
+
+    let mut lexer = lexer_luster::Lexer::new(source.as_slice(), |s| s.to_owned());
+    loop {
+        match lexer.read_token() {
+            Ok(Some(token)) => line.push(token, lexer.token_start(), lexer.byte_offset()),
+            Ok(None) => return Ok(line.finish(LineState::Normal)),
+            Err(err) => {
+                let span = Span::new(lexer.token_start(), lexer.byte_offset());
+                return line.error(err, span);
+            }
+        }
+    }
+}
+
+/// Lexes a line with the lexgen lexer. The line should include its newline. Returns the error and
+/// its span if the line has an error.
+pub fn lex_line_lexgen(line: &str, state: LineState) -> Result<LexedLine, (LexerError, Span)> {
+    // Prefixes are ASCII
+    let prefix = String::from_utf8(state.prefix(line.as_bytes())).unwrap();
+    let source = prefix.clone() + line;
+    let mut line = PrefixedLine::new(source.as_bytes(), prefix.len());
+
+    for token in lexer_lexgen::Lexer::new(&source) {
+        match token {
+            Ok((start, token, end)) => line.push(token, start.byte_idx, end.byte_idx),
+            Err(err) => {
+                let (err, span) = lexer_lexgen::luster_fallback_error_span(&source, &err);
+                return line.error(err, span);
+            }
+        }
+    }
+
+    Ok(line.finish(LineState::Normal))
+}
+
+/// Splits a source into lines, keeping the newline at the end of each line. Newlines are "\n",
+/// "\r", "\n\r", and "\r\n", as in the lexers.
+pub fn lines(source: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = source;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let end = match rest.iter().position(|&c| is_newline(c)) {
+            Some(i) => i + newline_len(&rest[i..]),
+            None => rest.len(),
+        };
+        let (line, next) = rest.split_at(end);
+        rest = next;
+        Some(line)
+    })
+}
+
+/// Tokens of a line lexed with the prefix of its start state.
+struct PrefixedLine<'a> {
+    source: &'a [u8],
+    prefix_len: usize,
+    tokens: Vec<Token<Vec<u8>>>,
+    spans: Vec<Span>,
+}
+
+impl<'a> PrefixedLine<'a> {
+    fn new(source: &'a [u8], prefix_len: usize) -> Self {
+        PrefixedLine {
+            source,
+            prefix_len,
+            tokens: vec![],
+            spans: vec![],
+        }
+    }
+
+    /// Span relative to the start of the line, from offsets in the prefixed source
+    fn span(&self, start: usize, end: usize) -> Span {
+        Span::new(
+            start.saturating_sub(self.prefix_len),
+            end.saturating_sub(self.prefix_len),
+        )
+    }
+
+    fn push(&mut self, token: Token<Vec<u8>>, start: usize, end: usize) {
+        self.tokens.push(token);
+        self.spans.push(self.span(start, end));
+    }
+
+    fn finish(self, end_state: LineState) -> LexedLine {
+        LexedLine {
+            tokens: self.tokens,
+            spans: self.spans,
+            end_state,
+        }
+    }
+
+    /// Handles an error at `span` of the prefixed source. Unfinished tokens that continue on the
+    /// next line are not errors.
+    fn error(self, err: LexerError, span: Span) -> Result<LexedLine, (LexerError, Span)> {
+        let unfinished = matches!(
+            err,
+            LexerError::UnfinishedShortString(_) | LexerError::UnfinishedLongString
+        );
+        let end_state = if unfinished {
+            continued_token_state(&self.source[span.start as usize..])
+        } else {
+            None
+        };
+
+        match end_state {
+            Some(end_state) => Ok(self.finish(end_state)),
+            None => Err((err, self.span(span.start as usize, span.end as usize))),
+        }
+    }
+}
+
+/// State at the end of a line for an unfinished token at the end of the line, given the source
+/// text of the token. Returns `None` if the token doesn't continue on the next line.
+fn continued_token_state(s: &[u8]) -> Option<LineState> {
+    let (s, comment) = match s.strip_prefix(b"--") {
+        Some(s) => (s, true),
+        None => (s, false),
+    };
+
+    match *s.first()? {
+        b'[' => {
+            let level = s[1..].iter().take_while(|&&c| c == b'=').count();
+            if comment {
+                Some(LineState::LongComment { level })
+            } else {
+                Some(LineState::LongString { level })
+            }
+        }
+
+        quote @ (b'"' | b'\'') if !comment => {
+            // Short strings continue on the next line only when the line ends with an escaped
+            // newline or whitespace skipped by `\z`
+            let mut i = 1;
+            while i < s.len() {
+                match s[i] {
+                    b'\\' if i + 1 < s.len() => match s[i + 1] {
+                        c if is_newline(c) => {
+                            i += 1 + newline_len(&s[i + 1..]);
+                            if i == s.len() {
+                                return Some(LineState::ShortString { quote });
+                            }
+                        }
+                        b'z' => {
+                            i += 2;
+                            while i < s.len() && is_space(s[i]) {
+                                i += 1;
+                            }
+                            if i == s.len() {
+                                return Some(LineState::EscapeZ { quote });
+                            }
+                        }
+                        _ => i += 2,
+                    },
+                    c if is_newline(c) => return None,
+                    _ => i += 1,
+                }
+            }
+            None
+        }
+
+        _ => None,
+    }
+}
+
+/// Length of the newline sequence at the start of `s`, which starts with a newline character
+fn newline_len(s: &[u8]) -> usize {
+    match s {
+        [b'\n', b'\r', ..] | [b'\r', b'\n', ..] => 2,
+        _ => 1,
+    }
+}
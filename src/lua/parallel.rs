@@ -0,0 +1,135 @@
+// Parallel lexing of a single source.
+//
+// The source is split into chunks at line starts, and each chunk is lexed on its own thread as if
+// it started outside of any token or comment. This is only a guess, as a chunk may start in a long
+// string or comment. The chunks are then stitched together in order: lexing continues from the
+// last valid token until it reaches a token start that the next chunk also has a token at. Lexing
+// a token only depends on the source from the start of the token, so from there the chunk's tokens
+// are the same as the tokens of sequential lexing. Usually that's the chunk's first token.
+
+use super::error::LexerError;
+use super::lexer_luster::{self, Lexer};
+use super::token::Span;
+use super::token_buffer::TokenBuffer;
+
+use std::mem;
+use std::thread;
+
+/// Tokens of a chunk, lexed as if the chunk started outside of any token or comment
+struct Chunk {
+    /// Tokens that start in the chunk. The last token may end after the chunk.
+    tokens: TokenBuffer,
+    /// Error after the last token
+    error: Option<LexerError>,
+}
+
+/// Lexes the whole source into a `TokenBuffer` using `n_threads` threads. Returns the same tokens
+/// or error as `lexer_luster::tokenize_all`.
+pub fn tokenize_all(source: &[u8], n_threads: usize) -> Result<TokenBuffer, LexerError> {
+    let boundaries = chunk_boundaries(source, n_threads);
+    if boundaries.len() <= 2 {
+        return lexer_luster::tokenize_all(source);
+    }
+
+    let mut chunks: Vec<Chunk> = thread::scope(|scope| {
+        let threads: Vec<_> = boundaries
+            .windows(2)
+            .map(|chunk| scope.spawn(move || lex_chunk(source, chunk[0], chunk[1])))
+            .collect();
+        threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect()
+    });
+
+    // The first chunk starts at the start of the source, so its tokens are valid
+    let mut tokens = mem::take(&mut chunks[0].tokens);
+    if let Some(err) = chunks[0].error.take() {
+        return Err(err);
+    }
+    let mut pos = tokens.spans.last().map_or(0, |span| span.end as usize);
+
+    let chunk_starts = &boundaries[..chunks.len()];
+
+    'stitch: loop {
+        let mut lexer = Lexer::new(&source[pos..], |s: &[u8]| s.to_owned());
+        loop {
+            lexer.skip_whitespace()?;
+            let start = pos + lexer.byte_offset();
+
+            // Continue with the tokens of the chunk if it has a token here
+            let chunk = &chunks[chunk_starts.partition_point(|&b| b <= start) - 1];
+            if let Ok(idx) = chunk
+                .tokens
+                .spans
+                .binary_search_by_key(&(start as u32), |span| span.start)
+            {
+                if idx == 0 {
+                    tokens.append(&chunk.tokens);
+                } else {
+                    for idx in idx..chunk.tokens.len() {
+                        tokens.push_token(chunk.tokens.token(idx), chunk.tokens.span(idx));
+                    }
+                }
+                if let Some(err) = &chunk.error {
+                    return Err(err.clone());
+                }
+                pos = tokens.spans.last().unwrap().end as usize;
+                continue 'stitch;
+            }
+
+            match lexer.read_token()? {
+                Some(token) => {
+                    tokens.push_token(token, Span::new(start, pos + lexer.byte_offset()))
+                }
+                None => return Ok(tokens),
+            }
+        }
+    }
+}
+
+/// Offsets where the chunks start, followed by the length of the source. Chunks start at line
+/// starts, as lines usually start outside of strings and comments.
+fn chunk_boundaries(source: &[u8], n_chunks: usize) -> Vec<usize> {
+    let mut boundaries = vec![0];
+    for i in 1..n_chunks {
+        let target = (source.len() * i / n_chunks).max(*boundaries.last().unwrap());
+        match source[target..].iter().position(|&c| c == b'\n') {
+            Some(newline) if target + newline + 1 < source.len() => {
+                boundaries.push(target + newline + 1)
+            }
+            _ => break,
+        }
+    }
+    boundaries.push(source.len());
+    boundaries
+}
+
+/// Lexes the tokens that start in `start..end` of the source, starting at `start` outside of any
+/// token or comment.
+fn lex_chunk(source: &[u8], start: usize, end: usize) -> Chunk {
+    let mut tokens = TokenBuffer::with_capacity_for_source(end - start);
+    let mut strings = mem::take(&mut tokens.strings);
+
+    let mut lexer = Lexer::new(&source[start..], |s: &[u8]| strings.alloc(s));
+    let error = loop {
+        if let Err(err) = lexer.skip_whitespace() {
+            break Some(err);
+        }
+        let token_start = start + lexer.byte_offset();
+        if token_start >= end {
+            break None;
+        }
+        match lexer.read_token() {
+            Ok(Some(token)) => {
+                tokens.push(token, Span::new(token_start, start + lexer.byte_offset()))
+            }
+            Ok(None) => break None,
+            Err(err) => break Some(err),
+        }
+    };
+    drop(lexer);
+
+    tokens.strings = strings;
+    Chunk { tokens, error }
+}
@@ -0,0 +1,468 @@
+// Parser of Lua 5.4, producing an `ast`. Generic over the token source, so it works with both
+// lexers.
+//
+// The syntax is recognized by `grammar`, which follows the grammar and the error messages of the
+// reference implementation (`lparser.c`). The AST is built bottom-up from the nodes that the
+// grammar reports. Labels, `goto`s, and `break`s are not resolved, and assignments to `const`
+// variables are not checked, so the errors that the reference implementation finds when
+// generating code are not reported.
+
+use super::ast::*;
+use super::buffered::{SpannedToken, TokenSource};
+use super::error::LexerError;
+use super::grammar::{Grammar, Sink};
+use super::syntax::{ParseErrorKind, SyntaxError, SyntaxKind};
+use super::token::{Span, Token, TokenKind};
+
+use std::fmt;
+use std::vec;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    /// The token where the error was found, or an empty span after the last token at the end of
+    /// the source. For lexer errors, the erroneous part of the source.
+    pub span: Span,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+impl From<(LexerError, Span)> for ParseError {
+    fn from((err, span): (LexerError, Span)) -> Self {
+        ParseError {
+            kind: ParseErrorKind::Lexer(err),
+            span,
+        }
+    }
+}
+
+impl From<SyntaxError> for ParseError {
+    fn from(err: SyntaxError) -> Self {
+        ParseError {
+            kind: err.kind,
+            span: err.span,
+        }
+    }
+}
+
+/// Parses the tokens of a whole source as a chunk.
+pub fn parse_chunk<L: TokenSource>(lexer: L) -> Result<Block, ParseError> {
+    Parser::new(lexer).parse_chunk()
+}
+
+pub struct Parser<L> {
+    grammar: Grammar<L, AstBuilder>,
+}
+
+impl<L: TokenSource> Parser<L> {
+    pub fn new(lexer: L) -> Self {
+        Parser {
+            grammar: Grammar::new(lexer, AstBuilder::default()),
+        }
+    }
+
+    /// Parses the rest of the tokens as a chunk.
+    pub fn parse_chunk(&mut self) -> Result<Block, ParseError> {
+        self.grammar.chunk()?;
+        match self.grammar.sink_mut().pop() {
+            Element::Block(block) => Ok(block),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Parses an expression. The tokens after the expression are not consumed.
+    pub fn parse_expr(&mut self) -> Result<Expr, ParseError> {
+        self.grammar.expr()?;
+        match self.grammar.sink_mut().pop() {
+            Element::Expr(expr) => Ok(expr),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Builds the AST bottom-up: each finished node is converted to an element of the AST, from the
+/// elements of its children.
+#[derive(Debug, Default)]
+struct AstBuilder {
+    /// Kinds of the nodes started and not finished yet, with the index of their first child in
+    /// `children`
+    parents: Vec<(SyntaxKind, usize)>,
+    /// Children of the nodes in `parents`, and the elements of the finished top-level nodes
+    children: Vec<Child>,
+}
+
+#[derive(Debug)]
+struct Child {
+    element: Element,
+    /// From the first to the last token of the child, `None` for nodes without tokens, e.g. empty
+    /// blocks
+    span: Option<Span>,
+}
+
+/// A token or a part of the AST, the element of a finished node
+#[derive(Debug)]
+enum Element {
+    Token(SpannedToken),
+    Block(Block),
+    Return(Return),
+    Stat(Stat),
+    Expr(Expr),
+    /// An expression list, or the arguments of a call
+    Exprs(Vec<Expr>),
+    Names(Vec<Name>),
+    FuncName(FuncName),
+    FuncBody(FuncBody),
+    /// Parameter names, and whether the parameters end with `...`
+    Params(Vec<Name>, bool),
+    LocalVar(LocalVar),
+    Attrib(Attrib),
+    Field(TableField),
+    /// Condition and block of an `elseif`
+    ElseIf(Expr, Block),
+}
+
+impl AstBuilder {
+    /// Removes the element of the last finished top-level node.
+    fn pop(&mut self) -> Element {
+        debug_assert!(self.parents.is_empty());
+        self.children.pop().unwrap().element
+    }
+}
+
+impl Sink for AstBuilder {
+    type Checkpoint = usize;
+
+    fn start_node(&mut self, kind: SyntaxKind) {
+        self.parents.push((kind, self.children.len()));
+    }
+
+    fn token(&mut self, token: SpannedToken) {
+        self.children.push(Child {
+            span: Some(token.span),
+            element: Element::Token(token),
+        });
+    }
+
+    fn finish_node(&mut self) {
+        let (kind, first) = self.parents.pop().unwrap();
+        let mut span: Option<Span> = None;
+        let mut tokens = vec![];
+        let mut nodes = vec![];
+        for child in self.children.drain(first..) {
+            if let Some(child_span) = child.span {
+                span = Some(Span {
+                    start: span.map_or(child_span.start, |span| span.start),
+                    end: child_span.end,
+                });
+            }
+            match child.element {
+                Element::Token(token) => tokens.push(token),
+                element => nodes.push(element),
+            }
+        }
+        let element = build(
+            kind,
+            span.unwrap_or_default(),
+            tokens,
+            Nodes(nodes.into_iter()),
+        );
+        self.children.push(Child { element, span });
+    }
+
+    fn checkpoint(&mut self) -> usize {
+        self.children.len()
+    }
+
+    fn start_node_at(&mut self, checkpoint: usize, kind: SyntaxKind) {
+        self.parents.push((kind, checkpoint));
+    }
+}
+
+/// Elements of the child nodes of a node, in order
+struct Nodes(vec::IntoIter<Element>);
+
+impl Nodes {
+    fn expr(&mut self) -> Expr {
+        match self.0.next() {
+            Some(Element::Expr(expr)) => expr,
+            _ => unreachable!(),
+        }
+    }
+
+    fn exprs(&mut self) -> Vec<Expr> {
+        match self.0.next() {
+            Some(Element::Exprs(exprs)) => exprs,
+            _ => unreachable!(),
+        }
+    }
+
+    fn block(&mut self) -> Block {
+        match self.0.next() {
+            Some(Element::Block(block)) => block,
+            _ => unreachable!(),
+        }
+    }
+
+    fn func_body(&mut self) -> FuncBody {
+        match self.0.next() {
+            Some(Element::FuncBody(body)) => body,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Converts a finished node to an element, from its tokens and the elements of its child nodes.
+fn build(kind: SyntaxKind, span: Span, tokens: Vec<SpannedToken>, mut nodes: Nodes) -> Element {
+    let mut tokens = tokens.into_iter();
+    let stat = |kind| Element::Stat(Stat { kind, span });
+    let expr = |kind| Element::Expr(Expr { kind, span });
+    match kind {
+        SyntaxKind::Block => {
+            let mut block = Block::default();
+            for element in nodes.0 {
+                match element {
+                    Element::Stat(stat) => block.stats.push(stat),
+                    Element::Return(ret) => block.ret = Some(ret),
+                    _ => unreachable!(),
+                }
+            }
+            Element::Block(block)
+        }
+        SyntaxKind::ReturnStat => Element::Return(Return {
+            values: match nodes.0.next() {
+                Some(Element::Exprs(values)) => values,
+                _ => vec![],
+            },
+            span,
+        }),
+
+        SyntaxKind::AssignStat => stat(StatKind::Assign {
+            targets: nodes.exprs(),
+            values: nodes.exprs(),
+        }),
+        SyntaxKind::CallStat => stat(StatKind::Call(nodes.expr())),
+        SyntaxKind::LabelStat => stat(StatKind::Label(name(tokens.nth(1)))),
+        SyntaxKind::BreakStat => stat(StatKind::Break),
+        SyntaxKind::GotoStat => stat(StatKind::Goto(name(tokens.nth(1)))),
+        SyntaxKind::DoStat => stat(StatKind::Do(nodes.block())),
+        SyntaxKind::WhileStat => stat(StatKind::While {
+            cond: nodes.expr(),
+            body: nodes.block(),
+        }),
+        SyntaxKind::RepeatStat => stat(StatKind::Repeat {
+            body: nodes.block(),
+            cond: nodes.expr(),
+        }),
+        SyntaxKind::IfStat => {
+            let mut branches = vec![(nodes.expr(), nodes.block())];
+            let mut else_block = None;
+            for element in nodes.0 {
+                match element {
+                    Element::ElseIf(cond, block) => branches.push((cond, block)),
+                    Element::Block(block) => else_block = Some(block),
+                    _ => unreachable!(),
+                }
+            }
+            stat(StatKind::If {
+                branches,
+                else_block,
+            })
+        }
+        SyntaxKind::ElseIfClause => Element::ElseIf(nodes.expr(), nodes.block()),
+        SyntaxKind::ElseClause => Element::Block(nodes.block()),
+        SyntaxKind::NumericForStat => {
+            let var = name(tokens.nth(1));
+            let start = nodes.expr();
+            let limit = nodes.expr();
+            let step = match nodes.0.len() {
+                2 => Some(nodes.expr()),
+                _ => None,
+            };
+            stat(StatKind::NumericFor {
+                var,
+                start,
+                limit,
+                step,
+                body: nodes.block(),
+            })
+        }
+        SyntaxKind::GenericForStat => stat(StatKind::GenericFor {
+            names: match nodes.0.next() {
+                Some(Element::Names(names)) => names,
+                _ => unreachable!(),
+            },
+            exprs: nodes.exprs(),
+            body: nodes.block(),
+        }),
+        SyntaxKind::FunctionStat => stat(StatKind::Function {
+            name: match nodes.0.next() {
+                Some(Element::FuncName(name)) => name,
+                _ => unreachable!(),
+            },
+            body: nodes.func_body(),
+        }),
+        SyntaxKind::LocalFunctionStat => stat(StatKind::LocalFunction {
+            name: name(tokens.nth(2)),
+            body: nodes.func_body(),
+        }),
+        SyntaxKind::LocalStat => {
+            let mut vars = vec![];
+            let mut values = vec![];
+            for element in nodes.0 {
+                match element {
+                    Element::LocalVar(var) => vars.push(var),
+                    Element::Exprs(exprs) => values = exprs,
+                    _ => unreachable!(),
+                }
+            }
+            stat(StatKind::Local { vars, values })
+        }
+
+        SyntaxKind::FuncName => {
+            let mut path = vec![];
+            let mut method = None;
+            let mut is_method = false;
+            for token in tokens {
+                match token.token.kind() {
+                    TokenKind::Name if is_method => method = Some(name(Some(token))),
+                    TokenKind::Name => path.push(name(Some(token))),
+                    TokenKind::Colon => is_method = true,
+                    _ => {}
+                }
+            }
+            Element::FuncName(FuncName { path, method })
+        }
+        SyntaxKind::FuncBody => {
+            let (params, is_vararg) = match nodes.0.next() {
+                Some(Element::Params(params, is_vararg)) => (params, is_vararg),
+                _ => unreachable!(),
+            };
+            Element::FuncBody(FuncBody {
+                params,
+                is_vararg,
+                body: nodes.block(),
+                span,
+            })
+        }
+        SyntaxKind::ParamList => {
+            let mut params = vec![];
+            let mut is_vararg = false;
+            for token in tokens {
+                match token.token.kind() {
+                    TokenKind::Name => params.push(name(Some(token))),
+                    TokenKind::Dots => is_vararg = true,
+                    _ => {}
+                }
+            }
+            Element::Params(params, is_vararg)
+        }
+        SyntaxKind::LocalVar => Element::LocalVar(LocalVar {
+            name: name(tokens.next()),
+            attrib: match nodes.0.next() {
+                Some(Element::Attrib(attrib)) => Some(attrib),
+                _ => None,
+            },
+        }),
+        SyntaxKind::Attrib => match &name(tokens.nth(1)).name[..] {
+            b"const" => Element::Attrib(Attrib::Const),
+            b"close" => Element::Attrib(Attrib::Close),
+            _ => unreachable!(),
+        },
+        SyntaxKind::NameList => Element::Names(
+            tokens
+                .filter(|token| token.token.kind() == TokenKind::Name)
+                .map(|token| name(Some(token)))
+                .collect(),
+        ),
+        SyntaxKind::VarList | SyntaxKind::ExprList => Element::Exprs(
+            nodes
+                .0
+                .map(|element| match element {
+                    Element::Expr(expr) => expr,
+                    _ => unreachable!(),
+                })
+                .collect(),
+        ),
+
+        SyntaxKind::LiteralExpr => expr(match tokens.next().unwrap().token {
+            Token::Nil => ExprKind::Nil,
+            Token::True => ExprKind::True,
+            Token::False => ExprKind::False,
+            Token::Integer(i) => ExprKind::Integer(i),
+            Token::Float(f) => ExprKind::Float(f),
+            Token::String(s) => ExprKind::String(s),
+            Token::Dots => ExprKind::VarArgs,
+            _ => unreachable!(),
+        }),
+        SyntaxKind::NameExpr => expr(ExprKind::Name(name(tokens.next()).name)),
+        SyntaxKind::FunctionExpr => expr(ExprKind::Function(Box::new(nodes.func_body()))),
+        SyntaxKind::TableExpr => expr(ExprKind::Table(
+            nodes
+                .0
+                .map(|element| match element {
+                    Element::Field(field) => field,
+                    _ => unreachable!(),
+                })
+                .collect(),
+        )),
+        SyntaxKind::IndexField => Element::Field(TableField::Index {
+            key: nodes.expr(),
+            value: nodes.expr(),
+        }),
+        SyntaxKind::NamedField => Element::Field(TableField::Named {
+            name: name(tokens.next()),
+            value: nodes.expr(),
+        }),
+        SyntaxKind::PositionalField => Element::Field(TableField::Positional(nodes.expr())),
+        SyntaxKind::FieldExpr => expr(ExprKind::Field {
+            object: Box::new(nodes.expr()),
+            name: name(tokens.nth(1)),
+        }),
+        SyntaxKind::IndexExpr => expr(ExprKind::Index {
+            object: Box::new(nodes.expr()),
+            key: Box::new(nodes.expr()),
+        }),
+        SyntaxKind::CallExpr => expr(ExprKind::Call {
+            function: Box::new(nodes.expr()),
+            args: nodes.exprs(),
+        }),
+        SyntaxKind::MethodCallExpr => expr(ExprKind::MethodCall {
+            object: Box::new(nodes.expr()),
+            method: name(tokens.nth(1)),
+            args: nodes.exprs(),
+        }),
+        SyntaxKind::CallArgs => Element::Exprs(match nodes.0.next() {
+            None => vec![],
+            Some(Element::Expr(arg)) => vec![arg],
+            Some(Element::Exprs(args)) => args,
+            _ => unreachable!(),
+        }),
+        SyntaxKind::ParenExpr => expr(ExprKind::Paren(Box::new(nodes.expr()))),
+        SyntaxKind::UnaryExpr => expr(ExprKind::Unary {
+            op: UnOp::from_token_kind(tokens.next().unwrap().token.kind()).unwrap(),
+            operand: Box::new(nodes.expr()),
+        }),
+        SyntaxKind::BinaryExpr => expr(ExprKind::Binary {
+            lhs: Box::new(nodes.expr()),
+            op: BinOp::from_token_kind(tokens.next().unwrap().token.kind()).unwrap(),
+            rhs: Box::new(nodes.expr()),
+        }),
+
+        _ => unreachable!(),
+    }
+}
+
+/// Name of a name token
+fn name(token: Option<SpannedToken>) -> Name {
+    match token {
+        Some(SpannedToken {
+            token: Token::Name(name),
+            span,
+        }) => Name { name, span },
+        _ => unreachable!(),
+    }
+}
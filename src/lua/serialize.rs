@@ -0,0 +1,251 @@
+// Token dumps, for caching lexer output and passing it between tools. Two formats are supported:
+//
+// - JSON lines: one JSON object per token, with the token's span and the token. Tokens without a
+//   payload are the `Token` variant name as a string, tokens with a payload are objects with the
+//   variant name as the key and the payload as the value. Names and strings are JSON strings when
+//   they're valid UTF-8, arrays of bytes otherwise. Finite floats are JSON numbers, infinities and
+//   NaNs are the strings "inf", "-inf" and "nan", or "nan:<bits in hex>" for NaNs other than
+//   `f64::NAN`, as JSON doesn't have them. Example, for `local x = '\xff' .. 10`:
+//
+//   {"span":{"start":0,"end":5},"token":"Local"}
+//   {"span":{"start":6,"end":7},"token":{"Name":"x"}}
+//   {"span":{"start":8,"end":9},"token":"Assign"}
+//   {"span":{"start":10,"end":16},"token":{"String":[255]}}
+//   {"span":{"start":17,"end":19},"token":"Concat"}
+//   {"span":{"start":20,"end":22},"token":{"Integer":10}}
+//
+// - Binary: number of tokens, followed by tokens, encoded with bincode's default options. Numbers
+//   in spans and the count are varints, a token is its variant index as a varint followed by the
+//   payload: integers as zigzag varints, floats as 8 little-endian bytes, names and strings as a
+//   varint length followed by the bytes.
+
+use super::token::{Span, Token};
+use super::token_buffer::TokenBuffer;
+
+use bincode::Options;
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+use std::str;
+
+/// Payload of a name or string token. Serialized as a string in human-readable formats when it's
+/// valid UTF-8, as bytes otherwise.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ByteString(pub Vec<u8>);
+
+struct ByteStr<'a>(&'a [u8]);
+
+impl<'a> Serialize for ByteStr<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match str::from_utf8(self.0) {
+            Ok(s) if serializer.is_human_readable() => serializer.serialize_str(s),
+            _ => serializer.serialize_bytes(self.0),
+        }
+    }
+}
+
+impl Serialize for ByteString {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ByteStr(&self.0).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ByteStringVisitor;
+
+        impl<'de> Visitor<'de> for ByteStringVisitor {
+            type Value = ByteString;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a string or an array of bytes")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<ByteString, E> {
+                Ok(ByteString(v.as_bytes().to_owned()))
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<ByteString, E> {
+                Ok(ByteString(v.to_owned()))
+            }
+
+            fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<ByteString, E> {
+                Ok(ByteString(v))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<ByteString, A::Error> {
+                let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(byte) = seq.next_element()? {
+                    bytes.push(byte);
+                }
+                Ok(ByteString(bytes))
+            }
+        }
+
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(ByteStringVisitor)
+        } else {
+            deserializer.deserialize_byte_buf(ByteStringVisitor)
+        }
+    }
+}
+
+/// Serialization of float payloads of tokens, used with `#[serde(with)]`. Floats are written as
+/// numbers, except non-finite floats in human-readable formats, which are written as strings.
+pub(crate) mod float {
+    use serde::de::{self, Deserializer, Visitor};
+    use serde::Serializer;
+
+    use std::fmt;
+
+    pub fn serialize<S: Serializer>(f: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        if f.is_finite() || !serializer.is_human_readable() {
+            serializer.serialize_f64(*f)
+        } else if f.is_nan() {
+            if f.to_bits() == f64::NAN.to_bits() {
+                serializer.serialize_str("nan")
+            } else {
+                serializer.serialize_str(&format!("nan:{:x}", f.to_bits()))
+            }
+        } else if *f > 0.0 {
+            serializer.serialize_str("inf")
+        } else {
+            serializer.serialize_str("-inf")
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        struct FloatVisitor;
+
+        impl<'de> Visitor<'de> for FloatVisitor {
+            type Value = f64;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a number, \"inf\", \"-inf\" or a NaN")
+            }
+
+            fn visit_f64<E: de::Error>(self, v: f64) -> Result<f64, E> {
+                Ok(v)
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<f64, E> {
+                Ok(v as f64)
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<f64, E> {
+                Ok(v as f64)
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<f64, E> {
+                let bits = match v {
+                    "inf" => return Ok(f64::INFINITY),
+                    "-inf" => return Ok(f64::NEG_INFINITY),
+                    "nan" => return Ok(f64::NAN),
+                    _ => v
+                        .strip_prefix("nan:")
+                        .and_then(|bits| u64::from_str_radix(bits, 16).ok()),
+                };
+                match bits.map(f64::from_bits) {
+                    Some(f) if f.is_nan() => Ok(f),
+                    _ => Err(E::invalid_value(de::Unexpected::Str(v), &self)),
+                }
+            }
+        }
+
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(FloatVisitor)
+        } else {
+            deserializer.deserialize_f64(FloatVisitor)
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TokenRecordRef<'a> {
+    span: Span,
+    token: Token<ByteStr<'a>>,
+}
+
+#[derive(Deserialize)]
+struct TokenRecord {
+    span: Span,
+    token: Token<ByteString>,
+}
+
+pub fn write_json_lines<W: Write>(tokens: &TokenBuffer, mut w: W) -> io::Result<()> {
+    for (token, span) in tokens.iter() {
+        let record = TokenRecordRef {
+            span,
+            token: token.map_string(ByteStr),
+        };
+        serde_json::to_writer(&mut w, &record)?;
+        w.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+pub fn read_json_lines<R: BufRead>(r: R) -> io::Result<TokenBuffer> {
+    let mut tokens = TokenBuffer::new();
+    for line in r.lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let record: TokenRecord = serde_json::from_str(&line)?;
+        tokens.push_token(record.token.map_string(|s| s.0), record.span);
+    }
+    Ok(tokens)
+}
+
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new().allow_trailing_bytes()
+}
+
+fn bincode_error(err: bincode::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+pub fn write_binary<W: Write>(tokens: &TokenBuffer, mut w: W) -> io::Result<()> {
+    bincode_options()
+        .serialize_into(&mut w, &(tokens.len() as u64))
+        .map_err(bincode_error)?;
+
+    for (token, span) in tokens.iter() {
+        let record = TokenRecordRef {
+            span,
+            token: token.map_string(ByteStr),
+        };
+        bincode_options()
+            .serialize_into(&mut w, &record)
+            .map_err(bincode_error)?;
+    }
+
+    Ok(())
+}
+
+pub fn read_binary<R: Read>(mut r: R) -> io::Result<TokenBuffer> {
+    let mut data = vec![];
+    r.read_to_end(&mut data)?;
+    // Lengths of payloads are checked against the size of the dump before allocating, so a
+    // corrupt length is an error instead of a huge allocation
+    let limit = data.len() as u64;
+    let mut data = data.as_slice();
+
+    let n_tokens: u64 = bincode_options()
+        .with_limit(limit)
+        .deserialize_from(&mut data)
+        .map_err(bincode_error)?;
+
+    let mut tokens = TokenBuffer::new();
+    for _ in 0..n_tokens {
+        let record: TokenRecord = bincode_options()
+            .with_limit(limit)
+            .deserialize_from(&mut data)
+            .map_err(bincode_error)?;
+        tokens.push_token(record.token.map_string(|s| s.0), record.span);
+    }
+
+    Ok(tokens)
+}
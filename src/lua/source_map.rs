@@ -0,0 +1,231 @@
+// Sources of multiple files, with spans that include the file they belong to.
+//
+// Sources are bytes, as Lua sources don't need to be valid UTF-8. The lexgen lexer lexes UTF-8, so
+// a source that is not valid UTF-8 is lexed with it until the first invalid byte, which is
+// reported as an unexpected character.
+
+use super::error::LexerError;
+use super::lexer_lexgen;
+use super::lexer_luster;
+use super::line_index::{ColumnUnit, LineCol, LineIndex};
+use super::lua_files_in_dir;
+use super::token::{Span, Token};
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str;
+
+/// Index of a file in a `SourceMap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileId(pub u32);
+
+/// Byte range of a token in a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileSpan {
+    pub file: FileId,
+    pub span: Span,
+}
+
+/// Lexer to lex files with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LexerChoice {
+    Luster,
+    Lexgen,
+}
+
+/// Path, line, and column of an offset in a file. Lines and columns are 0-based, columns are in
+/// characters. Displayed as `path:line:col` with 1-based lines and columns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location<'a> {
+    pub path: &'a Path,
+    pub line_col: LineCol,
+}
+
+impl fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}",
+            self.path.display(),
+            self.line_col.line + 1,
+            self.line_col.col + 1
+        )
+    }
+}
+
+/// Tokens of a file until the first error, and the error.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LexedFile {
+    pub file: FileId,
+    pub tokens: Vec<(Token<Vec<u8>>, FileSpan)>,
+    pub error: Option<(LexerError, FileSpan)>,
+}
+
+#[derive(Debug)]
+struct SourceFile {
+    path: PathBuf,
+    source: Vec<u8>,
+    line_index: LineIndex,
+}
+
+/// Registered files and their sources.
+#[derive(Debug, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Registers a file with the given source.
+    pub fn add_file<P: Into<PathBuf>, S: Into<Vec<u8>>>(&mut self, path: P, source: S) -> FileId {
+        let id = FileId(self.files.len() as u32);
+        let source = source.into();
+        let line_index = LineIndex::from_bytes(&source);
+        self.files.push(SourceFile {
+            path: path.into(),
+            source,
+            line_index,
+        });
+        id
+    }
+
+    /// Reads and registers a file.
+    pub fn load_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<FileId> {
+        let path = path.as_ref();
+        let source = fs::read(path)?;
+        Ok(self.add_file(path, source))
+    }
+
+    /// Reads and registers the `.lua` files in a directory, non-recursively, in the order of
+    /// their paths.
+    pub fn load_dir<P: AsRef<Path>>(&mut self, dir: P) -> io::Result<Vec<FileId>> {
+        let mut paths: Vec<PathBuf> = lua_files_in_dir(dir)?.collect();
+        paths.sort();
+        paths.into_iter().map(|path| self.load_file(path)).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn path(&self, file: FileId) -> &Path {
+        &self.file(file).path
+    }
+
+    pub fn source(&self, file: FileId) -> &[u8] {
+        &self.file(file).source
+    }
+
+    pub fn line_index(&self, file: FileId) -> &LineIndex {
+        &self.file(file).line_index
+    }
+
+    /// Location of the start of a span.
+    pub fn location(&self, span: FileSpan) -> Location<'_> {
+        let file = self.file(span.file);
+        Location {
+            path: &file.path,
+            line_col: file
+                .line_index
+                .line_col(span.span.start as usize, ColumnUnit::Char),
+        }
+    }
+
+    /// Source text of a span.
+    pub fn snippet(&self, span: FileSpan) -> &[u8] {
+        &self.source(span.file)[span.span.start as usize..span.span.end as usize]
+    }
+
+    /// Lexes a file with the given lexer.
+    pub fn lex_file(&self, file: FileId, lexer: LexerChoice) -> LexedFile {
+        let source = self.source(file);
+        let file_span = |span| FileSpan { file, span };
+
+        let mut tokens = vec![];
+        let mut error = None;
+
+        match lexer {
+            LexerChoice::Luster => {
+                let mut lexer = lexer_luster::Lexer::new(source, |s| s.to_owned());
+                loop {
+                    match lexer.read_token() {
+                        Ok(Some(token)) => {
+                            let span = Span::new(lexer.token_start(), lexer.byte_offset());
+                            tokens.push((token, file_span(span)));
+                        }
+                        Ok(None) => break,
+                        Err(err) => {
+                            let span = Span::new(lexer.token_start(), lexer.byte_offset());
+                            error = Some((err, file_span(span)));
+                            break;
+                        }
+                    }
+                }
+            }
+
+            LexerChoice::Lexgen => {
+                let (valid, invalid) = match str::from_utf8(source) {
+                    Ok(valid) => (valid, None),
+                    Err(err) => {
+                        let valid_len = err.valid_up_to();
+                        let valid = str::from_utf8(&source[..valid_len]).unwrap();
+                        (valid, Some(valid_len))
+                    }
+                };
+
+                for token in lexer_lexgen::Lexer::new(valid) {
+                    match token {
+                        Ok((start, token, end)) => {
+                            let span = Span::new(start.byte_idx, end.byte_idx);
+                            tokens.push((token, file_span(span)));
+                        }
+                        Err(err) => {
+                            let (err, span) = lexer_lexgen::luster_fallback_error_span(valid, &err);
+                            error = Some((err, file_span(span)));
+                            break;
+                        }
+                    }
+                }
+
+                if let (None, Some(offset)) = (&error, invalid) {
+                    let err = LexerError::UnexpectedCharacter(source[offset]);
+                    // Same as the luster lexer, the span of an unexpected character is empty
+                    error = Some((err, file_span(Span::new(offset, offset))));
+                }
+            }
+        }
+
+        LexedFile {
+            file,
+            tokens,
+            error,
+        }
+    }
+
+    /// Reads, registers, and lexes the `.lua` files in a directory, non-recursively, in the order
+    /// of their paths.
+    pub fn lex_dir<P: AsRef<Path>>(
+        &mut self,
+        dir: P,
+        lexer: LexerChoice,
+    ) -> io::Result<Vec<LexedFile>> {
+        let files = self.load_dir(dir)?;
+        Ok(files
+            .into_iter()
+            .map(|file| self.lex_file(file, lexer))
+            .collect())
+    }
+
+    fn file(&self, file: FileId) -> &SourceFile {
+        &self.files[file.0 as usize]
+    }
+}
@@ -0,0 +1,124 @@
+// Async lexing of a source read from an `AsyncRead`, as a `Stream` of tokens. Doesn't depend on a
+// specific runtime.
+//
+// Chunks read from the source are lexed with `ChunkedLexer`, so tokens are yielded as soon as
+// they are complete and reading never blocks a thread.
+
+use super::chunked::ChunkedLexer;
+use super::error::LexerError;
+use super::token::Token;
+
+use futures_core::Stream;
+use futures_io::AsyncRead;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Size of the buffer that the source is read into
+const READ_BUFFER_SIZE: usize = 8 * 1024;
+
+#[derive(Debug)]
+pub enum StreamError {
+    Lexer(LexerError),
+    /// Error when reading the source
+    Io(io::Error),
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StreamError::Lexer(err) => err.fmt(f),
+            StreamError::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl From<LexerError> for StreamError {
+    fn from(err: LexerError) -> Self {
+        StreamError::Lexer(err)
+    }
+}
+
+/// Stream of the tokens of a source read from an `AsyncRead`. Ends after the first error.
+pub struct TokenStream<R> {
+    /// `None` after the end of the source or an error
+    source: Option<R>,
+    lexer: ChunkedLexer,
+    read_buffer: Vec<u8>,
+    /// Complete tokens not yielded yet
+    tokens: VecDeque<Token<Vec<u8>>>,
+    /// Error to yield after the tokens before it
+    error: Option<StreamError>,
+}
+
+impl<R: AsyncRead + Unpin> TokenStream<R> {
+    pub fn new(source: R) -> Self {
+        TokenStream {
+            source: Some(source),
+            lexer: ChunkedLexer::new(),
+            read_buffer: vec![0; READ_BUFFER_SIZE],
+            tokens: VecDeque::new(),
+            error: None,
+        }
+    }
+
+    /// Reads a chunk of the source and lexes it. Returns `Poll::Ready` when there may be new
+    /// tokens or an error.
+    fn poll_read_chunk(&mut self, cx: &mut Context) -> Poll<()> {
+        let source = match &mut self.source {
+            Some(source) => source,
+            None => return Poll::Ready(()),
+        };
+
+        let result = match Pin::new(source).poll_read(cx, &mut self.read_buffer) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Err(err)) if err.kind() == io::ErrorKind::Interrupted => {
+                return Poll::Ready(());
+            }
+            Poll::Ready(Err(err)) => {
+                self.source = None;
+                self.error = Some(StreamError::Io(err));
+                return Poll::Ready(());
+            }
+            Poll::Ready(Ok(0)) => {
+                self.source = None;
+                self.lexer.finish()
+            }
+            Poll::Ready(Ok(n)) => self.lexer.feed(&self.read_buffer[..n]),
+        };
+
+        self.tokens
+            .extend(self.lexer.take_tokens().into_iter().map(|(token, _)| token));
+        if let Err((err, _)) = result {
+            self.source = None;
+            self.error = Some(err.into());
+        }
+
+        Poll::Ready(())
+    }
+}
+
+impl<R: AsyncRead + Unpin> Stream for TokenStream<R> {
+    type Item = Result<Token<Vec<u8>>, StreamError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(token) = this.tokens.pop_front() {
+                return Poll::Ready(Some(Ok(token)));
+            }
+            if let Some(err) = this.error.take() {
+                return Poll::Ready(Some(Err(err)));
+            }
+            if this.source.is_none() {
+                return Poll::Ready(None);
+            }
+            if this.poll_read_chunk(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+}
@@ -0,0 +1,232 @@
+// Kinds of syntax tree nodes and syntax errors, shared by `grammar` and the modules that build
+// on it: `parser`, `syntax_check`, and `cst`.
+
+use super::error::LexerError;
+use super::token::{Span, TokenKind, TokenSet};
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SyntaxKind {
+    // Tokens
+    /// A token of the lexer
+    Token(TokenKind),
+    Whitespace,
+    /// A short or long comment, including the `--`
+    Comment,
+    /// The first line of the source if it starts with `#`, without the newline. Skipped like in
+    /// `luaL_loadfile`.
+    Shebang,
+    /// The rest of the source after a lexer error
+    ErrorToken,
+
+    // Nodes
+    /// The whole source: a block and the trivia around it
+    Chunk,
+    Block,
+    /// The rest of the source after a parse error
+    Error,
+
+    /// `vars = exprs`
+    AssignStat,
+    /// A function call statement
+    CallStat,
+    /// `::name::`
+    LabelStat,
+    BreakStat,
+    GotoStat,
+    DoStat,
+    WhileStat,
+    RepeatStat,
+    /// `if cond then block`, followed by `ElseIfClause`s, an optional `ElseClause`, and `end`
+    IfStat,
+    /// `elseif cond then block`
+    ElseIfClause,
+    /// `else block`
+    ElseClause,
+    /// `for name = start, limit, step do block end`
+    NumericForStat,
+    /// `for names in exprs do block end`
+    GenericForStat,
+    /// `function name body`
+    FunctionStat,
+    /// `local function name body`
+    LocalFunctionStat,
+    /// `local vars = exprs`
+    LocalStat,
+    ReturnStat,
+
+    /// Name of a function in a function statement: `a.b.c` or `a.b:c`
+    FuncName,
+    /// Parameters and body of a function, from the opening parenthesis of the parameters to the
+    /// `end`
+    FuncBody,
+    /// `(params)`
+    ParamList,
+    /// A variable of a `local` statement, with its attribute
+    LocalVar,
+    /// `<name>`
+    Attrib,
+    /// Names of a generic `for` loop
+    NameList,
+    /// Targets of an assignment
+    VarList,
+    ExprList,
+
+    /// `nil`, `true`, `false`, a numeral, a string, or `...`
+    LiteralExpr,
+    NameExpr,
+    /// `function body`
+    FunctionExpr,
+    TableExpr,
+    /// `[key] = value` in a table constructor
+    IndexField,
+    /// `name = value` in a table constructor
+    NamedField,
+    /// `value` in a table constructor
+    PositionalField,
+    /// `object.name`
+    FieldExpr,
+    /// `object[key]`
+    IndexExpr,
+    /// `function args`
+    CallExpr,
+    /// `object:method args`
+    MethodCallExpr,
+    /// Arguments of a call: `(exprs)`, a string, or a table constructor
+    CallArgs,
+    /// `(expr)`
+    ParenExpr,
+    UnaryExpr,
+    BinaryExpr,
+}
+
+impl SyntaxKind {
+    /// Whether the kind is a token kind
+    pub fn is_token(self) -> bool {
+        matches!(
+            self,
+            SyntaxKind::Token(_)
+                | SyntaxKind::Whitespace
+                | SyntaxKind::Comment
+                | SyntaxKind::Shebang
+                | SyntaxKind::ErrorToken
+        )
+    }
+
+    pub fn is_trivia(self) -> bool {
+        matches!(
+            self,
+            SyntaxKind::Whitespace | SyntaxKind::Comment | SyntaxKind::Shebang
+        )
+    }
+
+    /// Whether the expression can be assigned to
+    pub fn is_var(self) -> bool {
+        matches!(
+            self,
+            SyntaxKind::NameExpr | SyntaxKind::FieldExpr | SyntaxKind::IndexExpr
+        )
+    }
+
+    pub fn is_call(self) -> bool {
+        matches!(self, SyntaxKind::CallExpr | SyntaxKind::MethodCallExpr)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseErrorKind {
+    Lexer(LexerError),
+    /// A token was expected, e.g. `end` to close a block
+    Expected(TokenKind),
+    /// The end of the source was expected after the last statement
+    ExpectedEof,
+    /// `=` or `in` was expected after the first name of a `for` loop
+    ExpectedAssignOrIn,
+    /// Arguments were expected after the method name of a method call
+    ExpectedArgs,
+    /// A parameter name or `...` was expected in the parameters of a function
+    ExpectedParam,
+    /// An expression was expected
+    UnexpectedSymbol,
+    /// An expression statement that is not a call, or an assignment to an expression that is not
+    /// a variable
+    SyntaxError,
+    UnknownAttribute(Vec<u8>),
+    /// More than one `<close>` variable in a `local` statement
+    MultipleToBeClosed,
+    /// `...` in a function without `...` parameter
+    VarargOutsideVarargFunction,
+    /// Statements or expressions nested deeper than `grammar::MAX_DEPTH`. Reported as a C stack
+    /// overflow, like in the reference implementation.
+    TooManyLevels,
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseErrorKind::Lexer(err) => err.fmt(f),
+            ParseErrorKind::Expected(
+                kind
+                @ (TokenKind::Name | TokenKind::String | TokenKind::Integer | TokenKind::Float),
+            ) => write!(f, "{} expected", kind),
+            ParseErrorKind::Expected(kind) => write!(f, "'{}' expected", kind),
+            ParseErrorKind::ExpectedEof => write!(f, "'<eof>' expected"),
+            ParseErrorKind::ExpectedAssignOrIn => write!(f, "'=' or 'in' expected"),
+            ParseErrorKind::ExpectedArgs => write!(f, "function arguments expected"),
+            ParseErrorKind::ExpectedParam => write!(f, "<name> or '...' expected"),
+            ParseErrorKind::UnexpectedSymbol => write!(f, "unexpected symbol"),
+            ParseErrorKind::SyntaxError => write!(f, "syntax error"),
+            ParseErrorKind::UnknownAttribute(name) => {
+                write!(f, "unknown attribute '{}'", String::from_utf8_lossy(name))
+            }
+            ParseErrorKind::MultipleToBeClosed => {
+                write!(f, "multiple to-be-closed variables in local list")
+            }
+            ParseErrorKind::VarargOutsideVarargFunction => {
+                write!(f, "cannot use '...' outside a vararg function")
+            }
+            ParseErrorKind::TooManyLevels => write!(f, "C stack overflow"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxError {
+    pub kind: ParseErrorKind,
+    /// Same as the span of the `ParseError` that `parser` returns for the source
+    pub span: Span,
+    /// Kind of the token at `span`, or `None` at the end of the source and for lexer errors
+    pub found: Option<TokenKind>,
+    /// Tokens that would have been accepted instead of `found`. Empty for errors that are not
+    /// about an unexpected token, e.g. lexer errors, unknown attributes, and assignments to
+    /// expressions that are not variables.
+    pub expected: TokenSet,
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.kind.fmt(f)?;
+        for (i, kind) in self.expected.iter().enumerate() {
+            f.write_str(if i == 0 { ", expected one of " } else { ", " })?;
+            match kind {
+                TokenKind::Name | TokenKind::String | TokenKind::Integer | TokenKind::Float => {
+                    write!(f, "{}", kind)?
+                }
+                _ => write!(f, "'{}'", kind)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<(LexerError, Span)> for SyntaxError {
+    fn from((err, span): (LexerError, Span)) -> Self {
+        SyntaxError {
+            kind: ParseErrorKind::Lexer(err),
+            span,
+            found: None,
+            expected: TokenSet::EMPTY,
+        }
+    }
+}
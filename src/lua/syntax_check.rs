@@ -0,0 +1,15 @@
+// Syntax checking of Lua 5.4 without building an AST, e.g. to check many scripts quickly.
+//
+// Runs `grammar` with a sink that ignores the syntax, so it finds the same first error as
+// `parser`. Errors about unexpected tokens also have the set of tokens that were expected instead.
+
+use super::buffered::TokenSource;
+use super::grammar::Grammar;
+use super::syntax::SyntaxError;
+
+/// Checks that the tokens of a whole source form a valid chunk. Returns the first syntax error.
+pub fn check_syntax<L: TokenSource>(lexer: L) -> Result<(), SyntaxError> {
+    let mut grammar = Grammar::new(lexer, ());
+    grammar.enable_expected_tokens();
+    grammar.chunk()
+}
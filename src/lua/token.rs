@@ -1,4 +1,11 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use std::fmt::{self, Write};
+use std::str;
+
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Token<S> {
     Break,
     Do,
@@ -55,10 +62,11 @@ pub enum Token<S> {
     RightBracket,
     LeftBrace,
     RightBrace,
-    /// Numerals are only lexed as integers in the range [-(2^63-1), 2^63-1], otherwise they will be
-    /// lexed as floats.
+    /// Decimal numerals are only lexed as integers in the range [0, 2^63-1], otherwise they will be
+    /// lexed as floats. Hexadecimal integer numerals wrap around modulo 2^64, so
+    /// `0xffffffffffffffff` is lexed as `Integer(-1)`.
     Integer(i64),
-    Float(f64),
+    Float(#[cfg_attr(feature = "serde", serde(with = "super::serialize::float"))] f64),
     Name(S),
     String(S),
 }
@@ -71,3 +79,735 @@ impl<S: Clone> Token<S> {
         }
     }
 }
+
+impl<S> Token<S> {
+    pub fn kind(&self) -> TokenKind {
+        match self {
+            Token::Break => TokenKind::Break,
+            Token::Do => TokenKind::Do,
+            Token::Else => TokenKind::Else,
+            Token::ElseIf => TokenKind::ElseIf,
+            Token::End => TokenKind::End,
+            Token::Function => TokenKind::Function,
+            Token::Goto => TokenKind::Goto,
+            Token::If => TokenKind::If,
+            Token::In => TokenKind::In,
+            Token::Local => TokenKind::Local,
+            Token::Nil => TokenKind::Nil,
+            Token::For => TokenKind::For,
+            Token::While => TokenKind::While,
+            Token::Repeat => TokenKind::Repeat,
+            Token::Until => TokenKind::Until,
+            Token::Return => TokenKind::Return,
+            Token::Then => TokenKind::Then,
+            Token::True => TokenKind::True,
+            Token::False => TokenKind::False,
+            Token::Not => TokenKind::Not,
+            Token::And => TokenKind::And,
+            Token::Or => TokenKind::Or,
+            Token::Minus => TokenKind::Minus,
+            Token::Add => TokenKind::Add,
+            Token::Mul => TokenKind::Mul,
+            Token::Div => TokenKind::Div,
+            Token::IDiv => TokenKind::IDiv,
+            Token::Pow => TokenKind::Pow,
+            Token::Mod => TokenKind::Mod,
+            Token::Len => TokenKind::Len,
+            Token::BitNotXor => TokenKind::BitNotXor,
+            Token::BitAnd => TokenKind::BitAnd,
+            Token::BitOr => TokenKind::BitOr,
+            Token::ShiftRight => TokenKind::ShiftRight,
+            Token::ShiftLeft => TokenKind::ShiftLeft,
+            Token::Concat => TokenKind::Concat,
+            Token::Dots => TokenKind::Dots,
+            Token::Assign => TokenKind::Assign,
+            Token::LessThan => TokenKind::LessThan,
+            Token::LessEqual => TokenKind::LessEqual,
+            Token::GreaterThan => TokenKind::GreaterThan,
+            Token::GreaterEqual => TokenKind::GreaterEqual,
+            Token::Equal => TokenKind::Equal,
+            Token::NotEqual => TokenKind::NotEqual,
+            Token::Dot => TokenKind::Dot,
+            Token::SemiColon => TokenKind::SemiColon,
+            Token::Colon => TokenKind::Colon,
+            Token::DoubleColon => TokenKind::DoubleColon,
+            Token::Comma => TokenKind::Comma,
+            Token::LeftParen => TokenKind::LeftParen,
+            Token::RightParen => TokenKind::RightParen,
+            Token::LeftBracket => TokenKind::LeftBracket,
+            Token::RightBracket => TokenKind::RightBracket,
+            Token::LeftBrace => TokenKind::LeftBrace,
+            Token::RightBrace => TokenKind::RightBrace,
+            Token::Integer(_) => TokenKind::Integer,
+            Token::Float(_) => TokenKind::Float,
+            Token::Name(_) => TokenKind::Name,
+            Token::String(_) => TokenKind::String,
+        }
+    }
+
+    /// Whether the token is a string or numeral literal
+    pub fn is_literal(&self) -> bool {
+        self.kind().is_literal()
+    }
+
+    pub fn as_ref(&self) -> Token<&S> {
+        match self {
+            Token::Integer(i) => Token::Integer(*i),
+            Token::Float(f) => Token::Float(*f),
+            Token::Name(s) => Token::Name(s),
+            Token::String(s) => Token::String(s),
+            token => Token::from_kind(token.kind()).unwrap(),
+        }
+    }
+
+    /// Maps the payload of names and strings.
+    pub fn map_string<T, F: FnOnce(S) -> T>(self, f: F) -> Token<T> {
+        match self {
+            Token::Integer(i) => Token::Integer(i),
+            Token::Float(f) => Token::Float(f),
+            Token::Name(s) => Token::Name(f(s)),
+            Token::String(s) => Token::String(f(s)),
+            token => Token::from_kind(token.kind()).unwrap(),
+        }
+    }
+
+    /// Gets the token for a token kind without a payload.
+    pub fn from_kind(kind: TokenKind) -> Option<Token<S>> {
+        Some(match kind {
+            TokenKind::Break => Token::Break,
+            TokenKind::Do => Token::Do,
+            TokenKind::Else => Token::Else,
+            TokenKind::ElseIf => Token::ElseIf,
+            TokenKind::End => Token::End,
+            TokenKind::Function => Token::Function,
+            TokenKind::Goto => Token::Goto,
+            TokenKind::If => Token::If,
+            TokenKind::In => Token::In,
+            TokenKind::Local => Token::Local,
+            TokenKind::Nil => Token::Nil,
+            TokenKind::For => Token::For,
+            TokenKind::While => Token::While,
+            TokenKind::Repeat => Token::Repeat,
+            TokenKind::Until => Token::Until,
+            TokenKind::Return => Token::Return,
+            TokenKind::Then => Token::Then,
+            TokenKind::True => Token::True,
+            TokenKind::False => Token::False,
+            TokenKind::Not => Token::Not,
+            TokenKind::And => Token::And,
+            TokenKind::Or => Token::Or,
+            TokenKind::Minus => Token::Minus,
+            TokenKind::Add => Token::Add,
+            TokenKind::Mul => Token::Mul,
+            TokenKind::Div => Token::Div,
+            TokenKind::IDiv => Token::IDiv,
+            TokenKind::Pow => Token::Pow,
+            TokenKind::Mod => Token::Mod,
+            TokenKind::Len => Token::Len,
+            TokenKind::BitNotXor => Token::BitNotXor,
+            TokenKind::BitAnd => Token::BitAnd,
+            TokenKind::BitOr => Token::BitOr,
+            TokenKind::ShiftRight => Token::ShiftRight,
+            TokenKind::ShiftLeft => Token::ShiftLeft,
+            TokenKind::Concat => Token::Concat,
+            TokenKind::Dots => Token::Dots,
+            TokenKind::Assign => Token::Assign,
+            TokenKind::LessThan => Token::LessThan,
+            TokenKind::LessEqual => Token::LessEqual,
+            TokenKind::GreaterThan => Token::GreaterThan,
+            TokenKind::GreaterEqual => Token::GreaterEqual,
+            TokenKind::Equal => Token::Equal,
+            TokenKind::NotEqual => Token::NotEqual,
+            TokenKind::Dot => Token::Dot,
+            TokenKind::SemiColon => Token::SemiColon,
+            TokenKind::Colon => Token::Colon,
+            TokenKind::DoubleColon => Token::DoubleColon,
+            TokenKind::Comma => Token::Comma,
+            TokenKind::LeftParen => Token::LeftParen,
+            TokenKind::RightParen => Token::RightParen,
+            TokenKind::LeftBracket => Token::LeftBracket,
+            TokenKind::RightBracket => Token::RightBracket,
+            TokenKind::LeftBrace => Token::LeftBrace,
+            TokenKind::RightBrace => Token::RightBrace,
+            TokenKind::Integer | TokenKind::Float | TokenKind::Name | TokenKind::String => {
+                return None
+            }
+        })
+    }
+}
+
+/// Kind of a token, without the payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[repr(u8)]
+pub enum TokenKind {
+    Break,
+    Do,
+    Else,
+    ElseIf,
+    End,
+    Function,
+    Goto,
+    If,
+    In,
+    Local,
+    Nil,
+    For,
+    While,
+    Repeat,
+    Until,
+    Return,
+    Then,
+    True,
+    False,
+    Not,
+    And,
+    Or,
+    Minus,
+    Add,
+    Mul,
+    Div,
+    IDiv,
+    Pow,
+    Mod,
+    Len,
+    BitNotXor,
+    BitAnd,
+    BitOr,
+    ShiftRight,
+    ShiftLeft,
+    Concat,
+    Dots,
+    Assign,
+    LessThan,
+    LessEqual,
+    GreaterThan,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+    Dot,
+    SemiColon,
+    Colon,
+    DoubleColon,
+    Comma,
+    LeftParen,
+    RightParen,
+    LeftBracket,
+    RightBracket,
+    LeftBrace,
+    RightBrace,
+    Integer,
+    Float,
+    Name,
+    String,
+}
+
+impl TokenKind {
+    /// All token kinds, in the order of their discriminants
+    pub const ALL: [TokenKind; 59] = [
+        TokenKind::Break,
+        TokenKind::Do,
+        TokenKind::Else,
+        TokenKind::ElseIf,
+        TokenKind::End,
+        TokenKind::Function,
+        TokenKind::Goto,
+        TokenKind::If,
+        TokenKind::In,
+        TokenKind::Local,
+        TokenKind::Nil,
+        TokenKind::For,
+        TokenKind::While,
+        TokenKind::Repeat,
+        TokenKind::Until,
+        TokenKind::Return,
+        TokenKind::Then,
+        TokenKind::True,
+        TokenKind::False,
+        TokenKind::Not,
+        TokenKind::And,
+        TokenKind::Or,
+        TokenKind::Minus,
+        TokenKind::Add,
+        TokenKind::Mul,
+        TokenKind::Div,
+        TokenKind::IDiv,
+        TokenKind::Pow,
+        TokenKind::Mod,
+        TokenKind::Len,
+        TokenKind::BitNotXor,
+        TokenKind::BitAnd,
+        TokenKind::BitOr,
+        TokenKind::ShiftRight,
+        TokenKind::ShiftLeft,
+        TokenKind::Concat,
+        TokenKind::Dots,
+        TokenKind::Assign,
+        TokenKind::LessThan,
+        TokenKind::LessEqual,
+        TokenKind::GreaterThan,
+        TokenKind::GreaterEqual,
+        TokenKind::Equal,
+        TokenKind::NotEqual,
+        TokenKind::Dot,
+        TokenKind::SemiColon,
+        TokenKind::Colon,
+        TokenKind::DoubleColon,
+        TokenKind::Comma,
+        TokenKind::LeftParen,
+        TokenKind::RightParen,
+        TokenKind::LeftBracket,
+        TokenKind::RightBracket,
+        TokenKind::LeftBrace,
+        TokenKind::RightBrace,
+        TokenKind::Integer,
+        TokenKind::Float,
+        TokenKind::Name,
+        TokenKind::String,
+    ];
+
+    /// Spelling of the token in Lua source. Tokens with a payload are shown as in the reference
+    /// implementation's error messages: `<integer>`, `<number>`, `<name>`, and `<string>`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TokenKind::Break => "break",
+            TokenKind::Do => "do",
+            TokenKind::Else => "else",
+            TokenKind::ElseIf => "elseif",
+            TokenKind::End => "end",
+            TokenKind::Function => "function",
+            TokenKind::Goto => "goto",
+            TokenKind::If => "if",
+            TokenKind::In => "in",
+            TokenKind::Local => "local",
+            TokenKind::Nil => "nil",
+            TokenKind::For => "for",
+            TokenKind::While => "while",
+            TokenKind::Repeat => "repeat",
+            TokenKind::Until => "until",
+            TokenKind::Return => "return",
+            TokenKind::Then => "then",
+            TokenKind::True => "true",
+            TokenKind::False => "false",
+            TokenKind::Not => "not",
+            TokenKind::And => "and",
+            TokenKind::Or => "or",
+            TokenKind::Minus => "-",
+            TokenKind::Add => "+",
+            TokenKind::Mul => "*",
+            TokenKind::Div => "/",
+            TokenKind::IDiv => "//",
+            TokenKind::Pow => "^",
+            TokenKind::Mod => "%",
+            TokenKind::Len => "#",
+            TokenKind::BitNotXor => "~",
+            TokenKind::BitAnd => "&",
+            TokenKind::BitOr => "|",
+            TokenKind::ShiftRight => ">>",
+            TokenKind::ShiftLeft => "<<",
+            TokenKind::Concat => "..",
+            TokenKind::Dots => "...",
+            TokenKind::Assign => "=",
+            TokenKind::LessThan => "<",
+            TokenKind::LessEqual => "<=",
+            TokenKind::GreaterThan => ">",
+            TokenKind::GreaterEqual => ">=",
+            TokenKind::Equal => "==",
+            TokenKind::NotEqual => "~=",
+            TokenKind::Dot => ".",
+            TokenKind::SemiColon => ";",
+            TokenKind::Colon => ":",
+            TokenKind::DoubleColon => "::",
+            TokenKind::Comma => ",",
+            TokenKind::LeftParen => "(",
+            TokenKind::RightParen => ")",
+            TokenKind::LeftBracket => "[",
+            TokenKind::RightBracket => "]",
+            TokenKind::LeftBrace => "{",
+            TokenKind::RightBrace => "}",
+            TokenKind::Integer => "<integer>",
+            TokenKind::Float => "<number>",
+            TokenKind::Name => "<name>",
+            TokenKind::String => "<string>",
+        }
+    }
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+/// Renders the token as Lua source. Lexing the rendered token gives back the same token, for all
+/// tokens returned by the lexers. (Floats that the lexers don't generate, like negative numbers and
+/// NaN, are rendered as Lua expressions.)
+impl<S: AsRef<[u8]>> fmt::Display for Token<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            // Negative integers can only be written as hexadecimal numerals
+            Token::Integer(i) if *i < 0 => write!(f, "0x{:x}", *i as u64),
+            Token::Integer(i) => write!(f, "{}", i),
+            Token::Float(n) => {
+                if n.is_nan() {
+                    f.write_str("(0/0)")
+                } else if n.is_infinite() {
+                    f.write_str(if *n > 0.0 { "1e9999" } else { "-1e9999" })
+                } else {
+                    // `Debug` prints the shortest representation that round-trips, and always
+                    // includes a radix point or exponent
+                    write!(f, "{:?}", n)
+                }
+            }
+            Token::Name(s) => f.write_str(&String::from_utf8_lossy(s.as_ref())),
+            Token::String(s) => write_quoted_string(f, s.as_ref()),
+            token => f.write_str(token.kind().as_str()),
+        }
+    }
+}
+
+/// Writes a byte string as a double-quoted Lua string literal. Valid UTF-8 sequences are written as
+/// they are, other non-printable bytes are escaped.
+pub fn write_quoted_string<W: fmt::Write>(w: &mut W, mut s: &[u8]) -> fmt::Result {
+    w.write_char('"')?;
+
+    loop {
+        let (valid, invalid) = match str::from_utf8(s) {
+            Ok(valid) => (valid, &[][..]),
+            Err(err) => {
+                let (valid, rest) = s.split_at(err.valid_up_to());
+                let invalid_len = err.error_len().unwrap_or(rest.len());
+                s = &rest[invalid_len..];
+                (str::from_utf8(valid).unwrap(), &rest[..invalid_len])
+            }
+        };
+
+        for c in valid.chars() {
+            match c {
+                '"' => w.write_str("\\\"")?,
+                '\\' => w.write_str("\\\\")?,
+                '\n' => w.write_str("\\n")?,
+                '\r' => w.write_str("\\r")?,
+                '\t' => w.write_str("\\t")?,
+                '\x07' => w.write_str("\\a")?,
+                '\x08' => w.write_str("\\b")?,
+                '\x0B' => w.write_str("\\v")?,
+                '\x0C' => w.write_str("\\f")?,
+                c if c.is_ascii_control() => write!(w, "\\x{:02x}", c as u8)?,
+                c => w.write_char(c)?,
+            }
+        }
+
+        for b in invalid {
+            write!(w, "\\x{:02x}", b)?;
+        }
+
+        if invalid.is_empty() {
+            break;
+        }
+    }
+
+    w.write_char('"')
+}
+
+/// Renders tokens as Lua source, separated by spaces.
+pub fn tokens_to_string<S: AsRef<[u8]>>(tokens: &[Token<S>]) -> String {
+    let mut s = String::new();
+    for (i, token) in tokens.iter().enumerate() {
+        if i != 0 {
+            s.push(' ');
+        }
+        write!(s, "{}", token).unwrap();
+    }
+    s
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Associativity {
+    Left,
+    Right,
+}
+
+/// Precedence of unary operators (`not`, `#`, `-`, `~`). See `TokenKind::binary_precedence`.
+pub const UNARY_PRECEDENCE: u8 = 11;
+
+impl TokenKind {
+    /// Whether the token is a reserved word
+    pub fn is_keyword(self) -> bool {
+        self >= TokenKind::Break && self <= TokenKind::Or
+    }
+
+    /// Whether the token is a string or numeral literal
+    pub fn is_literal(self) -> bool {
+        matches!(
+            self,
+            TokenKind::Integer | TokenKind::Float | TokenKind::String
+        )
+    }
+
+    /// Whether the token is a unary or binary operator
+    pub fn is_operator(self) -> bool {
+        self.is_unary_operator() || self.binary_precedence().is_some()
+    }
+
+    pub fn is_unary_operator(self) -> bool {
+        matches!(
+            self,
+            TokenKind::Not | TokenKind::Len | TokenKind::Minus | TokenKind::BitNotXor
+        )
+    }
+
+    /// Precedence and associativity of the token as a binary operator, or `None` if the token is
+    /// not a binary operator. Higher precedence binds tighter. From lower to higher precedence
+    /// (section 3.4.8 of the Lua 5.4 manual):
+    ///
+    /// ```text
+    /// or
+    /// and
+    /// <     >     <=    >=    ~=    ==
+    /// |
+    /// ~
+    /// &
+    /// <<    >>
+    /// ..                          (right associative)
+    /// +     -
+    /// *     /     //    %
+    /// unary operators (not   #     -     ~)
+    /// ^                           (right associative)
+    /// ```
+    pub fn binary_precedence(self) -> Option<(u8, Associativity)> {
+        use Associativity::*;
+        Some(match self {
+            TokenKind::Or => (1, Left),
+            TokenKind::And => (2, Left),
+            TokenKind::LessThan
+            | TokenKind::GreaterThan
+            | TokenKind::LessEqual
+            | TokenKind::GreaterEqual
+            | TokenKind::NotEqual
+            | TokenKind::Equal => (3, Left),
+            TokenKind::BitOr => (4, Left),
+            TokenKind::BitNotXor => (5, Left),
+            TokenKind::BitAnd => (6, Left),
+            TokenKind::ShiftLeft | TokenKind::ShiftRight => (7, Left),
+            TokenKind::Concat => (8, Right),
+            TokenKind::Add | TokenKind::Minus => (9, Left),
+            TokenKind::Mul | TokenKind::Div | TokenKind::IDiv | TokenKind::Mod => (10, Left),
+            TokenKind::Pow => (12, Right),
+            _ => return None,
+        })
+    }
+}
+
+/// A set of token kinds, e.g. the tokens that a parser expected where it found a syntax error.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct TokenSet(u64);
+
+impl TokenSet {
+    pub const EMPTY: TokenSet = TokenSet(0);
+
+    pub const fn new(kinds: &[TokenKind]) -> TokenSet {
+        let mut bits = 0;
+        let mut i = 0;
+        while i < kinds.len() {
+            bits |= 1 << kinds[i] as u8;
+            i += 1;
+        }
+        TokenSet(bits)
+    }
+
+    pub fn contains(self, kind: TokenKind) -> bool {
+        self.0 & (1 << kind as u8) != 0
+    }
+
+    pub fn insert(&mut self, kind: TokenKind) {
+        self.0 |= 1 << kind as u8;
+    }
+
+    pub const fn union(self, other: TokenSet) -> TokenSet {
+        TokenSet(self.0 | other.0)
+    }
+
+    pub fn len(self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Iterates the token kinds in the order of `TokenKind::ALL`.
+    pub fn iter(self) -> impl Iterator<Item = TokenKind> {
+        TokenKind::ALL
+            .iter()
+            .copied()
+            .filter(move |&kind| self.contains(kind))
+    }
+}
+
+impl fmt::Debug for TokenSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+/// A token in 8 bytes, with the payload stored in a `TokenPayloads` table. `payload` is the index
+/// of the payload in the table for the token kind (`integers`, `floats`, or `strings` for names
+/// and strings), and 0 for tokens without a payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactToken {
+    pub kind: TokenKind,
+    pub payload: u32,
+}
+
+/// Payloads of `CompactToken`s.
+#[derive(Debug, Clone)]
+pub struct TokenPayloads<S> {
+    pub integers: Vec<i64>,
+    pub floats: Vec<f64>,
+    pub strings: Vec<S>,
+}
+
+impl<S> Default for TokenPayloads<S> {
+    fn default() -> Self {
+        TokenPayloads::new()
+    }
+}
+
+impl<S> TokenPayloads<S> {
+    pub fn new() -> Self {
+        TokenPayloads {
+            integers: Vec::new(),
+            floats: Vec::new(),
+            strings: Vec::new(),
+        }
+    }
+
+    /// Moves the payload of the token to the table and returns the compact token.
+    pub fn compact(&mut self, token: Token<S>) -> CompactToken {
+        fn push<A>(vec: &mut Vec<A>, a: A) -> u32 {
+            let idx = vec.len() as u32;
+            vec.push(a);
+            idx
+        }
+
+        let kind = token.kind();
+        let payload = match token {
+            Token::Integer(i) => push(&mut self.integers, i),
+            Token::Float(f) => push(&mut self.floats, f),
+            Token::Name(s) | Token::String(s) => push(&mut self.strings, s),
+            _ => 0,
+        };
+
+        CompactToken { kind, payload }
+    }
+
+    /// Gets the token back from a compact token, borrowing the string payload.
+    pub fn get(&self, token: CompactToken) -> Token<&S> {
+        let idx = token.payload as usize;
+        match token.kind {
+            TokenKind::Integer => Token::Integer(self.integers[idx]),
+            TokenKind::Float => Token::Float(self.floats[idx]),
+            TokenKind::Name => Token::Name(&self.strings[idx]),
+            TokenKind::String => Token::String(&self.strings[idx]),
+            kind => Token::from_kind(kind).unwrap(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.integers.clear();
+        self.floats.clear();
+        self.strings.clear();
+    }
+}
+
+/// Byte range of a token in the source. Offsets are `u32`s to keep token buffers compact, so
+/// sources larger than 4 GiB are not supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Span {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span {
+            start: start as u32,
+            end: end as u32,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        (self.end - self.start) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+/// Raw source text and metadata of a literal (string or numeral) token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Literal<S> {
+    /// Source text of the literal, including quotes and long brackets of strings
+    pub raw: S,
+    pub kind: LiteralKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum LiteralKind {
+    String(StringDelimiter),
+    Numeral {
+        base: NumeralBase,
+        /// Whether the numeral has a radix point
+        has_fraction: bool,
+        has_exponent: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum StringDelimiter {
+    SingleQuote,
+    DoubleQuote,
+    /// Long bracket with the given level (number of `=`s)
+    LongBracket(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum NumeralBase {
+    Decimal,
+    Hexadecimal,
+}
+
+impl LiteralKind {
+    /// Gets the metadata of a literal from its source text. Does not check that the literal is
+    /// well-formed.
+    pub fn from_raw(raw: &[u8]) -> LiteralKind {
+        match raw.first() {
+            Some(b'\'') => LiteralKind::String(StringDelimiter::SingleQuote),
+            Some(b'"') => LiteralKind::String(StringDelimiter::DoubleQuote),
+            Some(b'[') => {
+                let level = raw[1..].iter().take_while(|&&c| c == b'=').count();
+                LiteralKind::String(StringDelimiter::LongBracket(level))
+            }
+            _ => {
+                let is_hex = raw.len() >= 2 && raw[0] == b'0' && (raw[1] == b'x' || raw[1] == b'X');
+                let (base, exponent_marks) = if is_hex {
+                    (NumeralBase::Hexadecimal, [b'p', b'P'])
+                } else {
+                    (NumeralBase::Decimal, [b'e', b'E'])
+                };
+                LiteralKind::Numeral {
+                    base,
+                    has_fraction: raw.contains(&b'.'),
+                    has_exponent: raw.iter().any(|c| exponent_marks.contains(c)),
+                }
+            }
+        }
+    }
+}
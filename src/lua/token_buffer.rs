@@ -0,0 +1,189 @@
+use super::token::{Span, Token, TokenKind};
+
+/// Tokens of a whole source in struct-of-arrays form. Token kinds, spans, and payload indices are
+/// stored in separate arrays, integer and float payloads are stored in per-type tables, and names
+/// and strings are stored back to back in a string arena.
+#[derive(Debug, Clone, Default)]
+pub struct TokenBuffer {
+    pub kinds: Vec<TokenKind>,
+    pub spans: Vec<Span>,
+    /// Index of the payload in `integers`, `floats`, or `strings` depending on the token kind. 0
+    /// for tokens without a payload.
+    pub payloads: Vec<u32>,
+    pub integers: Vec<i64>,
+    pub floats: Vec<f64>,
+    pub strings: StringArena,
+}
+
+/// Index of a name or string in a `StringArena`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StringId(pub u32);
+
+/// Names and strings stored back to back in a single buffer.
+#[derive(Debug, Clone, Default)]
+pub struct StringArena {
+    pub bytes: Vec<u8>,
+    /// End offsets of strings in `bytes`. Start offset of a string is the end offset of the
+    /// previous one.
+    pub ends: Vec<u32>,
+}
+
+impl StringArena {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn with_capacity(n_strings: usize, n_bytes: usize) -> Self {
+        StringArena {
+            bytes: Vec::with_capacity(n_bytes),
+            ends: Vec::with_capacity(n_strings),
+        }
+    }
+
+    pub fn alloc(&mut self, s: &[u8]) -> StringId {
+        let id = StringId(self.ends.len() as u32);
+        self.bytes.extend_from_slice(s);
+        self.ends.push(self.bytes.len() as u32);
+        id
+    }
+
+    pub fn get(&self, id: StringId) -> &[u8] {
+        let idx = id.0 as usize;
+        let start = if idx == 0 { 0 } else { self.ends[idx - 1] };
+        &self.bytes[start as usize..self.ends[idx] as usize]
+    }
+
+    pub fn len(&self) -> usize {
+        self.ends.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ends.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.bytes.clear();
+        self.ends.clear();
+    }
+
+    /// Appends the strings of another arena. String `i` of `other` becomes string
+    /// `self.len() + i`.
+    pub fn append(&mut self, other: &StringArena) {
+        let offset = self.bytes.len() as u32;
+        self.bytes.extend_from_slice(&other.bytes);
+        self.ends.extend(other.ends.iter().map(|end| end + offset));
+    }
+}
+
+impl TokenBuffer {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Creates a buffer with enough capacity for most sources of the given size, to avoid
+    /// reallocations while lexing. On the Lua test suite there's a token for every 4 bytes, a name
+    /// or string for every 3 tokens, and names and strings make up half of the source.
+    pub fn with_capacity_for_source(source_len: usize) -> Self {
+        let n_tokens = source_len / 4 + 1;
+        TokenBuffer {
+            kinds: Vec::with_capacity(n_tokens),
+            spans: Vec::with_capacity(n_tokens),
+            payloads: Vec::with_capacity(n_tokens),
+            integers: Vec::new(),
+            floats: Vec::new(),
+            strings: StringArena::with_capacity(n_tokens / 3, source_len / 2),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.kinds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.kinds.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.kinds.clear();
+        self.spans.clear();
+        self.payloads.clear();
+        self.integers.clear();
+        self.floats.clear();
+        self.strings.clear();
+    }
+
+    /// Appends the tokens of another buffer.
+    pub fn append(&mut self, other: &TokenBuffer) {
+        let n_integers = self.integers.len() as u32;
+        let n_floats = self.floats.len() as u32;
+        let n_strings = self.strings.len() as u32;
+
+        self.kinds.extend_from_slice(&other.kinds);
+        self.spans.extend_from_slice(&other.spans);
+        self.payloads.extend(
+            other
+                .kinds
+                .iter()
+                .zip(&other.payloads)
+                .map(|(kind, payload)| match kind {
+                    TokenKind::Integer => payload + n_integers,
+                    TokenKind::Float => payload + n_floats,
+                    TokenKind::Name | TokenKind::String => payload + n_strings,
+                    _ => *payload,
+                }),
+        );
+        self.integers.extend_from_slice(&other.integers);
+        self.floats.extend_from_slice(&other.floats);
+        self.strings.append(&other.strings);
+    }
+
+    /// Adds a token whose name or string is already in the string arena.
+    pub fn push(&mut self, token: Token<StringId>, span: Span) {
+        let kind = token.kind();
+        let payload = match token {
+            Token::Integer(i) => {
+                self.integers.push(i);
+                self.integers.len() as u32 - 1
+            }
+            Token::Float(f) => {
+                self.floats.push(f);
+                self.floats.len() as u32 - 1
+            }
+            Token::Name(id) | Token::String(id) => id.0,
+            _ => 0,
+        };
+
+        self.kinds.push(kind);
+        self.spans.push(span);
+        self.payloads.push(payload);
+    }
+
+    /// Adds a token, copying the name or string to the string arena.
+    pub fn push_token<S: AsRef<[u8]>>(&mut self, token: Token<S>, span: Span) {
+        let token = token.map_string(|s| self.strings.alloc(s.as_ref()));
+        self.push(token, span);
+    }
+
+    pub fn kind(&self, idx: usize) -> TokenKind {
+        self.kinds[idx]
+    }
+
+    pub fn span(&self, idx: usize) -> Span {
+        self.spans[idx]
+    }
+
+    pub fn token(&self, idx: usize) -> Token<&[u8]> {
+        let payload = self.payloads[idx];
+        match self.kinds[idx] {
+            TokenKind::Integer => Token::Integer(self.integers[payload as usize]),
+            TokenKind::Float => Token::Float(self.floats[payload as usize]),
+            TokenKind::Name => Token::Name(self.strings.get(StringId(payload))),
+            TokenKind::String => Token::String(self.strings.get(StringId(payload))),
+            kind => Token::from_kind(kind).unwrap(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Token<&[u8]>, Span)> + '_ {
+        (0..self.len()).map(move |idx| (self.token(idx), self.spans[idx]))
+    }
+}
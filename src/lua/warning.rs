@@ -0,0 +1,80 @@
+// Warnings for legal but suspicious code. Warnings don't affect the tokens.
+//
+// Both lexers report warnings as they lex, when enabled: the luster lexer with
+// `Lexer::enable_warnings`, the lexgen lexer with `lex_with_warnings`.
+
+use super::token::Span;
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LexerWarning {
+    /// A three-digit decimal escape followed by a digit, as in `"\0123"`, which is `"\012" .. "3"`
+    /// and not `"\0" .. "123"`
+    DecimalEscapeFollowedByDigit,
+    /// `\z` at the end of a string, which doesn't skip anything
+    EscapeZAtEndOfString,
+    /// A closing long bracket with `=`s but of a different level than the long comment, as in
+    /// `--[[ ]=] ]]`
+    MismatchedLongBracketInComment,
+    /// A newline sequence different from the first one in the source. Reported once per source.
+    MixedLineEndings,
+    /// Indentation of a line with a tab
+    TabInIndentation,
+}
+
+impl fmt::Display for LexerWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LexerWarning::DecimalEscapeFollowedByDigit => write!(
+                f,
+                "decimal escape followed by a digit, decimal escapes take at most 3 digits"
+            ),
+            LexerWarning::EscapeZAtEndOfString => {
+                write!(f, "'\\z' at the end of string does not skip anything")
+            }
+            LexerWarning::MismatchedLongBracketInComment => {
+                write!(
+                    f,
+                    "closing long bracket does not match the long comment's level"
+                )
+            }
+            LexerWarning::MixedLineEndings => write!(f, "mixed line endings"),
+            LexerWarning::TabInIndentation => write!(f, "tab in indentation"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Warning {
+    pub kind: LexerWarning,
+    pub span: Span,
+}
+
+/// Tracks newline sequences to report mixed line endings.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct NewlineTracker {
+    /// First newline sequence in the source
+    first: Option<(u8, Option<u8>)>,
+    reported: bool,
+}
+
+impl NewlineTracker {
+    /// Returns whether the newline sequence should be reported.
+    pub(crate) fn newline(&mut self, newline: (u8, Option<u8>)) -> bool {
+        match self.first {
+            None => {
+                self.first = Some(newline);
+                false
+            }
+            Some(first) => {
+                if first != newline && !self.reported {
+                    self.reported = true;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+}